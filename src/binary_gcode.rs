@@ -0,0 +1,248 @@
+use crate::post_process::TemplateValues;
+use crate::thumbnail::Thumbnail;
+
+//Binary G-code as read by Prusa printers, version 1 of the format without compression
+
+const MAGIC: &[u8; 4] = b"GCDE";
+const VERSION: u32 = 1;
+const CHECKSUM_CRC32: u16 = 1;
+
+const BLOCK_GCODE: u16 = 1;
+const BLOCK_SLICER_METADATA: u16 = 2;
+const BLOCK_PRINTER_METADATA: u16 = 3;
+const BLOCK_PRINT_METADATA: u16 = 4;
+const BLOCK_THUMBNAIL: u16 = 5;
+
+const COMPRESSION_NONE: u16 = 0;
+const ENCODING_INI: u16 = 0;
+const ENCODING_NONE: u16 = 0;
+const THUMBNAIL_PNG: u16 = 0;
+
+///Largest G-code block written, matching the reference implementation
+const MAX_GCODE_BLOCK_SIZE: usize = 65536;
+
+fn get_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                0xEDB8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+    table
+}
+
+fn get_crc32(table: &[u32; 256], bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        table[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+fn write_block(output: &mut Vec<u8>, table: &[u32; 256], block_type: u16, parameters: &[u16], data: &[u8]) {
+    let start = output.len();
+
+    output.extend_from_slice(&block_type.to_le_bytes());
+    output.extend_from_slice(&COMPRESSION_NONE.to_le_bytes());
+    output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    for parameter in parameters {
+        output.extend_from_slice(&parameter.to_le_bytes());
+    }
+    output.extend_from_slice(data);
+
+    //The checksum covers the header, parameters and data
+    let crc = get_crc32(table, &output[start..]);
+    output.extend_from_slice(&crc.to_le_bytes());
+}
+
+fn format_ini(values: &[(&str, String)]) -> Vec<u8> {
+    values
+        .iter()
+        .map(|(key, value)| format!("{}={}\n", key, value))
+        .collect::<String>()
+        .into_bytes()
+}
+
+///Pack the G-code into blocks along with the metadata and thumbnails
+pub fn write_binary_gcode(gcode: &str, thumbnails: &[Thumbnail], values: &TemplateValues) -> Vec<u8> {
+    let table = get_crc32_table();
+    let mut output = Vec::with_capacity(gcode.len() + 1024);
+
+    output.extend_from_slice(MAGIC);
+    output.extend_from_slice(&VERSION.to_le_bytes());
+    output.extend_from_slice(&CHECKSUM_CRC32.to_le_bytes());
+
+    let total = values.print_time.round() as u64;
+    let print_time = format!("{}h {}m {}s", total / 3600, (total / 60) % 60, total % 60);
+
+    write_block(
+        &mut output,
+        &table,
+        BLOCK_PRINTER_METADATA,
+        &[ENCODING_INI],
+        &format_ini(&[
+            ("filament_type", values.material.clone()),
+            ("layer_height", format!("{:.2}", values.layer_height)),
+        ]),
+    );
+
+    for thumbnail in thumbnails {
+        write_block(
+            &mut output,
+            &table,
+            BLOCK_THUMBNAIL,
+            &[THUMBNAIL_PNG, thumbnail.width as u16, thumbnail.height as u16],
+            &thumbnail.png,
+        );
+    }
+
+    write_block(
+        &mut output,
+        &table,
+        BLOCK_PRINT_METADATA,
+        &[ENCODING_INI],
+        &format_ini(&[
+            ("filament used [mm]", format!("{:.2}", values.filament_length * 1000.0)),
+            ("filament used [g]", format!("{:.2}", values.filament_weight)),
+            ("estimated printing time (normal mode)", print_time),
+            ("total layers count", values.layer_count.to_string()),
+        ]),
+    );
+
+    write_block(
+        &mut output,
+        &table,
+        BLOCK_SLICER_METADATA,
+        &[ENCODING_INI],
+        &format_ini(&[("producer", String::from("GladiusGUI"))]),
+    );
+
+    //Split on line boundaries so no command is cut in half
+    let bytes = gcode.as_bytes();
+    let mut block_start = 0;
+    while block_start < bytes.len() {
+        let mut block_end = (block_start + MAX_GCODE_BLOCK_SIZE).min(bytes.len());
+        if block_end < bytes.len() {
+            if let Some(newline) = bytes[block_start..block_end].iter().rposition(|byte| *byte == b'\n') {
+                block_end = block_start + newline + 1;
+            }
+        }
+        write_block(
+            &mut output,
+            &table,
+            BLOCK_GCODE,
+            &[ENCODING_NONE],
+            &bytes[block_start..block_end],
+        );
+        block_start = block_end;
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Block {
+        block_type: u16,
+        parameters: Vec<u16>,
+        data: Vec<u8>,
+    }
+
+    fn read_u16(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn read_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    ///Split the file after the header into blocks, checking the checksum of each
+    fn read_blocks(bytes: &[u8]) -> Vec<Block> {
+        let table = get_crc32_table();
+        let mut blocks = vec![];
+        let mut at = 10;
+        while at < bytes.len() {
+            let block_type = read_u16(bytes, at);
+            assert_eq!(read_u16(bytes, at + 2), COMPRESSION_NONE);
+            let size = read_u32(bytes, at + 4) as usize;
+            let parameter_count = if block_type == BLOCK_THUMBNAIL { 3 } else { 1 };
+            let parameters = (0..parameter_count).map(|i| read_u16(bytes, at + 8 + i * 2)).collect();
+            let data_start = at + 8 + parameter_count * 2;
+            let end = data_start + size;
+
+            assert_eq!(read_u32(bytes, end), get_crc32(&table, &bytes[at..end]));
+            blocks.push(Block {
+                block_type,
+                parameters,
+                data: bytes[data_start..end].to_vec(),
+            });
+            at = end + 4;
+        }
+        blocks
+    }
+
+    #[test]
+    fn crc_matches_the_standard_check_value() {
+        assert_eq!(get_crc32(&get_crc32_table(), b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn header_and_blocks_are_written_in_order() {
+        let thumbnails = vec![Thumbnail {
+            width: 16,
+            height: 12,
+            png: vec![1, 2, 3, 4],
+        }];
+        let values = TemplateValues {
+            print_time: 3725.0,
+            layer_count: 3,
+            material: String::from("PLA"),
+            ..Default::default()
+        };
+        let bytes = write_binary_gcode("G28\nG1 X10\n", &thumbnails, &values);
+
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(read_u32(&bytes, 4), VERSION);
+        assert_eq!(read_u16(&bytes, 8), CHECKSUM_CRC32);
+
+        let blocks = read_blocks(&bytes);
+        let types: Vec<u16> = blocks.iter().map(|block| block.block_type).collect();
+        assert_eq!(
+            types,
+            vec![BLOCK_PRINTER_METADATA, BLOCK_THUMBNAIL, BLOCK_PRINT_METADATA, BLOCK_SLICER_METADATA, BLOCK_GCODE]
+        );
+
+        assert_eq!(blocks[1].parameters, vec![THUMBNAIL_PNG, 16, 12]);
+        assert_eq!(blocks[1].data, vec![1, 2, 3, 4]);
+        let print_metadata = String::from_utf8(blocks[2].data.clone()).unwrap();
+        assert!(print_metadata.contains("estimated printing time (normal mode)=1h 2m 5s\n"));
+        assert!(print_metadata.contains("total layers count=3\n"));
+        assert_eq!(blocks[4].parameters, vec![ENCODING_NONE]);
+        assert_eq!(blocks[4].data, b"G28\nG1 X10\n");
+    }
+
+    #[test]
+    fn gcode_blocks_end_on_line_boundaries() {
+        let gcode: String = (0..20_000).map(|i| format!("G1 X{} Y{}\n", i % 200, i % 150)).collect();
+        assert!(gcode.len() > 2 * MAX_GCODE_BLOCK_SIZE);
+
+        let bytes = write_binary_gcode(&gcode, &[], &TemplateValues::default());
+        let gcode_blocks: Vec<Block> = read_blocks(&bytes)
+            .into_iter()
+            .filter(|block| block.block_type == BLOCK_GCODE)
+            .collect();
+
+        assert!(gcode_blocks.len() > 2);
+        assert!(gcode_blocks
+            .iter()
+            .all(|block| block.data.len() <= MAX_GCODE_BLOCK_SIZE && block.data.ends_with(b"\n")));
+        let joined: Vec<u8> = gcode_blocks.into_iter().flat_map(|block| block.data).collect();
+        assert_eq!(joined, gcode.as_bytes());
+    }
+}
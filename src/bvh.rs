@@ -0,0 +1,387 @@
+use glam::Vec3;
+use std::cmp::Ordering;
+
+///Nodes with more triangles than this are split
+const MAX_LEAF_TRIANGLES: usize = 4;
+
+#[derive(Clone, Debug)]
+struct Node {
+    min: Vec3,
+    max: Vec3,
+    ///First child for inner nodes, the second follows it. First entry in the triangle list for leaves.
+    start: usize,
+    ///Triangles in a leaf, 0 for inner nodes
+    count: usize,
+}
+
+///Bounding volume hierarchy over the triangles of a mesh, built in model space so it stays
+///valid however the mesh is moved or scaled
+#[derive(Clone, Debug, Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    ///Triangle indices ordered so every node covers a contiguous run
+    triangles: Vec<usize>,
+}
+
+impl Bvh {
+    pub fn new(vertices: &[Vec3], indices: &[u32]) -> Self {
+        let bounds: Vec<(Vec3, Vec3)> = indices
+            .chunks_exact(3)
+            .map(|tri| {
+                let [a, b, c] = [tri[0], tri[1], tri[2]].map(|v| vertices[v as usize]);
+                (a.min(b).min(c), a.max(b).max(c))
+            })
+            .collect();
+        let centers: Vec<Vec3> = bounds.iter().map(|(min, max)| (*min + *max) / 2.0).collect();
+
+        let mut triangles: Vec<usize> = (0..bounds.len()).collect();
+        if triangles.is_empty() {
+            return Bvh::default();
+        }
+
+        let mut nodes = vec![Node {
+            min: Vec3::ZERO,
+            max: Vec3::ZERO,
+            start: 0,
+            count: triangles.len(),
+        }];
+        let mut stack = vec![0];
+
+        while let Some(index) = stack.pop() {
+            let (start, count) = (nodes[index].start, nodes[index].count);
+            let run = &mut triangles[start..start + count];
+
+            let (min, max) = run
+                .iter()
+                .map(|tri| bounds[*tri])
+                .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)))
+                .unwrap_or((Vec3::ZERO, Vec3::ZERO));
+            nodes[index].min = min;
+            nodes[index].max = max;
+
+            if count <= MAX_LEAF_TRIANGLES {
+                continue;
+            }
+
+            //Split at the median center along the axis the centers are most spread out on
+            let (center_min, center_max) = run
+                .iter()
+                .map(|tri| (centers[*tri], centers[*tri]))
+                .reduce(|(min_a, max_a), (min_b, max_b)| (min_a.min(min_b), max_a.max(max_b)))
+                .unwrap_or((Vec3::ZERO, Vec3::ZERO));
+            let extent = center_max - center_min;
+            let axis = if extent.x >= extent.y && extent.x >= extent.z {
+                0
+            } else if extent.y >= extent.z {
+                1
+            } else {
+                2
+            };
+            if extent[axis] <= 0.0 {
+                continue;
+            }
+
+            let middle = count / 2;
+            run.select_nth_unstable_by(middle, |a, b| {
+                centers[*a][axis].partial_cmp(&centers[*b][axis]).unwrap_or(Ordering::Equal)
+            });
+
+            let left = nodes.len();
+            for (child_start, child_count) in [(start, middle), (start + middle, count - middle)] {
+                nodes.push(Node {
+                    min,
+                    max,
+                    start: child_start,
+                    count: child_count,
+                });
+            }
+            nodes[index].start = left;
+            nodes[index].count = 0;
+            stack.push(left);
+            stack.push(left + 1);
+        }
+
+        Bvh { nodes, triangles }
+    }
+
+    ///Distance along the ray to the nearest triangle hit. `hit` tests one triangle and returns how far along the ray it is hit.
+    pub fn intersect_ray(&self, origin: Vec3, dir: Vec3, mut hit: impl FnMut(usize) -> Option<f32>) -> Option<f32> {
+        let inv_dir = dir.recip();
+        let mut nearest: Option<f32> = None;
+        let mut stack = vec![];
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            match intersect_box(node.min, node.max, origin, inv_dir) {
+                Some(t) if nearest.map_or(true, |nearest| t <= nearest) => {}
+                _ => continue,
+            }
+
+            if node.count > 0 {
+                for tri in &self.triangles[node.start..node.start + node.count] {
+                    if let Some(t) = hit(*tri) {
+                        if nearest.map_or(true, |nearest| t < nearest) {
+                            nearest = Some(t);
+                        }
+                    }
+                }
+            } else {
+                //Visit the nearer child first so the farther one can often be skipped
+                let (left, right) = (node.start, node.start + 1);
+                let distance = |child: usize| {
+                    intersect_box(self.nodes[child].min, self.nodes[child].max, origin, inv_dir).unwrap_or(f32::INFINITY)
+                };
+                if distance(left) <= distance(right) {
+                    stack.push(right);
+                    stack.push(left);
+                } else {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+        nearest
+    }
+
+    ///Triangles in the leaves overlapping the box. Some may not overlap it themselves so callers test them exactly.
+    pub fn query_box(&self, min: Vec3, max: Vec3) -> Vec<usize> {
+        let mut found = vec![];
+        let mut stack = vec![];
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.min.cmpgt(max).any() || node.max.cmplt(min).any() {
+                continue;
+            }
+            if node.count > 0 {
+                found.extend_from_slice(&self.triangles[node.start..node.start + node.count]);
+            } else {
+                stack.push(node.start);
+                stack.push(node.start + 1);
+            }
+        }
+        found
+    }
+}
+
+///Distance along the ray to where it enters the box, 0 if it starts inside
+fn intersect_box(min: Vec3, max: Vec3, origin: Vec3, inv_dir: Vec3) -> Option<f32> {
+    let mut t_enter = 0.0_f32;
+    let mut t_exit = f32::INFINITY;
+
+    for axis in 0..3 {
+        //A ray parallel to the sides is always or never between them, 0 * inf would give NaN
+        if inv_dir[axis].is_infinite() {
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return None;
+            }
+            continue;
+        }
+        let t1 = (min[axis] - origin[axis]) * inv_dir[axis];
+        let t2 = (max[axis] - origin[axis]) * inv_dir[axis];
+        t_enter = t_enter.max(t1.min(t2));
+        t_exit = t_exit.min(t1.max(t2));
+    }
+
+    if t_exit >= t_enter {
+        Some(t_enter)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///Möller–Trumbore, hits in front of the origin only
+    fn hit_triangle(origin: Vec3, dir: Vec3, [a, b, c]: [Vec3; 3]) -> Option<f32> {
+        let (edge1, edge2) = (b - a, c - a);
+        let h = dir.cross(edge2);
+        let det = edge1.dot(h);
+        if det.abs() < 1e-7 {
+            return None;
+        }
+        let s = origin - a;
+        let u = s.dot(h) / det;
+        let q = s.cross(edge1);
+        let v = dir.dot(q) / det;
+        let t = edge2.dot(q) / det;
+        (u >= 0.0 && v >= 0.0 && u + v <= 1.0 && t > 0.0).then(|| t)
+    }
+
+    ///Check the hierarchy finds the same nearest hit as testing every triangle
+    fn assert_matches_brute_force(vertices: &[Vec3], indices: &[u32], rays: &[(Vec3, Vec3)]) -> usize {
+        let bvh = Bvh::new(vertices, indices);
+        let triangle = |tri: usize| [0, 1, 2].map(|i| vertices[indices[tri * 3 + i] as usize]);
+        let mut hits = 0;
+
+        for (origin, dir) in rays {
+            let expected = (0..indices.len() / 3)
+                .filter_map(|tri| hit_triangle(*origin, *dir, triangle(tri)))
+                .reduce(f32::min);
+            let found = bvh.intersect_ray(*origin, *dir, |tri| hit_triangle(*origin, *dir, triangle(tri)));
+            assert_eq!(found, expected, "ray from {} along {}", origin, dir);
+            hits += found.is_some() as usize;
+        }
+        hits
+    }
+
+    ///Small deterministic generator so failures can be reproduced
+    fn get_random(seed: &mut u32) -> f32 {
+        *seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (*seed >> 8) as f32 / (1 << 24) as f32
+    }
+
+    ///Rays along and between the axes through the middle of the area, from every side
+    fn get_rays(center: Vec3, size: f32, seed: &mut u32) -> Vec<(Vec3, Vec3)> {
+        let mut rays = vec![];
+        for _ in 0..200 {
+            let offset = Vec3::new(get_random(seed), get_random(seed), get_random(seed)) * size - size / 2.0;
+            for dir in [Vec3::X, -Vec3::X, Vec3::Y, -Vec3::Y, Vec3::Z, -Vec3::Z] {
+                //Start outside the area on the axis the ray runs along
+                rays.push((center + offset - dir * size * 2.0, dir));
+            }
+            let dir = Vec3::new(get_random(seed), get_random(seed), get_random(seed)) - 0.5;
+            rays.push((center + offset - dir.normalize_or_zero() * size * 2.0, dir.normalize_or_zero()));
+        }
+        rays
+    }
+
+    #[test]
+    fn random_triangles_match_brute_force() {
+        let mut seed = 7;
+        let mut vertices = vec![];
+        for _ in 0..300 {
+            let corner = Vec3::new(get_random(&mut seed), get_random(&mut seed), get_random(&mut seed)) * 100.0;
+            for _ in 0..3 {
+                vertices.push(corner + Vec3::new(get_random(&mut seed), get_random(&mut seed), get_random(&mut seed)) * 15.0);
+            }
+        }
+        let indices: Vec<u32> = (0..vertices.len() as u32).collect();
+
+        let rays = get_rays(Vec3::splat(55.0), 110.0, &mut seed);
+        assert!(assert_matches_brute_force(&vertices, &indices, &rays) > rays.len() / 4);
+    }
+
+    #[test]
+    fn flat_mesh_matches_brute_force() {
+        //A grid in the z = 5 plane, every box has no height
+        let mut vertices = vec![];
+        for y in 0..=20 {
+            for x in 0..=20 {
+                vertices.push(Vec3::new(x as f32, y as f32, 5.0));
+            }
+        }
+        let mut indices = vec![];
+        for y in 0..20 {
+            for x in 0..20 {
+                let corner = y * 21 + x;
+                indices.extend([corner, corner + 1, corner + 22, corner, corner + 22, corner + 21]);
+            }
+        }
+
+        let mut seed = 11;
+        let rays = get_rays(Vec3::new(10.0, 10.0, 5.0), 20.0, &mut seed);
+        assert!(assert_matches_brute_force(&vertices, &indices, &rays) > 0);
+
+        //Straight down onto the plane and along it at its height
+        let bvh = Bvh::new(&vertices, &indices);
+        let down = |x: f32, y: f32| {
+            let origin = Vec3::new(x, y, 10.0);
+            bvh.intersect_ray(origin, -Vec3::Z, |tri| {
+                hit_triangle(origin, -Vec3::Z, [0, 1, 2].map(|i| vertices[indices[tri * 3 + i] as usize]))
+            })
+        };
+        assert_eq!(down(3.3, 7.6), Some(5.0));
+        assert_eq!(down(25.0, 7.6), None);
+    }
+
+    #[test]
+    fn identical_centers_match_brute_force() {
+        //Triangles of every size around the same point can't be split so they stay in one leaf
+        let mut vertices = vec![];
+        for i in 1..=40 {
+            let size = i as f32;
+            let turn = i as f32 * 0.3;
+            let (sin, cos) = turn.sin_cos();
+            vertices.extend([
+                Vec3::new(cos * size, sin * size, -size),
+                Vec3::new(-cos * size, -sin * size, -size),
+                Vec3::new(0.0, 0.0, 2.0 * size),
+            ]);
+        }
+        //The centers of the boxes need to match exactly, so keep the z extents symmetric
+        for tri in vertices.chunks_exact_mut(3) {
+            let center = tri.iter().fold(Vec3::splat(f32::INFINITY), |min, v| min.min(*v))
+                + tri.iter().fold(Vec3::splat(f32::NEG_INFINITY), |max, v| max.max(*v));
+            for v in tri.iter_mut() {
+                *v -= center / 2.0;
+            }
+        }
+        let indices: Vec<u32> = (0..vertices.len() as u32).collect();
+
+        let mut seed = 3;
+        let rays = get_rays(Vec3::ZERO, 60.0, &mut seed);
+        assert!(assert_matches_brute_force(&vertices, &indices, &rays) > 0);
+    }
+
+    #[test]
+    fn axis_parallel_rays_on_box_sides() {
+        let (min, max) = (Vec3::ZERO, Vec3::splat(10.0));
+
+        //Running along a face of the box still goes through it
+        assert_eq!(intersect_box(min, max, Vec3::new(-5.0, 0.0, 5.0), Vec3::X.recip()), Some(5.0));
+        assert_eq!(intersect_box(min, max, Vec3::new(5.0, 10.0, 20.0), (-Vec3::Z).recip()), Some(10.0));
+        assert_eq!(intersect_box(min, max, Vec3::new(-5.0, 10.5, 5.0), Vec3::X.recip()), None);
+        //Starting inside
+        assert_eq!(intersect_box(min, max, Vec3::splat(5.0), Vec3::Y.recip()), Some(0.0));
+        //Pointing away
+        assert_eq!(intersect_box(min, max, Vec3::new(-5.0, 5.0, 5.0), (-Vec3::X).recip()), None);
+    }
+
+    #[test]
+    fn box_query_finds_every_overlapping_triangle() {
+        let mut seed = 5;
+        let mut vertices = vec![];
+        for _ in 0..300 {
+            let corner = Vec3::new(get_random(&mut seed), get_random(&mut seed), get_random(&mut seed)) * 100.0;
+            for _ in 0..3 {
+                vertices.push(corner + Vec3::new(get_random(&mut seed), get_random(&mut seed), get_random(&mut seed)) * 10.0);
+            }
+        }
+        let indices: Vec<u32> = (0..vertices.len() as u32).collect();
+        let bvh = Bvh::new(&vertices, &indices);
+
+        for _ in 0..50 {
+            let min = Vec3::new(get_random(&mut seed), get_random(&mut seed), get_random(&mut seed)) * 100.0;
+            let max = min + Vec3::new(get_random(&mut seed), get_random(&mut seed), get_random(&mut seed)) * 30.0;
+
+            let found = bvh.query_box(min, max);
+            let mut unique = found.clone();
+            unique.sort_unstable();
+            unique.dedup();
+            assert_eq!(unique.len(), found.len());
+
+            for (tri, corners) in vertices.chunks_exact(3).enumerate() {
+                let tri_min = corners[0].min(corners[1]).min(corners[2]);
+                let tri_max = corners[0].max(corners[1]).max(corners[2]);
+                let overlaps = !(tri_min.cmpgt(max).any() || tri_max.cmplt(min).any());
+                assert!(!overlaps || found.contains(&tri), "triangle {} overlaps the box but was not found", tri);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_mesh_has_no_hits() {
+        let bvh = Bvh::new(&[], &[]);
+        assert_eq!(bvh.intersect_ray(Vec3::ZERO, Vec3::X, |_| Some(1.0)), None);
+        assert!(bvh.query_box(Vec3::ZERO, Vec3::ONE).is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::model::{get_object_arg, GUIData};
+use crate::mesh::{load_meshes, Mesh};
+use crate::plate::Plate;
+use crate::Errors;
+use glam::{Vec2, Vec3};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "Usage: gladius-gui [FILE]... [--settings SETTINGS] [--project PLATE] [--slice [--output GCODE] [--repair]]
+
+  FILE                 Models, settings, plates or G-code to open
+  -s, --settings FILE  Settings file to slice with
+  -p, --project FILE   Plate to open, loaded before any other file
+      --slice          Slice without opening a window
+  -o, --output FILE    Where to write the G-code when slicing without a window
+      --repair         Repair models with problems before slicing without a window
+  -h, --help           Show this message";
+
+///How often the slicer is checked on while slicing without a window
+const SLICE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+///Space left between models laid out on the bed in mm
+const ARRANGE_GAP: f32 = 5.0;
+
+///Files to open on launch and whether to slice them without a window
+#[derive(Debug, Default)]
+pub struct Arguments {
+    pub files: Vec<PathBuf>,
+    pub settings: Option<String>,
+    pub project: Option<String>,
+    pub slice: bool,
+    pub output: Option<PathBuf>,
+    pub repair: bool,
+    pub help: bool,
+}
+
+impl Arguments {
+    ///Parse the arguments without the program name
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut arguments = Arguments::default();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a file", arg));
+            match arg.as_str() {
+                "-s" | "--settings" => arguments.settings = Some(value()?),
+                "-p" | "--project" => arguments.project = Some(value()?),
+                "-o" | "--output" => arguments.output = Some(PathBuf::from(value()?)),
+                "--slice" => arguments.slice = true,
+                "--repair" => arguments.repair = true,
+                "-h" | "--help" => arguments.help = true,
+                flag if flag.starts_with('-') => return Err(format!("Unknown option {}", flag)),
+                _ => arguments.files.push(PathBuf::from(arg)),
+            }
+        }
+
+        if arguments.output.is_some() && !arguments.slice {
+            return Err(String::from("--output can only be used with --slice"));
+        }
+        if arguments.repair && !arguments.slice {
+            return Err(String::from("--repair can only be used with --slice"));
+        }
+        Ok(arguments)
+    }
+}
+
+fn describe(error: &Errors) -> String {
+    let (code, message) = error.get_code_and_message();
+    format!("Error {:#X}: {}", code, message)
+}
+
+///Warn about problems in the mesh, repairing it if asked to
+fn check_mesh(mesh: &mut Mesh, repair: bool) -> Result<(), String> {
+    if repair && mesh.health.is_repairable() {
+        mesh.repair().map_err(|e| describe(&e))?;
+        eprintln!("Repaired {}", mesh.name);
+    }
+    for (issue, count) in mesh.health.get_issues() {
+        eprintln!("Warning: {} has {} {}", mesh.name, count, issue.replace('_', " "));
+    }
+    Ok(())
+}
+
+///Footprint of the model on the bed
+fn get_footprint(mesh: &mut Mesh) -> Vec2 {
+    mesh.revalidate_cache();
+    mesh.aabb
+        .as_ref()
+        .map(|aabb| Vec2::new(aabb.max_x - aabb.min_x, aabb.max_y - aabb.min_y))
+        .unwrap_or_default()
+}
+
+///Lay the models out in rows around the middle of the bed, a row is ended before it gets wider than the bed
+fn arrange(meshes: &mut [Mesh], area: Vec3) {
+    let footprints: Vec<Vec2> = meshes.iter_mut().map(get_footprint).collect();
+
+    //Indices of the models in each row with the size of the row
+    let mut rows: Vec<(Vec<usize>, Vec2)> = vec![];
+    for (index, footprint) in footprints.iter().enumerate() {
+        match rows.last_mut() {
+            Some((row, size)) if size.x + ARRANGE_GAP + footprint.x <= area.x => {
+                row.push(index);
+                size.x += ARRANGE_GAP + footprint.x;
+                size.y = size.y.max(footprint.y);
+            }
+            _ => rows.push((vec![index], *footprint)),
+        }
+    }
+
+    let depth = rows.iter().map(|(_, size)| size.y).sum::<f32>() + ARRANGE_GAP * rows.len().saturating_sub(1) as f32;
+    let mut y = (area.y - depth) / 2.0;
+    for (row, size) in &rows {
+        let mut x = (area.x - size.x) / 2.0;
+        for index in row {
+            //Models are centered on their location
+            let footprint = footprints[*index];
+            meshes[*index].set_location(Vec3::new(x + footprint.x / 2.0, y + size.y / 2.0, 0.0));
+            meshes[*index].revalidate_cache();
+            x += footprint.x + ARRANGE_GAP;
+        }
+        y += size.y + ARRANGE_GAP;
+    }
+}
+
+///Slice the plate and models without opening a window and write the G-code. Returns the file written.
+pub fn slice_headless(arguments: &Arguments, gui_data: &mut GUIData) -> Result<PathBuf, String> {
+    let mut placed = vec![];
+    let mut meshes: HashMap<String, Vec<Mesh>> = HashMap::new();
+
+    if let Some(project) = arguments.project.as_ref() {
+        let plate = Plate::load(project).map_err(|e| describe(&e))?;
+
+        //Match placements up with the models in their file the same way the plate is opened
+        for placement in &plate.objects {
+            let file_meshes = match meshes.entry(placement.file_path.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut file_meshes = load_meshes(&placement.file_path).map_err(|e| describe(&e))?;
+                    for mesh in file_meshes.iter_mut() {
+                        check_mesh(mesh, arguments.repair)?;
+                    }
+                    entry.insert(file_meshes)
+                }
+            };
+            //Copies of a model are placed from the same mesh
+            if let Some(mesh) = file_meshes.iter().find(|mesh| mesh.name == placement.name) {
+                let mut mesh = mesh.clone();
+                mesh.set_location(Vec3::from(placement.location));
+                mesh.set_scale(Vec3::from(placement.scale));
+                placed.push(mesh);
+            }
+        }
+
+        gui_data.set_settings_path(plate.settings_path);
+        gui_data.set_layer_insertions(plate.layer_insertions);
+    }
+
+    let mut loose = vec![];
+    for file in &arguments.files {
+        let path = file.to_str().ok_or_else(|| format!("{} is not a valid path", file.display()))?;
+        for mut mesh in load_meshes(path).map_err(|e| describe(&e))? {
+            check_mesh(&mut mesh, arguments.repair)?;
+            loose.push(mesh);
+        }
+    }
+
+    let area = gui_data.get_print_area_size();
+    arrange(&mut loose, area);
+
+    //The meshes are kept until the slice is done as their STL copies are deleted with them
+    let mut sliced = placed;
+    sliced.extend(loose);
+    let mut args = vec![];
+    for mesh in sliced.iter_mut() {
+        mesh.revalidate_cache();
+        if !mesh.aabb.as_ref().map_or(false, |aabb| aabb.is_inside(area)) {
+            return Err(format!("{} does not fit in the build volume", mesh.name));
+        }
+        args.push(get_object_arg(mesh.get_slice_path(), mesh.get_model_matrix()));
+    }
+
+    if let Some(settings) = arguments.settings.as_ref() {
+        gui_data.set_settings_path(settings.clone());
+    }
+    if args.is_empty() {
+        return Err(String::from("No models to slice"));
+    }
+    if gui_data.get_settings_path().is_empty() {
+        return Err(String::from("No settings file to slice with, use --settings"));
+    }
+
+    gui_data.start_slice_with_args(args);
+
+    let mut state = String::new();
+    while gui_data.is_command_running() {
+        std::thread::sleep(SLICE_POLL_INTERVAL);
+        let new_state = gui_data.get_command_state();
+        if new_state != state && !new_state.is_empty() {
+            eprintln!("{}", new_state);
+            state = new_state;
+        }
+    }
+
+    if let Some(error) = gui_data.get_errors().first() {
+        return Err(describe(error));
+    }
+
+    //Write in the format the output file asks for if a preset has it
+    if let Some(extension) = arguments.output.as_ref().and_then(|output| output.extension()).and_then(OsStr::to_str) {
+        let preset = gui_data
+            .get_export_presets()
+            .iter()
+            .position(|preset| preset.format.get_filter_extension().eq_ignore_ascii_case(extension));
+        if let Some(index) = preset {
+            gui_data.set_selected_export_preset(index);
+        }
+    }
+
+    let output = arguments
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(gui_data.get_export_file_name()));
+
+    let gcode = gui_data.get_exported_gcode(None).ok_or_else(|| {
+        gui_data
+            .get_errors()
+            .first()
+            .map(describe)
+            .unwrap_or_else(|| String::from("The slicer did not return any G-code"))
+    })?;
+    std::fs::write(&output, gcode).map_err(|e| format!("{} could not be written: {}", output.display(), e))?;
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::tests::get_box;
+    use crate::mesh::AABB;
+
+    #[test]
+    fn single_model_is_centered() {
+        let mut meshes = vec![get_box(Vec3::new(20.0, 10.0, 5.0))];
+        arrange(&mut meshes, Vec3::new(250.0, 210.0, 200.0));
+
+        assert_eq!(*meshes[0].get_location(), Vec3::new(125.0, 105.0, 0.0));
+        let aabb = meshes[0].aabb.as_ref().unwrap();
+        assert_eq!((aabb.min_x, aabb.max_x, aabb.min_y, aabb.max_y, aabb.min_z), (115.0, 135.0, 100.0, 110.0, 0.0));
+    }
+
+    #[test]
+    fn models_are_laid_out_in_rows_without_overlap() {
+        let area = Vec3::new(100.0, 100.0, 100.0);
+        let mut meshes: Vec<Mesh> = (0..5).map(|_| get_box(Vec3::new(30.0, 20.0, 10.0))).collect();
+        arrange(&mut meshes, area);
+
+        let boxes: Vec<&AABB> = meshes.iter().map(|mesh| mesh.aabb.as_ref().unwrap()).collect();
+        assert!(boxes.iter().all(|aabb| aabb.is_inside(area)));
+        for (i, a) in boxes.iter().enumerate() {
+            for b in &boxes[i + 1..] {
+                let overlap = a.min_x < b.max_x && b.min_x < a.max_x && a.min_y < b.max_y && b.min_y < a.max_y;
+                assert!(!overlap);
+            }
+        }
+        //Three fit across the bed so the last two start a second row
+        assert_eq!(boxes[0].min_y, boxes[2].min_y);
+        assert!(boxes[3].min_y > boxes[2].max_y);
+    }
+
+    #[test]
+    fn models_too_big_for_the_bed_are_not_inside() {
+        let area = Vec3::new(100.0, 100.0, 100.0);
+        let mut meshes = vec![get_box(Vec3::new(120.0, 20.0, 10.0))];
+        arrange(&mut meshes, area);
+        assert!(!meshes[0].aabb.as_ref().unwrap().is_inside(area));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::toolpath::{ExtrusionMove, FeatureType, LayerMoves};
+use egui::Color32;
+
+///Property of the extrusions that is used to color the toolpath
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorScheme {
+    FeatureType,
+    Speed,
+    Width,
+    LayerTime,
+    FanSpeed,
+    Temperature,
+}
+
+impl ColorScheme {
+    pub const ALL: [ColorScheme; 6] = [
+        ColorScheme::FeatureType,
+        ColorScheme::Speed,
+        ColorScheme::Width,
+        ColorScheme::LayerTime,
+        ColorScheme::FanSpeed,
+        ColorScheme::Temperature,
+    ];
+
+    pub fn get_translation_key(&self) -> &'static str {
+        match self {
+            ColorScheme::FeatureType => "color_feature_type",
+            ColorScheme::Speed => "color_speed",
+            ColorScheme::Width => "color_width",
+            ColorScheme::LayerTime => "color_layer_time",
+            ColorScheme::FanSpeed => "color_fan_speed",
+            ColorScheme::Temperature => "color_temperature",
+        }
+    }
+
+    pub fn get_unit(&self) -> &'static str {
+        match self {
+            ColorScheme::FeatureType => "",
+            ColorScheme::Speed => "mm/s",
+            ColorScheme::Width => "mm",
+            ColorScheme::LayerTime => "s",
+            ColorScheme::FanSpeed => "%",
+            ColorScheme::Temperature => "°C",
+        }
+    }
+
+    ///Categorical schemes have a color per category rather than a gradient
+    pub fn is_categorical(&self) -> bool {
+        *self == ColorScheme::FeatureType
+    }
+
+    fn get_value(&self, layer: &LayerMoves, extrusion: &ExtrusionMove) -> f64 {
+        match self {
+            ColorScheme::FeatureType => Self::get_category(extrusion) as f64,
+            ColorScheme::Speed => extrusion.state.speed,
+            ColorScheme::Width => extrusion.width as f64,
+            ColorScheme::LayerTime => layer.time,
+            ColorScheme::FanSpeed => extrusion.state.fan_speed,
+            ColorScheme::Temperature => extrusion.state.temperature,
+        }
+    }
+
+    ///Index of the feature type of the extrusion, each feature keeps its color between slices
+    fn get_category(extrusion: &ExtrusionMove) -> usize {
+        FeatureType::ALL
+            .iter()
+            .position(|feature| *feature == extrusion.state.feature)
+            .unwrap_or(FeatureType::ALL.len() - 1)
+    }
+}
+
+const CATEGORY_COLORS: [Color32; 8] = [
+    Color32::from_rgb(255, 128, 0),
+    Color32::from_rgb(0, 160, 255),
+    Color32::from_rgb(60, 200, 60),
+    Color32::from_rgb(230, 50, 50),
+    Color32::from_rgb(200, 80, 220),
+    Color32::from_rgb(240, 220, 40),
+    Color32::from_rgb(40, 220, 200),
+    Color32::from_rgb(160, 160, 160),
+];
+
+const GRADIENT_COLORS: [Color32; 5] = [
+    Color32::from_rgb(0, 0, 255),
+    Color32::from_rgb(0, 255, 255),
+    Color32::from_rgb(0, 255, 0),
+    Color32::from_rgb(255, 255, 0),
+    Color32::from_rgb(255, 0, 0),
+];
+
+///Sample the gradient used for continuous color schemes, t is clamped to [0,1]
+pub fn gradient_color(t: f32) -> Color32 {
+    let scaled = t.max(0.0).min(1.0) * (GRADIENT_COLORS.len() - 1) as f32;
+    let index = (scaled as usize).min(GRADIENT_COLORS.len() - 2);
+    let fract = scaled - index as f32;
+
+    let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * fract).round() as u8;
+    let (a, b) = (GRADIENT_COLORS[index], GRADIENT_COLORS[index + 1]);
+
+    Color32::from_rgb(lerp(a.r(), b.r()), lerp(a.g(), b.g()), lerp(a.b(), b.b()))
+}
+
+///Maps the extrusions of a toolpath to colors for a given scheme
+#[derive(Clone, Debug)]
+pub struct ColorMap {
+    scheme: ColorScheme,
+    min: f64,
+    max: f64,
+    ///Feature types present in the toolpath
+    categories: Vec<FeatureType>,
+}
+
+impl ColorMap {
+    pub fn new(scheme: ColorScheme, layers: &[LayerMoves]) -> Self {
+        let values: Vec<f64> = layers
+            .iter()
+            .flat_map(|layer| {
+                layer
+                    .extrusions
+                    .iter()
+                    .map(move |extrusion| scheme.get_value(layer, extrusion))
+            })
+            .collect();
+
+        let (min, max) = values
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), v| {
+                (min.min(*v), max.max(*v))
+            });
+
+        let categories = if scheme.is_categorical() {
+            FeatureType::ALL
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| values.iter().any(|value| *value as usize == *index))
+                .map(|(_, feature)| *feature)
+                .collect()
+        } else {
+            vec![]
+        };
+
+        ColorMap {
+            scheme,
+            min,
+            max,
+            categories,
+        }
+    }
+
+    ///Color map covering the values of both maps, which must be of the same scheme
+    pub fn merge(&self, other: &ColorMap) -> ColorMap {
+        ColorMap {
+            scheme: self.scheme,
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+            categories: FeatureType::ALL
+                .iter()
+                .filter(|feature| self.categories.contains(feature) || other.categories.contains(feature))
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn get_scheme(&self) -> ColorScheme {
+        self.scheme
+    }
+
+    ///Return the minimum and maximum value of the scheme, None if there are no extrusions
+    pub fn get_range(&self) -> Option<(f64, f64)> {
+        if self.min <= self.max {
+            Some((self.min, self.max))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_color(&self, layer: &LayerMoves, extrusion: &ExtrusionMove) -> Color32 {
+        self.get_color_for_value(self.scheme.get_value(layer, extrusion))
+    }
+
+    pub fn get_color_for_value(&self, value: f64) -> Color32 {
+        if self.scheme.is_categorical() {
+            CATEGORY_COLORS[value as usize % CATEGORY_COLORS.len()]
+        } else if self.max - self.min > f64::EPSILON {
+            gradient_color(((value - self.min) / (self.max - self.min)) as f32)
+        } else {
+            gradient_color(0.5)
+        }
+    }
+
+    ///Legend entries for categorical schemes, the color and translation key of each feature type present
+    pub fn get_legend(&self) -> Vec<(Color32, &'static str)> {
+        self.categories
+            .iter()
+            .map(|feature| {
+                let index = FeatureType::ALL.iter().position(|f| f == feature).unwrap_or(0);
+                (self.get_color_for_value(index as f64), feature.get_translation_key())
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,175 @@
+use crate::binary_gcode::write_binary_gcode;
+use crate::post_process::{apply_layer_insertions, post_process, LayerInsertion, PostProcessor, TemplateValues};
+use crate::thumbnail::{format_thumbnails, Thumbnail};
+use crate::Errors;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::sync::Arc;
+
+///How G-code is written to disk
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ExportFormat {
+    Plain,
+    ///Gzip compressed text
+    Compressed,
+    ///Binary G-code with metadata and thumbnail blocks
+    Binary,
+}
+
+impl ExportFormat {
+    pub const ALL: [ExportFormat; 3] = [
+        ExportFormat::Plain,
+        ExportFormat::Compressed,
+        ExportFormat::Binary,
+    ];
+
+    pub fn get_translation_key(&self) -> &'static str {
+        match self {
+            ExportFormat::Plain => "export_plain",
+            ExportFormat::Compressed => "export_compressed",
+            ExportFormat::Binary => "export_binary",
+        }
+    }
+
+    pub fn get_extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Plain => "gcode",
+            ExportFormat::Compressed => "gcode.gz",
+            ExportFormat::Binary => "bgcode",
+        }
+    }
+
+    ///Extension used to filter the file dialog, which only matches the last extension
+    pub fn get_filter_extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Plain => "gcode",
+            ExportFormat::Compressed => "gz",
+            ExportFormat::Binary => "bgcode",
+        }
+    }
+
+    ///Thumbnails are written as comments unless the format has its own blocks for them
+    pub fn has_thumbnail_blocks(&self) -> bool {
+        *self == ExportFormat::Binary
+    }
+}
+
+///Output settings for a printer or print host
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ExportPreset {
+    pub name: String,
+    pub format: ExportFormat,
+    ///Default file name without the extension, see fill_template for the placeholders
+    pub filename_template: String,
+}
+
+impl ExportPreset {
+    pub fn get_defaults() -> Vec<ExportPreset> {
+        let filename_template = String::from("{object}_{layer_height}mm_{material}_{print_time}");
+        vec![
+            ExportPreset {
+                name: String::from("G-code"),
+                format: ExportFormat::Plain,
+                filename_template: filename_template.clone(),
+            },
+            ExportPreset {
+                name: String::from("Compressed G-code"),
+                format: ExportFormat::Compressed,
+                filename_template: filename_template.clone(),
+            },
+            ExportPreset {
+                name: String::from("Binary G-code"),
+                format: ExportFormat::Binary,
+                filename_template,
+            },
+        ]
+    }
+}
+
+///Replace characters that aren't allowed in file names on common platforms
+pub fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+///Encode the processed G-code in the given format
+pub fn export_gcode(gcode: &str, format: ExportFormat, thumbnails: &[Thumbnail], values: &TemplateValues) -> std::io::Result<Vec<u8>> {
+    match format {
+        ExportFormat::Plain => Ok(gcode.as_bytes().to_vec()),
+        ExportFormat::Compressed => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(gcode.as_bytes())?;
+            encoder.finish()
+        }
+        ExportFormat::Binary => Ok(write_binary_gcode(gcode, thumbnails, values)),
+    }
+}
+
+///Everything an export needs, copied out of the GUI so the G-code can be processed on another thread
+pub struct ExportJob {
+    pub gcode: Arc<String>,
+    pub layer_insertions: Vec<LayerInsertion>,
+    pub post_processors: Vec<PostProcessor>,
+    pub values: TemplateValues,
+    pub format: ExportFormat,
+    ///Rendered beforehand as rendering needs the display
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+impl ExportJob {
+    ///G-code with the layer insertions, thumbnail comments and post-processing pipeline applied
+    pub fn process(&self) -> Result<String, Errors> {
+        let mut gcode = apply_layer_insertions(&self.gcode, &self.layer_insertions);
+        if !self.format.has_thumbnail_blocks() {
+            gcode.insert_str(0, &format_thumbnails(&self.thumbnails));
+        }
+        post_process(gcode, &self.post_processors, &self.values)
+    }
+
+    ///Processed G-code encoded in the format of the job
+    pub fn run(&self) -> Result<Vec<u8>, Errors> {
+        let gcode = self.process()?;
+        export_gcode(&gcode, self.format, &self.thumbnails, &self.values).map_err(|_| Errors::GCodeNotExportable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    const GCODE: &str = "G28\nG1 Z0.2\nG1 X10 E1\n";
+
+    #[test]
+    fn plain_export_is_the_gcode() {
+        let bytes = export_gcode(GCODE, ExportFormat::Plain, &[], &TemplateValues::default()).unwrap();
+        assert_eq!(bytes, GCODE.as_bytes());
+    }
+
+    #[test]
+    fn compressed_export_round_trips() {
+        let bytes = export_gcode(GCODE, ExportFormat::Compressed, &[], &TemplateValues::default()).unwrap();
+
+        let mut decoded = String::new();
+        GzDecoder::new(bytes.as_slice()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, GCODE);
+    }
+
+    #[test]
+    fn binary_export_is_binary_gcode() {
+        let values = TemplateValues::default();
+        let bytes = export_gcode(GCODE, ExportFormat::Binary, &[], &values).unwrap();
+        assert_eq!(bytes, write_binary_gcode(GCODE, &[], &values));
+        assert!(bytes.starts_with(b"GCDE"));
+    }
+}
@@ -0,0 +1,642 @@
+use crate::materials::MaterialProfile;
+use gladius_shared::types::{Command, StateChange};
+use geo::Coordinate;
+
+///Diameter of the filament used to convert extrusion lengths into widths
+pub const DEFAULT_FILAMENT_DIAMETER: f64 = 1.75;
+
+///Density of PLA in g/cm^3
+pub const DEFAULT_FILAMENT_DENSITY: f64 = 1.24;
+
+///Layer height assumed until two layers with extrusions have been seen
+const DEFAULT_LAYER_HEIGHT: f64 = 0.2;
+
+///Maximum length of the segments arcs are split into
+const ARC_SEGMENT_LENGTH: f64 = 1.0;
+
+const EPSILON: f64 = 0.000_001;
+
+///The commands parsed from a G-code file along with statistics about the print
+#[derive(Debug, Default)]
+pub struct ParsedGCode {
+    pub commands: Vec<Command>,
+    ///The zero based line each command was parsed from
+    pub command_lines: Vec<usize>,
+    pub statistics: GCodeStatistics,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GCodeStatistics {
+    ///Volume of plastic in cm^3
+    pub plastic_volume: f64,
+    ///Weight of plastic in grams
+    pub plastic_weight: f64,
+    ///Print time in seconds
+    pub total_time: f64,
+}
+
+impl GCodeStatistics {
+    pub fn get_hours_minutes(&self) -> (usize, usize) {
+        let minutes = (self.total_time / 60.0) as usize;
+        (minutes / 60, minutes % 60)
+    }
+}
+
+///Position and modes of the printer as the G-code is executed
+struct ParserState {
+    x: f64,
+    y: f64,
+    z: f64,
+    e: f64,
+    ///Feedrate in mm/min
+    feedrate: f64,
+    absolute_positioning: bool,
+    absolute_extrusion: bool,
+    layer_index: usize,
+    ///Highest z extruded at, moving above it only starts a layer once something is extruded there
+    max_z: f64,
+    ///Command of the last change in z, given the new layer index if the change starts a layer
+    last_layer_change: Option<usize>,
+    last_extrusion_z: Option<f64>,
+    thickness: f64,
+    retracted: bool,
+}
+
+struct Parser {
+    state: ParserState,
+    filament_area: f64,
+    filament_length: f64,
+    total_time: f64,
+    output: ParsedGCode,
+}
+
+impl Parser {
+    fn push(&mut self, line: usize, command: Command) {
+        self.output.commands.push(command);
+        self.output.command_lines.push(line);
+    }
+
+    fn push_state(&mut self, line: usize, new_state: StateChange) {
+        self.push(line, Command::SetState { new_state });
+    }
+
+    fn set_z(&mut self, line: usize, z: f64) {
+        if (z - self.state.z).abs() < EPSILON {
+            return;
+        }
+        self.state.z = z;
+
+        //Z hops return to the layer before extruding so they keep its index
+        self.state.last_layer_change = Some(self.output.commands.len());
+        self.push(
+            line,
+            Command::LayerChange {
+                z,
+                index: self.state.layer_index,
+            },
+        );
+    }
+
+    ///Count a new layer at z now that it is extruded at
+    fn start_layer(&mut self, z: f64) {
+        self.state.max_z = z;
+        self.state.layer_index += 1;
+
+        let layer_index = self.state.layer_index;
+        if let Some(Command::LayerChange { index, .. }) = self
+            .state
+            .last_layer_change
+            .and_then(|command| self.output.commands.get_mut(command))
+        {
+            *index = layer_index;
+        }
+    }
+
+    ///Move in XY to the given point while extruding the given length of filament
+    fn move_xy(&mut self, line: usize, x: f64, y: f64, e_delta: f64) {
+        let start = Coordinate {
+            x: self.state.x,
+            y: self.state.y,
+        };
+        let end = Coordinate { x, y };
+        let length = ((end.x - start.x).powi(2) + (end.y - start.y).powi(2)).sqrt();
+
+        if length < EPSILON {
+            return;
+        }
+
+        if self.state.feedrate > 0.0 {
+            self.total_time += length / (self.state.feedrate / 60.0);
+        }
+
+        if e_delta > EPSILON {
+            self.set_retracted(line, false);
+
+            let z = self.state.z;
+            if self
+                .state
+                .last_extrusion_z
+                .map(|last_z| (z - last_z).abs() > EPSILON)
+                .unwrap_or(true)
+            {
+                self.state.thickness = match self.state.last_extrusion_z {
+                    Some(last_z) if z > last_z => z - last_z,
+                    Some(_) => self.state.thickness,
+                    None if z > EPSILON => z,
+                    None => DEFAULT_LAYER_HEIGHT,
+                };
+                self.state.last_extrusion_z = Some(z);
+            }
+            if z > self.state.max_z + EPSILON {
+                self.start_layer(z);
+            }
+
+            let thickness = self.state.thickness;
+            let width = e_delta * self.filament_area / (length * thickness);
+            self.filament_length += e_delta;
+
+            self.push(
+                line,
+                Command::MoveAndExtrude {
+                    start,
+                    end,
+                    thickness,
+                    width,
+                },
+            );
+        } else {
+            //Retracting while moving is a wipe
+            if e_delta < -EPSILON {
+                self.filament_length += e_delta;
+                self.set_retracted(line, true);
+            }
+            self.push(line, Command::MoveTo { end });
+        }
+
+        self.state.x = x;
+        self.state.y = y;
+    }
+
+    fn set_retracted(&mut self, line: usize, retract: bool) {
+        if retract != self.state.retracted {
+            self.state.retracted = retract;
+            self.push_state(
+                line,
+                StateChange {
+                    retract: Some(retract),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    fn set_feedrate(&mut self, line: usize, feedrate: f64) {
+        if (feedrate - self.state.feedrate).abs() > EPSILON {
+            self.state.feedrate = feedrate;
+            self.push_state(
+                line,
+                StateChange {
+                    movement_speed: Some(feedrate / 60.0),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    fn linear_move(&mut self, line: usize, words: &Words) {
+        if let Some(f) = words.get('F') {
+            self.set_feedrate(line, f);
+        }
+
+        let (x, y, z) = if self.state.absolute_positioning {
+            (
+                words.get('X').unwrap_or(self.state.x),
+                words.get('Y').unwrap_or(self.state.y),
+                words.get('Z').unwrap_or(self.state.z),
+            )
+        } else {
+            (
+                self.state.x + words.get('X').unwrap_or(0.0),
+                self.state.y + words.get('Y').unwrap_or(0.0),
+                self.state.z + words.get('Z').unwrap_or(0.0),
+            )
+        };
+        let e_delta = self.take_extrusion(words);
+
+        self.set_z(line, z);
+
+        let moves_xy = (x - self.state.x).abs() > EPSILON || (y - self.state.y).abs() > EPSILON;
+        if moves_xy {
+            self.move_xy(line, x, y, e_delta);
+        } else if e_delta.abs() > EPSILON {
+            //Extrusion without movement is either a retraction or an unretraction
+            self.filament_length += e_delta;
+            self.set_retracted(line, e_delta < 0.0);
+        }
+    }
+
+    fn arc_move(&mut self, line: usize, words: &Words, clockwise: bool) {
+        if let Some(f) = words.get('F') {
+            self.set_feedrate(line, f);
+        }
+
+        let (start_x, start_y) = (self.state.x, self.state.y);
+        let (end_x, end_y) = if self.state.absolute_positioning {
+            (
+                words.get('X').unwrap_or(start_x),
+                words.get('Y').unwrap_or(start_y),
+            )
+        } else {
+            (
+                start_x + words.get('X').unwrap_or(0.0),
+                start_y + words.get('Y').unwrap_or(0.0),
+            )
+        };
+        let e_delta = self.take_extrusion(words);
+
+        let center = match (words.get('I'), words.get('J'), words.get('R')) {
+            (None, None, Some(r)) => arc_center_from_radius(start_x, start_y, end_x, end_y, r, clockwise),
+            (i, j, _) => Some((start_x + i.unwrap_or(0.0), start_y + j.unwrap_or(0.0))),
+        };
+
+        let (center_x, center_y) = match center {
+            Some(center) => center,
+            None => {
+                self.move_xy(line, end_x, end_y, e_delta);
+                return;
+            }
+        };
+
+        let radius = ((start_x - center_x).powi(2) + (start_y - center_y).powi(2)).sqrt();
+        let start_angle = (start_y - center_y).atan2(start_x - center_x);
+        let end_angle = (end_y - center_y).atan2(end_x - center_x);
+
+        let mut sweep = end_angle - start_angle;
+        if clockwise && sweep >= -EPSILON {
+            sweep -= std::f64::consts::TAU;
+        } else if !clockwise && sweep <= EPSILON {
+            sweep += std::f64::consts::TAU;
+        }
+
+        let segments = ((sweep.abs() * radius) / ARC_SEGMENT_LENGTH).ceil().max(1.0) as usize;
+
+        for segment in 1..=segments {
+            let (x, y) = if segment == segments {
+                (end_x, end_y)
+            } else {
+                let angle = start_angle + sweep * segment as f64 / segments as f64;
+                (
+                    center_x + radius * angle.cos(),
+                    center_y + radius * angle.sin(),
+                )
+            };
+            self.move_xy(line, x, y, e_delta / segments as f64);
+        }
+    }
+
+    ///Return the relative extrusion of the move and update the extruder position
+    fn take_extrusion(&mut self, words: &Words) -> f64 {
+        match words.get('E') {
+            Some(e) if self.state.absolute_extrusion => {
+                let delta = e - self.state.e;
+                self.state.e = e;
+                delta
+            }
+            Some(e) => e,
+            None => 0.0,
+        }
+    }
+
+    fn parse_line(&mut self, line: usize, text: &str) {
+        let words = Words::parse(text);
+
+        let (letter, number) = match words.0.first() {
+            Some(first) => *first,
+            None => return,
+        };
+
+        match (letter, number as u32, (number * 10.0).round() as u32 % 10) {
+            ('G', 0, 0) | ('G', 1, 0) => self.linear_move(line, &words),
+            ('G', 2, 0) => self.arc_move(line, &words, true),
+            ('G', 3, 0) => self.arc_move(line, &words, false),
+            ('G', 4, 0) => {
+                let msec = words
+                    .get('P')
+                    .or_else(|| words.get('S').map(|s| s * 1000.0))
+                    .unwrap_or(0.0);
+                self.total_time += msec / 1000.0;
+                self.push(line, Command::Delay { msec: msec as u64 });
+            }
+            ('G', 10, 0) => self.set_retracted(line, true),
+            ('G', 11, 0) => self.set_retracted(line, false),
+            ('G', 90, 0) => {
+                self.state.absolute_positioning = true;
+                self.state.absolute_extrusion = true;
+            }
+            ('G', 91, 0) => {
+                self.state.absolute_positioning = false;
+                self.state.absolute_extrusion = false;
+            }
+            ('G', 92, 0) => {
+                if let Some(x) = words.get('X') {
+                    self.state.x = x;
+                }
+                if let Some(y) = words.get('Y') {
+                    self.state.y = y;
+                }
+                if let Some(z) = words.get('Z') {
+                    self.state.z = z;
+                }
+                if let Some(e) = words.get('E') {
+                    self.state.e = e;
+                }
+            }
+            ('M', 82, 0) => self.state.absolute_extrusion = true,
+            ('M', 83, 0) => self.state.absolute_extrusion = false,
+            ('M', 104, 0) | ('M', 109, 0) => {
+                if let Some(temp) = words.get('S').or_else(|| words.get('R')) {
+                    self.push_state(
+                        line,
+                        StateChange {
+                            extruder_temp: Some(temp),
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+            ('M', 140, 0) | ('M', 190, 0) => {
+                if let Some(temp) = words.get('S').or_else(|| words.get('R')) {
+                    self.push_state(
+                        line,
+                        StateChange {
+                            bed_temp: Some(temp),
+                            ..Default::default()
+                        },
+                    );
+                }
+            }
+            ('M', 106, 0) => {
+                let speed = words.get('S').unwrap_or(255.0);
+                self.push_state(
+                    line,
+                    StateChange {
+                        fan_speed: Some(speed / 255.0 * 100.0),
+                        ..Default::default()
+                    },
+                );
+            }
+            ('M', 107, 0) => self.push_state(
+                line,
+                StateChange {
+                    fan_speed: Some(0.0),
+                    ..Default::default()
+                },
+            ),
+            _ => {}
+        }
+    }
+}
+
+fn arc_center_from_radius(
+    start_x: f64,
+    start_y: f64,
+    end_x: f64,
+    end_y: f64,
+    radius: f64,
+    clockwise: bool,
+) -> Option<(f64, f64)> {
+    let (dx, dy) = (end_x - start_x, end_y - start_y);
+    let chord = (dx * dx + dy * dy).sqrt();
+    if chord < EPSILON || chord > 2.0 * radius.abs() {
+        return None;
+    }
+
+    let (mid_x, mid_y) = (start_x + dx / 2.0, start_y + dy / 2.0);
+    let offset = (radius * radius - chord * chord / 4.0).sqrt();
+
+    //Negative radius selects the longer of the two arcs
+    let sign = if clockwise == (radius > 0.0) { -1.0 } else { 1.0 };
+    Some((
+        mid_x - sign * offset * dy / chord,
+        mid_y + sign * offset * dx / chord,
+    ))
+}
+
+///The letter/number pairs of a single line with comments removed
+struct Words(Vec<(char, f64)>);
+
+impl Words {
+    fn parse(line: &str) -> Self {
+        let code = line.split(';').next().unwrap_or("");
+        let code = code.split('*').next().unwrap_or("");
+
+        let mut words = vec![];
+        let mut in_comment = false;
+        let mut current: Option<(char, String)> = None;
+
+        for c in code.chars() {
+            if in_comment {
+                in_comment = c != ')';
+                continue;
+            }
+
+            if c == '(' {
+                in_comment = true;
+            } else if c.is_ascii_alphabetic() {
+                words.extend(current.take().and_then(finish_word));
+                current = Some((c.to_ascii_uppercase(), String::new()));
+            } else if !c.is_whitespace() {
+                if let Some((_, number)) = current.as_mut() {
+                    number.push(c);
+                }
+            }
+        }
+        words.extend(current.take().and_then(finish_word));
+
+        //Line numbers are not part of the command
+        if let Some(('N', _)) = words.first() {
+            words.remove(0);
+        }
+
+        Words(words)
+    }
+
+    fn get(&self, letter: char) -> Option<f64> {
+        self.0.iter().skip(1).find(|(l, _)| *l == letter).map(|(_, v)| *v)
+    }
+}
+
+fn finish_word((letter, number): (char, String)) -> Option<(char, f64)> {
+    if number.is_empty() {
+        Some((letter, 0.0))
+    } else {
+        number.parse().ok().map(|n| (letter, n))
+    }
+}
+
+///Parse G-code into the commands used by the viewers. Supports absolute and relative
+///positioning and extrusion, arcs and position resets.
+pub fn parse_gcode(gcode: &str, material: &MaterialProfile) -> ParsedGCode {
+    let filament_area = material.get_cross_section_area();
+
+    let mut parser = Parser {
+        state: ParserState {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+            e: 0.0,
+            feedrate: 0.0,
+            absolute_positioning: true,
+            absolute_extrusion: true,
+            layer_index: 0,
+            max_z: 0.0,
+            last_layer_change: None,
+            last_extrusion_z: None,
+            thickness: DEFAULT_LAYER_HEIGHT,
+            retracted: false,
+        },
+        filament_area,
+        filament_length: 0.0,
+        total_time: 0.0,
+        output: ParsedGCode::default(),
+    };
+
+    for (line, text) in gcode.lines().enumerate() {
+        parser.parse_line(line, text);
+    }
+
+    //mm^3 to cm^3
+    let plastic_volume = parser.filament_length * filament_area / 1000.0;
+
+    parser.output.statistics = GCodeStatistics {
+        plastic_volume,
+        plastic_weight: plastic_volume * material.density,
+        total_time: parser.total_time,
+    };
+
+    parser.output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(gcode: &str) -> ParsedGCode {
+        parse_gcode(gcode, &MaterialProfile::default())
+    }
+
+    fn layer_changes(parsed: &ParsedGCode) -> Vec<(f64, usize)> {
+        parsed
+            .commands
+            .iter()
+            .filter_map(|cmd| match cmd {
+                Command::LayerChange { z, index } => Some((*z, *index)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn extrusions(parsed: &ParsedGCode) -> Vec<(f64, f64, f64)> {
+        parsed
+            .commands
+            .iter()
+            .filter_map(|cmd| match cmd {
+                Command::MoveAndExtrude { end, width, .. } => Some((end.x, end.y, *width)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn layers_are_counted_where_extruded() {
+        let parsed = parse(
+            "G1 Z0.2\n\
+             G1 X10 E1\n\
+             G1 Z0.6\n\
+             G1 X0\n\
+             G1 Z0.2\n\
+             G1 X10 E2\n\
+             G1 Z0.4\n\
+             G1 X0 E3\n",
+        );
+
+        assert_eq!(layer_changes(&parsed), vec![(0.2, 1), (0.6, 1), (0.2, 1), (0.4, 2)]);
+    }
+
+    #[test]
+    fn z_hop_before_a_new_layer_keeps_the_index() {
+        let parsed = parse(
+            "G1 Z0.2\n\
+             G1 X10 E1\n\
+             G1 Z0.8\n\
+             G1 X0\n\
+             G1 Z0.4\n\
+             G1 X10 E2\n",
+        );
+
+        assert_eq!(layer_changes(&parsed), vec![(0.2, 1), (0.8, 1), (0.4, 2)]);
+    }
+
+    #[test]
+    fn relative_extrusion_gives_the_same_widths() {
+        let absolute = parse("G1 Z0.2\nG1 X10 E1\nG1 X20 E2\n");
+        let relative = parse("M83\nG1 Z0.2\nG1 X10 E1\nG1 X20 E1\n");
+
+        assert_eq!(extrusions(&absolute), extrusions(&relative));
+        let area = MaterialProfile::default().get_cross_section_area();
+        let (_, _, width) = extrusions(&absolute)[0];
+        assert!((width - area / (10.0 * 0.2)).abs() < EPSILON);
+    }
+
+    #[test]
+    fn retractions_are_state_changes() {
+        let parsed = parse("G1 Z0.2\nG1 X10 E1\nG1 E0\nG1 X0\nG1 E1\nG10\nG11\n");
+        let retracts: Vec<bool> = parsed
+            .commands
+            .iter()
+            .filter_map(|cmd| match cmd {
+                Command::SetState { new_state } => new_state.retract,
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(retracts, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn arcs_end_at_the_target() {
+        let parsed = parse("G1 Z0.2\nG1 X10 Y0\nG2 X0 Y10 I-10 J0 E5\n");
+        let moves = extrusions(&parsed);
+
+        assert!(moves.len() > 1);
+        let (x, y, _) = *moves.last().unwrap();
+        assert!(x.abs() < EPSILON && (y - 10.0).abs() < EPSILON);
+        //Clockwise from +x to +y around the origin passes below it
+        assert!(moves.iter().any(|(_, y, _)| *y < -9.0));
+    }
+
+    #[test]
+    fn comments_and_line_numbers_are_ignored() {
+        let parsed = parse("N10 G1 Z0.2 ; first layer\nN11 G1 (move) X10 E1*42\n");
+
+        assert_eq!(layer_changes(&parsed), vec![(0.2, 1)]);
+        assert_eq!(extrusions(&parsed).len(), 1);
+        assert_eq!(parsed.command_lines.last(), Some(&1));
+    }
+
+    #[test]
+    fn statistics_use_the_material() {
+        let gcode = "G1 Z0.2 F600\nG1 X10 E10\n";
+        let material = MaterialProfile {
+            density: 2.0,
+            diameter: 2.85,
+            ..Default::default()
+        };
+        let statistics = parse_gcode(gcode, &material).statistics;
+
+        let volume = 10.0 * material.get_cross_section_area() / 1000.0;
+        assert!((statistics.plastic_volume - volume).abs() < EPSILON);
+        assert!((statistics.plastic_weight - volume * 2.0).abs() < EPSILON);
+        assert!((statistics.total_time - 1.0).abs() < EPSILON);
+    }
+}
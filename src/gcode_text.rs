@@ -0,0 +1,210 @@
+use geo::Coordinate;
+use gladius_shared::types::Command;
+
+///How many parsed commands ahead to look for the line of a slicer move
+const MATCH_LOOKAHEAD: usize = 256;
+
+///Slicer output is written with 3 decimals so positions only match up to rounding
+const MATCH_TOLERANCE: f64 = 0.01;
+
+///Line index into a G-code string so the text viewer only touches the visible lines
+#[derive(Debug, Default)]
+pub struct GCodeLines {
+    ///Byte offset of the start of every line
+    line_starts: Vec<usize>,
+    ///The zero based line each command was generated from, never decreasing
+    command_lines: Vec<usize>,
+}
+
+impl GCodeLines {
+    pub fn new(gcode: &str, command_lines: Vec<usize>) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(gcode.match_indices('\n').map(|(i, _)| i + 1))
+            .filter(|start| *start < gcode.len())
+            .collect();
+
+        GCodeLines {
+            line_starts,
+            command_lines,
+        }
+    }
+
+    ///Index G-code written by the slicer, finding the line of every command by scanning the
+    ///text for moves and matching their end points in order.
+    pub fn from_commands(gcode: &str, commands: &[Command]) -> Self {
+        let move_ends = scan_move_ends(gcode);
+        let mut command_lines = Vec::with_capacity(commands.len());
+        let mut next = 0;
+        let mut line = 0;
+
+        for command in commands {
+            if let Some(end) = get_move_end(command) {
+                let window_end = (next + MATCH_LOOKAHEAD).min(move_ends.len());
+                let found = (next..window_end).find(|i| {
+                    let (_, p) = move_ends[*i];
+                    (p.x - end.x).abs() < MATCH_TOLERANCE && (p.y - end.y).abs() < MATCH_TOLERANCE
+                });
+
+                //Unmatched moves keep the line of the previous command
+                if let Some(i) = found {
+                    line = move_ends[i].0;
+                    next = i + 1;
+                }
+            }
+            command_lines.push(line);
+        }
+
+        GCodeLines::new(gcode, command_lines)
+    }
+
+    pub fn len(&self) -> usize {
+        self.line_starts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.line_starts.is_empty()
+    }
+
+    ///Text of the line without the line ending
+    pub fn get_line<'a>(&self, gcode: &'a str, line: usize) -> &'a str {
+        let start = self.line_starts[line];
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .copied()
+            .unwrap_or(gcode.len());
+        gcode[start..end].trim_end_matches(|c| c == '\n' || c == '\r')
+    }
+
+    pub fn get_command_line(&self, command_index: usize) -> Option<usize> {
+        self.command_lines.get(command_index).copied()
+    }
+
+    ///Last command generated at or before the line
+    pub fn get_line_command(&self, line: usize) -> Option<usize> {
+        self.command_lines
+            .partition_point(|command_line| *command_line <= line)
+            .checked_sub(1)
+    }
+
+    pub fn get_offset_line(&self, offset: usize) -> usize {
+        self.line_starts
+            .partition_point(|start| *start <= offset)
+            .saturating_sub(1)
+    }
+
+    ///Find the next line containing the query, searching from the line after or before the given one
+    pub fn find(&self, gcode: &str, query: &str, from_line: Option<usize>, forward: bool) -> Option<usize> {
+        if query.is_empty() || self.is_empty() {
+            return None;
+        }
+
+        if forward {
+            let start = from_line
+                .and_then(|line| self.line_starts.get(line + 1).copied())
+                .unwrap_or(if from_line.is_some() { gcode.len() } else { 0 });
+            gcode[start..]
+                .find(query)
+                .map(|offset| self.get_offset_line(start + offset))
+        } else {
+            let end = from_line
+                .map(|line| self.line_starts[line.min(self.len() - 1)])
+                .unwrap_or(gcode.len());
+            gcode[..end]
+                .rfind(query)
+                .map(|offset| self.get_offset_line(offset))
+        }
+    }
+}
+
+///Line and end point of every move in XY. Only positioning is followed, which is all matching
+///the slicer's commands needs.
+fn scan_move_ends(gcode: &str) -> Vec<(usize, Coordinate<f64>)> {
+    let mut position = Coordinate { x: 0.0, y: 0.0 };
+    let mut relative = false;
+    let mut ends = vec![];
+
+    for (line, text) in gcode.lines().enumerate() {
+        let words: Vec<(char, f64)> = text
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .split_whitespace()
+            .filter_map(|word| {
+                let letter = word.chars().next()?;
+                let value = word[letter.len_utf8()..].parse().ok()?;
+                Some((letter.to_ascii_uppercase(), value))
+            })
+            .skip_while(|(letter, _)| *letter == 'N')
+            .collect();
+        let get = |letter: char| words.iter().skip(1).find(|(l, _)| *l == letter).map(|(_, v)| *v);
+
+        match words.first().map(|(letter, value)| (*letter, *value as u32, value.fract() == 0.0)) {
+            Some(('G', 0..=3, true)) => {
+                let (x, y) = (get('X'), get('Y'));
+                if x.is_none() && y.is_none() {
+                    continue;
+                }
+                if relative {
+                    position.x += x.unwrap_or(0.0);
+                    position.y += y.unwrap_or(0.0);
+                } else {
+                    position.x = x.unwrap_or(position.x);
+                    position.y = y.unwrap_or(position.y);
+                }
+                ends.push((line, position));
+            }
+            Some(('G', 90, true)) => relative = false,
+            Some(('G', 91, true)) => relative = true,
+            Some(('G', 92, true)) => {
+                position.x = get('X').unwrap_or(position.x);
+                position.y = get('Y').unwrap_or(position.y);
+            }
+            _ => {}
+        }
+    }
+    ends
+}
+
+fn get_move_end(command: &Command) -> Option<Coordinate<f64>> {
+    match command {
+        Command::MoveTo { end } | Command::MoveAndExtrude { end, .. } => Some(*end),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn move_to(x: f64, y: f64) -> Command {
+        Command::MoveTo {
+            end: Coordinate { x, y },
+        }
+    }
+
+    #[test]
+    fn commands_are_matched_to_their_lines() {
+        let gcode = "; header\nG90\nG1 X10.000 Y0.000\nM106 S255\nG1 X10.000 Y10.000 E1\nG91\nG1 X-10 ; relative\n";
+        let commands = [move_to(10.0, 0.0), move_to(10.0, 10.0), move_to(0.0, 10.0)];
+        let lines = GCodeLines::from_commands(gcode, &commands);
+
+        assert_eq!(lines.len(), 7);
+        assert_eq!(
+            (0..3).map(|i| lines.get_command_line(i)).collect::<Vec<_>>(),
+            vec![Some(2), Some(4), Some(6)]
+        );
+        assert_eq!(lines.get_line(gcode, 6), "G1 X-10 ; relative");
+    }
+
+    #[test]
+    fn unmatched_commands_keep_the_previous_line() {
+        let gcode = "G1 X1 Y1\nG1 X2 Y2\n";
+        let commands = [move_to(1.0, 1.0), move_to(5.0, 5.0), move_to(2.0, 2.0)];
+        let lines = GCodeLines::from_commands(gcode, &commands);
+
+        assert_eq!(lines.get_command_line(1), Some(0));
+        assert_eq!(lines.get_command_line(2), Some(1));
+        assert_eq!(lines.get_line_command(1), Some(2));
+    }
+}
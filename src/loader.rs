@@ -0,0 +1,734 @@
+use gladius_shared::error::SlicerErrors;
+use gladius_shared::loader::Loader;
+use gladius_shared::types::{IndexedTriangle, Vertex};
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+//Loaders for the model formats the slicer doesn't read itself, they return the same
+//vertex and triangle lists as the STL and 3MF loaders of gladius_shared
+
+type Mesh = (Vec<Vertex>, Vec<IndexedTriangle>);
+
+///How far into the file being loaded the loader has read, shared with the thread showing the
+///progress. Once cancelled every further read fails so the loader stops part way through the file.
+#[derive(Debug, Default)]
+pub struct LoadProgress {
+    bytes_read: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl LoadProgress {
+    pub fn get_bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    ///Start counting a new file
+    pub fn reset(&self) {
+        self.bytes_read.store(0, Ordering::Relaxed);
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+///Counts the bytes read through it into the progress
+pub struct ProgressReader<R: Read> {
+    inner: R,
+    progress: Arc<LoadProgress>,
+}
+
+impl<R: Read> ProgressReader<R> {
+    pub fn new(inner: R, progress: Arc<LoadProgress>) -> Self {
+        ProgressReader { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        //Not Interrupted, readers retry those
+        if self.progress.is_cancelled() {
+            return Err(std::io::Error::new(ErrorKind::Other, "model load cancelled"));
+        }
+        let read = self.inner.read(buf)?;
+        self.progress.bytes_read.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+fn open_file(filepath: &str, progress: &Arc<LoadProgress>) -> Result<ProgressReader<File>, SlicerErrors> {
+    File::open(filepath)
+        .map(|file| ProgressReader::new(file, progress.clone()))
+        .map_err(|_| SlicerErrors::ObjectFileNotFound {
+            filepath: filepath.to_string(),
+        })
+}
+
+fn read_file(filepath: &str, progress: &Arc<LoadProgress>) -> Result<Vec<u8>, SlicerErrors> {
+    let mut data = vec![];
+    open_file(filepath, progress)?
+        .read_to_end(&mut data)
+        .map_err(|_| SlicerErrors::InputMisformat)?;
+    Ok(data)
+}
+
+///Split a polygon into a fan of triangles
+fn push_polygon(triangles: &mut Vec<IndexedTriangle>, polygon: &[usize]) {
+    for i in 1..polygon.len().saturating_sub(1) {
+        triangles.push(IndexedTriangle {
+            verts: [polygon[0], polygon[i], polygon[i + 1]],
+        });
+    }
+}
+
+///Wavefront OBJ, all groups are loaded as one object
+#[derive(Default)]
+pub struct OBJLoader {
+    pub progress: Arc<LoadProgress>,
+}
+
+impl Loader for OBJLoader {
+    fn load(&self, filepath: &str) -> Result<Vec<Mesh>, SlicerErrors> {
+        let file = open_file(filepath, &self.progress)?;
+
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|_| SlicerErrors::InputMisformat)?;
+            let mut tokens = line.split_whitespace();
+
+            match tokens.next() {
+                Some("v") => {
+                    let mut coordinate = || -> Result<f64, SlicerErrors> {
+                        tokens
+                            .next()
+                            .and_then(|token| token.parse().ok())
+                            .ok_or(SlicerErrors::InputMisformat)
+                    };
+                    vertices.push(Vertex {
+                        x: coordinate()?,
+                        y: coordinate()?,
+                        z: coordinate()?,
+                    });
+                }
+                Some("f") => {
+                    let polygon = tokens
+                        .map(|token| {
+                            //Faces are vertex/texture/normal, only the vertex is needed
+                            let index: i64 = token
+                                .split('/')
+                                .next()
+                                .and_then(|index| index.parse().ok())
+                                .ok_or(SlicerErrors::InputMisformat)?;
+
+                            //Indices start at 1, negative ones count back from the last vertex
+                            let index = if index < 0 {
+                                vertices.len() as i64 + index
+                            } else {
+                                index - 1
+                            };
+                            if index < 0 || index as usize >= vertices.len() {
+                                return Err(SlicerErrors::InputMisformat);
+                            }
+                            Ok(index as usize)
+                        })
+                        .collect::<Result<Vec<usize>, SlicerErrors>>()?;
+                    push_polygon(&mut triangles, &polygon);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(vec![(vertices, triangles)])
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum PlyType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "char" | "int8" => Some(PlyType::Int8),
+            "uchar" | "uint8" => Some(PlyType::UInt8),
+            "short" | "int16" => Some(PlyType::Int16),
+            "ushort" | "uint16" => Some(PlyType::UInt16),
+            "int" | "int32" => Some(PlyType::Int32),
+            "uint" | "uint32" => Some(PlyType::UInt32),
+            "float" | "float32" => Some(PlyType::Float32),
+            "double" | "float64" => Some(PlyType::Float64),
+            _ => None,
+        }
+    }
+
+    fn get_size(&self) -> usize {
+        match self {
+            PlyType::Int8 | PlyType::UInt8 => 1,
+            PlyType::Int16 | PlyType::UInt16 => 2,
+            PlyType::Int32 | PlyType::UInt32 | PlyType::Float32 => 4,
+            PlyType::Float64 => 8,
+        }
+    }
+
+    fn read_binary(&self, bytes: &[u8], little_endian: bool) -> f64 {
+        macro_rules! read {
+            ($t:ty) => {{
+                let bytes = bytes.try_into().unwrap_or_default();
+                if little_endian {
+                    <$t>::from_le_bytes(bytes) as f64
+                } else {
+                    <$t>::from_be_bytes(bytes) as f64
+                }
+            }};
+        }
+        match self {
+            PlyType::Int8 => read!(i8),
+            PlyType::UInt8 => read!(u8),
+            PlyType::Int16 => read!(i16),
+            PlyType::UInt16 => read!(u16),
+            PlyType::Int32 => read!(i32),
+            PlyType::UInt32 => read!(u32),
+            PlyType::Float32 => read!(f32),
+            PlyType::Float64 => read!(f64),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PlyProperty {
+    Scalar(String, PlyType),
+    ///Name, type of the count and type of the items
+    List(String, PlyType, PlyType),
+}
+
+#[derive(Debug)]
+struct PlyElement {
+    name: String,
+    count: usize,
+    properties: Vec<PlyProperty>,
+}
+
+///Reads the values of the body one at a time whatever the encoding
+struct PlyReader<'a> {
+    format: PlyFormat,
+    body: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PlyReader<'a> {
+    fn read(&mut self, value_type: PlyType) -> Result<f64, SlicerErrors> {
+        if self.format == PlyFormat::Ascii {
+            let rest = &self.body[self.position..];
+            let start = rest
+                .iter()
+                .position(|byte| !byte.is_ascii_whitespace())
+                .ok_or(SlicerErrors::InputMisformat)?;
+            let end = rest[start..]
+                .iter()
+                .position(|byte| byte.is_ascii_whitespace())
+                .map_or(rest.len(), |end| start + end);
+            self.position += end;
+
+            std::str::from_utf8(&rest[start..end])
+                .ok()
+                .and_then(|token| token.parse().ok())
+                .ok_or(SlicerErrors::InputMisformat)
+        } else {
+            let end = self.position + value_type.get_size();
+            let bytes = self
+                .body
+                .get(self.position..end)
+                .ok_or(SlicerErrors::InputMisformat)?;
+            self.position = end;
+            Ok(value_type.read_binary(bytes, self.format == PlyFormat::BinaryLittleEndian))
+        }
+    }
+}
+
+///Stanford PLY in ASCII or binary encoding
+#[derive(Default)]
+pub struct PLYLoader {
+    pub progress: Arc<LoadProgress>,
+}
+
+impl PLYLoader {
+    ///Elements of the header and the offset the body starts at
+    fn parse_header(data: &[u8]) -> Result<(PlyFormat, Vec<PlyElement>, usize), SlicerErrors> {
+        let end_marker = b"end_header";
+        let header_end = data
+            .windows(end_marker.len())
+            .position(|window| window == end_marker)
+            .ok_or(SlicerErrors::InputMisformat)?;
+        let body_start = data[header_end..]
+            .iter()
+            .position(|byte| *byte == b'\n')
+            .map(|newline| header_end + newline + 1)
+            .ok_or(SlicerErrors::InputMisformat)?;
+
+        let header = String::from_utf8_lossy(&data[..header_end]);
+        let mut lines = header.lines();
+        if lines.next().map(str::trim) != Some("ply") {
+            return Err(SlicerErrors::InputMisformat);
+        }
+
+        let mut format = None;
+        let mut elements: Vec<PlyElement> = vec![];
+        for line in lines {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            match tokens.as_slice() {
+                ["format", "ascii", ..] => format = Some(PlyFormat::Ascii),
+                ["format", "binary_little_endian", ..] => format = Some(PlyFormat::BinaryLittleEndian),
+                ["format", "binary_big_endian", ..] => format = Some(PlyFormat::BinaryBigEndian),
+                ["element", name, count] => elements.push(PlyElement {
+                    name: name.to_string(),
+                    count: count.parse().map_err(|_| SlicerErrors::InputMisformat)?,
+                    properties: vec![],
+                }),
+                ["property", "list", count_type, item_type, name] => {
+                    let property = PlyProperty::List(
+                        name.to_string(),
+                        PlyType::parse(count_type).ok_or(SlicerErrors::InputMisformat)?,
+                        PlyType::parse(item_type).ok_or(SlicerErrors::InputMisformat)?,
+                    );
+                    elements.last_mut().ok_or(SlicerErrors::InputMisformat)?.properties.push(property);
+                }
+                ["property", value_type, name] => {
+                    let property = PlyProperty::Scalar(
+                        name.to_string(),
+                        PlyType::parse(value_type).ok_or(SlicerErrors::InputMisformat)?,
+                    );
+                    elements.last_mut().ok_or(SlicerErrors::InputMisformat)?.properties.push(property);
+                }
+                _ => {}
+            }
+        }
+
+        Ok((format.ok_or(SlicerErrors::InputMisformat)?, elements, body_start))
+    }
+}
+
+impl Loader for PLYLoader {
+    fn load(&self, filepath: &str) -> Result<Vec<Mesh>, SlicerErrors> {
+        let data = read_file(filepath, &self.progress)?;
+        let (format, elements, body_start) = Self::parse_header(&data)?;
+
+        let mut reader = PlyReader {
+            format,
+            body: &data[body_start..],
+            position: 0,
+        };
+        let mut vertices = vec![];
+        let mut triangles = vec![];
+
+        //Every element has to be read in order to find where the next one starts
+        for element in &elements {
+            for _ in 0..element.count {
+                let mut position = [0.0; 3];
+                let mut polygon = vec![];
+
+                for property in &element.properties {
+                    match property {
+                        PlyProperty::Scalar(name, value_type) => {
+                            let value = reader.read(*value_type)?;
+                            match name.as_str() {
+                                "x" => position[0] = value,
+                                "y" => position[1] = value,
+                                "z" => position[2] = value,
+                                _ => {}
+                            }
+                        }
+                        PlyProperty::List(name, count_type, item_type) => {
+                            let count = reader.read(*count_type)? as usize;
+                            let items = (0..count)
+                                .map(|_| reader.read(*item_type))
+                                .collect::<Result<Vec<f64>, SlicerErrors>>()?;
+                            if name == "vertex_indices" || name == "vertex_index" {
+                                polygon = items.into_iter().map(|index| index as usize).collect();
+                            }
+                        }
+                    }
+                }
+
+                match element.name.as_str() {
+                    "vertex" => vertices.push(Vertex {
+                        x: position[0],
+                        y: position[1],
+                        z: position[2],
+                    }),
+                    "face" => push_polygon(&mut triangles, &polygon),
+                    _ => {}
+                }
+            }
+        }
+
+        if triangles.iter().flat_map(|tri| tri.verts.iter()).any(|index| *index >= vertices.len()) {
+            return Err(SlicerErrors::InputMisformat);
+        }
+
+        Ok(vec![(vertices, triangles)])
+    }
+}
+
+///Additive Manufacturing File Format, plain or zip compressed. Every object is loaded with all its volumes.
+#[derive(Default)]
+pub struct AMFLoader {
+    pub progress: Arc<LoadProgress>,
+}
+
+impl AMFLoader {
+    fn get_unit_scale(unit: &str) -> f64 {
+        match unit {
+            "inch" => 25.4,
+            "feet" => 304.8,
+            "meter" => 1000.0,
+            "micron" => 0.001,
+            _ => 1.0,
+        }
+    }
+
+    fn parse(xml: &str) -> Result<Vec<Mesh>, SlicerErrors> {
+        let mut meshes = vec![];
+        let mut scale = 1.0;
+        let mut path: Vec<String> = vec![];
+        let mut rest = xml;
+
+        let mut vertex = [0.0; 3];
+        let mut triangle = [0usize; 3];
+
+        while let Some(start) = rest.find('<') {
+            let text = rest[..start].trim();
+            rest = &rest[start..];
+
+            //Comments and declarations can contain anything
+            if rest.starts_with("<!--") {
+                let end = rest.find("-->").ok_or(SlicerErrors::InputMisformat)?;
+                rest = &rest[end + 3..];
+                continue;
+            }
+            let end = rest.find('>').ok_or(SlicerErrors::InputMisformat)?;
+            let tag = &rest[1..end];
+            rest = &rest[end + 1..];
+            if tag.starts_with('?') || tag.starts_with('!') {
+                continue;
+            }
+
+            if let Some(name) = tag.strip_prefix('/') {
+                //The text of an element comes right before its closing tag
+                let value = || text.parse::<f64>().map_err(|_| SlicerErrors::InputMisformat);
+                let index = || text.parse::<usize>().map_err(|_| SlicerErrors::InputMisformat);
+                match name.trim() {
+                    "x" if path.iter().any(|tag| tag == "coordinates") => vertex[0] = value()? * scale,
+                    "y" if path.iter().any(|tag| tag == "coordinates") => vertex[1] = value()? * scale,
+                    "z" if path.iter().any(|tag| tag == "coordinates") => vertex[2] = value()? * scale,
+                    "v1" => triangle[0] = index()?,
+                    "v2" => triangle[1] = index()?,
+                    "v3" => triangle[2] = index()?,
+                    "vertex" => {
+                        let (vertices, _): &mut Mesh = meshes.last_mut().ok_or(SlicerErrors::InputMisformat)?;
+                        vertices.push(Vertex {
+                            x: vertex[0],
+                            y: vertex[1],
+                            z: vertex[2],
+                        });
+                    }
+                    "triangle" => {
+                        let (_, triangles): &mut Mesh = meshes.last_mut().ok_or(SlicerErrors::InputMisformat)?;
+                        triangles.push(IndexedTriangle { verts: triangle });
+                    }
+                    _ => {}
+                }
+                path.pop();
+                continue;
+            }
+
+            let self_closing = tag.ends_with('/');
+            let tag = tag.trim_end_matches('/');
+            let name = tag.split_whitespace().next().unwrap_or_default();
+
+            match name {
+                "amf" => {
+                    if let Some(unit) = Self::get_attribute(tag, "unit") {
+                        scale = Self::get_unit_scale(unit);
+                    }
+                }
+                "mesh" => meshes.push((vec![], vec![])),
+                _ => {}
+            }
+
+            if !self_closing {
+                path.push(name.to_string());
+            }
+        }
+
+        for (vertices, triangles) in &meshes {
+            if triangles.iter().flat_map(|tri| tri.verts.iter()).any(|index| *index >= vertices.len()) {
+                return Err(SlicerErrors::InputMisformat);
+            }
+        }
+
+        Ok(meshes)
+    }
+
+    fn get_attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+        let start = tag.find(&format!("{}=", name))? + name.len() + 1;
+        let quote = tag[start..].chars().next()?;
+        let value = &tag[start + 1..];
+        Some(&value[..value.find(quote)?])
+    }
+}
+
+impl Loader for AMFLoader {
+    fn load(&self, filepath: &str) -> Result<Vec<Mesh>, SlicerErrors> {
+        let data = read_file(filepath, &self.progress)?;
+
+        //Compressed files are a zip archive holding the document
+        let xml = if data.starts_with(b"PK") {
+            let mut archive = zip::ZipArchive::new(std::io::Cursor::new(data)).map_err(|_| SlicerErrors::InputMisformat)?;
+            let mut file = archive.by_index(0).map_err(|_| SlicerErrors::InputMisformat)?;
+            let mut xml = String::new();
+            file.read_to_string(&mut xml).map_err(|_| SlicerErrors::InputMisformat)?;
+            xml
+        } else {
+            String::from_utf8(data).map_err(|_| SlicerErrors::InputMisformat)?
+        };
+
+        Self::parse(&xml)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::load_meshes;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    ///A square pyramid, the base is a quad so polygons have to be split
+    const POSITIONS: [[f64; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [10.0, 0.0, 0.0],
+        [10.0, 10.0, 0.0],
+        [0.0, 10.0, 0.0],
+        [5.0, 5.0, 8.0],
+    ];
+    const FACES: [&[usize]; 5] = [&[3, 2, 1, 0], &[0, 1, 4], &[1, 2, 4], &[2, 3, 4], &[3, 0, 4]];
+
+    fn get_expected_triangles() -> Vec<[usize; 3]> {
+        let mut triangles = vec![];
+        for face in FACES {
+            let mut split = vec![];
+            push_polygon(&mut split, face);
+            triangles.extend(split.iter().map(|tri| tri.verts));
+        }
+        triangles
+    }
+
+    fn write_temp(name: &str, data: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("gladius_loader_test_{}_{}", std::process::id(), name));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    fn load(loader: &dyn Loader, name: &str, data: &[u8]) -> Vec<Mesh> {
+        let path = write_temp(name, data);
+        let meshes = loader.load(path.to_str().unwrap());
+        std::fs::remove_file(path).unwrap();
+        meshes.unwrap()
+    }
+
+    fn assert_pyramid(mesh: &Mesh, scale: f64) {
+        let (vertices, triangles) = mesh;
+        let positions: Vec<[f64; 3]> = vertices.iter().map(|v| [v.x, v.y, v.z]).collect();
+        let expected: Vec<[f64; 3]> = POSITIONS.iter().map(|p| p.map(|value| value * scale)).collect();
+        assert_eq!(positions, expected);
+        let triangles: Vec<[usize; 3]> = triangles.iter().map(|tri| tri.verts).collect();
+        assert_eq!(triangles, get_expected_triangles());
+    }
+
+    fn get_obj() -> String {
+        let mut obj = String::from("# pyramid\no pyramid\n");
+        for [x, y, z] in POSITIONS {
+            obj += &format!("v {} {} {}\n", x, y, z);
+        }
+        obj += "vn 0 0 1\n";
+        for face in FACES {
+            let indices: Vec<String> = face.iter().map(|i| format!("{}//1", i + 1)).collect();
+            obj += &format!("f {}\n", indices.join(" "));
+        }
+        obj
+    }
+
+    fn get_ply_header(format: &str) -> String {
+        format!(
+            "ply\nformat {} 1.0\ncomment pyramid\nelement vertex 5\nproperty float x\nproperty float y\nproperty float z\n\
+             property uchar red\nelement face 5\nproperty list uchar int vertex_indices\nend_header\n",
+            format
+        )
+    }
+
+    fn get_amf(scale_unit: &str) -> String {
+        let mut amf = format!("<?xml version=\"1.0\"?>\n<amf unit=\"{}\">\n<!-- <mesh> -->\n<object id=\"0\"><mesh><vertices>\n", scale_unit);
+        for [x, y, z] in POSITIONS {
+            amf += &format!("<vertex><coordinates><x>{}</x><y>{}</y><z>{}</z></coordinates></vertex>\n", x, y, z);
+        }
+        amf += "</vertices><volume>\n";
+        for [v1, v2, v3] in get_expected_triangles() {
+            amf += &format!("<triangle><v1>{}</v1><v2>{}</v2><v3>{}</v3></triangle>\n", v1, v2, v3);
+        }
+        amf += "</volume></mesh></object>\n<metadata type=\"name\"/>\n</amf>\n";
+        amf
+    }
+
+    #[test]
+    fn loaders_count_bytes_and_stop_when_cancelled() {
+        let obj = get_obj();
+        let path = write_temp("progress.obj", obj.as_bytes());
+        let loader = OBJLoader::default();
+
+        assert!(loader.load(path.to_str().unwrap()).is_ok());
+        assert_eq!(loader.progress.get_bytes_read(), obj.len() as u64);
+
+        loader.progress.reset();
+        loader.progress.cancel();
+        assert!(loader.load(path.to_str().unwrap()).is_err());
+        assert_eq!(loader.progress.get_bytes_read(), 0);
+
+        let loader = AMFLoader {
+            progress: loader.progress.clone(),
+        };
+        assert!(loader.load(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn obj_round_trip() {
+        let meshes = load(&OBJLoader::default(), "pyramid.obj", get_obj().as_bytes());
+        assert_eq!(meshes.len(), 1);
+        assert_pyramid(&meshes[0], 1.0);
+
+        //Negative indices count back from the last vertex
+        let relative = "v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\n";
+        let meshes = load(&OBJLoader::default(), "relative.obj", relative.as_bytes());
+        assert_eq!(meshes[0].1[0].verts, [0, 1, 2]);
+
+        let out_of_range = "v 0 0 0\nf 1 2 3\n";
+        let path = write_temp("broken.obj", out_of_range.as_bytes());
+        assert!(OBJLoader::default().load(path.to_str().unwrap()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn ply_ascii_round_trip() {
+        let mut ply = get_ply_header("ascii");
+        for [x, y, z] in POSITIONS {
+            ply += &format!("{} {} {} 255\n", x, y, z);
+        }
+        for face in FACES {
+            let indices: Vec<String> = face.iter().map(|i| i.to_string()).collect();
+            ply += &format!("{} {}\n", face.len(), indices.join(" "));
+        }
+
+        let meshes = load(&PLYLoader::default(), "pyramid_ascii.ply", ply.as_bytes());
+        assert_pyramid(&meshes[0], 1.0);
+    }
+
+    #[test]
+    fn ply_binary_round_trip() {
+        for (format, little_endian) in [("binary_little_endian", true), ("binary_big_endian", false)] {
+            let mut ply = get_ply_header(format).into_bytes();
+            for position in POSITIONS {
+                for value in position {
+                    let value = value as f32;
+                    ply.extend_from_slice(&if little_endian { value.to_le_bytes() } else { value.to_be_bytes() });
+                }
+                ply.push(255);
+            }
+            for face in FACES {
+                ply.push(face.len() as u8);
+                for index in face {
+                    let index = *index as i32;
+                    ply.extend_from_slice(&if little_endian { index.to_le_bytes() } else { index.to_be_bytes() });
+                }
+            }
+
+            let meshes = load(&PLYLoader::default(), &format!("pyramid_{}.ply", format), &ply);
+            assert_pyramid(&meshes[0], 1.0);
+
+            //A body cut short is an error rather than a partial model
+            let path = write_temp("truncated.ply", &ply[..ply.len() - 3]);
+            assert!(PLYLoader::default().load(path.to_str().unwrap()).is_err());
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn amf_round_trip() {
+        let meshes = load(&AMFLoader::default(), "pyramid.amf", get_amf("millimeter").as_bytes());
+        assert_eq!(meshes.len(), 1);
+        assert_pyramid(&meshes[0], 1.0);
+
+        let meshes = load(&AMFLoader::default(), "pyramid_inch.amf", get_amf("inch").as_bytes());
+        assert_pyramid(&meshes[0], 25.4);
+    }
+
+    #[test]
+    fn zipped_amf_round_trip() {
+        let mut zipped = std::io::Cursor::new(vec![]);
+        {
+            let mut archive = zip::ZipWriter::new(&mut zipped);
+            archive.start_file("pyramid.amf", zip::write::FileOptions::default()).unwrap();
+            archive.write_all(get_amf("millimeter").as_bytes()).unwrap();
+            archive.finish().unwrap();
+        }
+
+        let meshes = load(&AMFLoader::default(), "pyramid_zip.amf", zipped.get_ref());
+        assert_pyramid(&meshes[0], 1.0);
+    }
+
+    #[test]
+    fn converted_formats_are_sliced_from_an_stl_copy() {
+        let path = write_temp("converted.obj", get_obj().as_bytes());
+        let meshes = load_meshes(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let slice_path = meshes[0].get_slice_path().to_string();
+        assert_ne!(slice_path, path.to_str().unwrap());
+        assert!(slice_path.ends_with(".stl"));
+
+        //Binary STL, a header, the triangle count and 50 bytes per triangle
+        let stl = std::fs::read(&slice_path).unwrap();
+        let count = u32::from_le_bytes(stl[80..84].try_into().unwrap()) as usize;
+        assert_eq!(count, get_expected_triangles().len());
+        assert_eq!(stl.len(), 84 + count * 50);
+
+        //Copies share the file, it goes once the last mesh does
+        let copy = meshes[0].clone();
+        drop(meshes);
+        assert!(Path::new(&slice_path).exists());
+        drop(copy);
+        assert!(!Path::new(&slice_path).exists());
+    }
+}
@@ -1,30 +1,71 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 mod object;
+mod mesh;
+mod bvh;
+mod loader;
 mod shaders;
 mod model;
-
-use crate::object::{load, DisplayVertex, Object};
+mod toolpath;
+mod color;
+mod playback;
+mod gcode_parser;
+mod slice_result;
+mod statistics;
+mod materials;
+mod gcode_text;
+mod post_process;
+mod plate;
+mod thumbnail;
+mod preferences;
+mod export;
+mod binary_gcode;
+mod print_host;
+mod serial;
+mod cli;
+mod model_load;
+mod mesh_repair;
+
+use crate::object::{DisplayVertex, Object};
 use crate::shaders::*;
 use crate::model::*;
-
-use native_dialog::FileDialog;
-
-use egui::plot::{Corner, Legend, Line, Plot, Value, Values};
+use crate::toolpath::{find_move, LayerMoves, Overlay, OverlayVisibility, ToolpathMove};
+use crate::playback::Playback;
+use crate::statistics::LayerStatistic;
+use crate::color::{gradient_color, ColorMap, ColorScheme};
+use crate::post_process::{LayerAction, PostProcessor};
+use crate::export::{ExportFormat, ExportPreset};
+use crate::print_host::{HostType, JobCommand, PrintHost};
+use crate::serial::{get_available_ports, BAUD_RATES};
+use crate::cli::{slice_headless, Arguments, USAGE};
+use crate::model_load::FileLoadState;
+use crate::mesh_repair::MeshHealth;
+
+
+use egui::plot::{Corner, Legend, Line, LinkedAxisGroup, Plot, PlotUi, Points, VLine, Value, Values};
 use egui::{
-    Color32, FontDefinitions, FontFamily, InnerResponse, Pos2, Sense, Stroke, TextStyle,
+    Align2, Color32, FontDefinitions, FontFamily, FontId, Id, InnerResponse, LayerId, Order, Pos2, RichText, Sense, Stroke, TextStyle,
 };
 use gladius_shared::error::SlicerErrors;
 use gladius_shared::messages::Message;
 use glam::{Vec2, Vec3};
 use glium::{glutin, Surface, uniform};
 use itertools::Itertools;
-use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use json_gettext::JSONGetText;
-use winit::event::{DeviceEvent, ElementState, MouseScrollDelta, WindowEvent};
+use winit::event::{DeviceEvent, StartCause, ElementState, MouseScrollDelta, WindowEvent};
+
 #[macro_use] extern crate json_gettext;
+
+///How often the window redraws to show new printer state while monitoring
+const MONITOR_REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+///Distance in points from a chart point that still counts as clicking it
+const PLOT_POINT_HIT_RADIUS: f32 = 6.0;
+
 fn vertex(pos: [f32; 3]) -> DisplayVertex {
     DisplayVertex {
         position: (pos[0], pos[1], pos[2]),
@@ -36,6 +77,28 @@ enum Errors {
     SlicerCommunicationIssue,
     SlicerApplicationIssue,
     SlicerError(SlicerErrors),
+    GCodeFileNotReadable(String),
+    MaterialsFileNotWritable,
+    PostProcessScriptFailed(String),
+    PostProcessorsFileNotWritable,
+    PlateFileNotReadable(String),
+    PlateFileNotWritable(String),
+    PreferencesFileNotWritable,
+    GCodeNotExportable,
+    UploadFailed(String),
+    PrinterCommandFailed(String),
+    SerialPortNotOpenable(String),
+    FileDialogFailed,
+    ModelFileNameInvalid(String),
+    ModelFormatNotSupported(String),
+    ModelFileNotLoadable(String, SlicerErrors),
+    ModelBuffersNotCreatable(String),
+    ///The errors of every file that failed and how many files were loaded
+    ModelsNotLoadable(Vec<Errors>, usize),
+    FileTypeNotSupported(String),
+    ModelCopyNotWritable(String),
+    GCodeFileNotWritable(String),
+    GCodeNotOpenableWhileSlicing(String),
 }
 
 impl Errors {
@@ -50,6 +113,90 @@ impl Errors {
                 format!("Error found in communication between GUI and slicer application."),
             ),
             Errors::SlicerError(e) => e.get_code_and_message(),
+            Errors::GCodeFileNotReadable(path) => (
+                0x8002,
+                format!("G-code file {} could not be read.", path),
+            ),
+            Errors::MaterialsFileNotWritable => (
+                0x8003,
+                String::from("Material profiles could not be saved."),
+            ),
+            Errors::PostProcessScriptFailed(command) => (
+                0x8004,
+                format!("Post-processing script {} failed.", command),
+            ),
+            Errors::PostProcessorsFileNotWritable => (
+                0x8005,
+                String::from("Post-processing steps could not be saved."),
+            ),
+            Errors::PlateFileNotReadable(path) => (
+                0x8006,
+                format!("Plate file {} could not be read.", path),
+            ),
+            Errors::PlateFileNotWritable(path) => (
+                0x8007,
+                format!("Plate file {} could not be written.", path),
+            ),
+            Errors::PreferencesFileNotWritable => (
+                0x8008,
+                String::from("Preferences could not be saved."),
+            ),
+            Errors::GCodeNotExportable => (
+                0x8009,
+                String::from("G-code could not be encoded for export."),
+            ),
+            Errors::UploadFailed(e) => (
+                0x800A,
+                format!("Upload to the print host failed: {}", e),
+            ),
+            Errors::PrinterCommandFailed(e) => (
+                0x800B,
+                format!("The print host did not accept the command: {}", e),
+            ),
+            Errors::SerialPortNotOpenable(e) => (
+                0x800C,
+                format!("Serial port could not be opened, {}", e),
+            ),
+            Errors::FileDialogFailed => (
+                0x800D,
+                String::from("The file dialog could not be opened."),
+            ),
+            Errors::ModelFileNameInvalid(path) => (
+                0x800E,
+                format!("Model file {} has no usable name or extension.", path),
+            ),
+            Errors::ModelFormatNotSupported(path) => (
+                0x800F,
+                format!("The format of model file {} is not supported.", path),
+            ),
+            Errors::ModelFileNotLoadable(path, e) => (
+                0x8010,
+                format!("Model file {} could not be loaded. {}", path, e.get_code_and_message().1),
+            ),
+            Errors::ModelBuffersNotCreatable(path) => (
+                0x8011,
+                format!("Model {} could not be sent to the graphics card.", path),
+            ),
+            Errors::ModelsNotLoadable(failed, total) => (
+                0x8012,
+                format!("{} of {} files could not be loaded.", failed.len(), total),
+            ),
+            Errors::FileTypeNotSupported(path) => (
+                0x8013,
+                format!("File {} is not a model, settings, plate or G-code file.", path),
+            ),
+            Errors::ModelCopyNotWritable(path) => (
+                0x8014,
+                format!("Model could not be written for the slicer to {}.", path),
+            ),
+            Errors::GCodeFileNotWritable(path) => (
+                0x8015,
+                format!("G-code could not be written to {}.", path),
+            ),
+            Errors::GCodeNotOpenableWhileSlicing(path) => (
+                0x8016,
+                format!("G-code {} can't be opened while a slice is running.", path),
+            ),
         }
     }
 }
@@ -131,6 +278,34 @@ fn main() {
 
     let lang = "en_US";
 
+    let arguments = match Arguments::parse(std::env::args().skip(1)) {
+        Ok(arguments) => arguments,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if arguments.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let build_x = 250.0;
+    let build_y = 210.0;
+    let build_z = 210.0;
+
+    if arguments.slice {
+        let mut gui_data = GUIData::new(Vec2::new(400.0, 400.0),Vec3::new(build_x,build_y,build_z));
+        match slice_headless(&arguments, &mut gui_data) {
+            Ok(output) => println!("{}", output.display()),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let event_loop = glutin::event_loop::EventLoop::with_user_event();
     let display = create_display(&event_loop);
 
@@ -145,10 +320,30 @@ fn main() {
 
 
     let mut viewer_open = false;
-
-    let build_x = 250.0;
-    let build_y = 210.0;
-    let build_z = 210.0;
+    let mut show_toolpath = false;
+    let mut toolpath_layer = 0;
+    let mut overlay_visibility = OverlayVisibility::default();
+    let mut playback = Playback::new();
+    let comparison_axes = LinkedAxisGroup::both();
+    let mut statistics_open = false;
+    let mut materials_open = false;
+    let mut post_processing_open = false;
+    let mut preferences_open = false;
+    let mut printer_open = false;
+    let mut follow_printer = false;
+    let mut fit_layer = false;
+    let mut serial_open = false;
+    let mut serial_ports = get_available_ports();
+    let mut console_input = String::new();
+    //Files from the command line are opened on the first frame like dropped files
+    let mut dropped_files: Vec<PathBuf> = arguments.files.clone();
+    let mut file_hovered = false;
+    let mut custom_insertion = String::new();
+    let mut gcode_open = false;
+    let mut gcode_search = String::new();
+    let mut selected_line: Option<usize> = None;
+    let mut scroll_to_line: Option<usize> = None;
+    let mut selected_command: Option<usize> = None;
 
     let model_program =
         glium::Program::from_source(&display, VERTEX_SHADER_SRC, FRAGMENT_SHADER_SRC, None)
@@ -160,9 +355,30 @@ fn main() {
         None,
     )
     .unwrap();
+    let toolpath_program = glium::Program::from_source(
+        &display,
+        TOOLPATH_VERTEX_SHADER_SRC,
+        TOOLPATH_FRAGMENT_SHADER_SRC,
+        None,
+    )
+    .unwrap();
+
+    let overlay_program = glium::Program::from_source(
+        &display,
+        OVERLAY_VERTEX_SHADER_SRC,
+        OVERLAY_FRAGMENT_SHADER_SRC,
+        None,
+    )
+    .unwrap();
 
     let mut gui_data = GUIData::new(Vec2::new(400.0, 400.0),Vec3::new(build_x,build_y,build_z));
 
+    if let Some(project) = arguments.project.as_ref() {
+        gui_data.load_plate_file(project);
+    }
+    if let Some(settings) = arguments.settings.as_ref() {
+        gui_data.set_settings_path(settings.clone());
+    }
 
 
     let mut plot_window_resp = None;
@@ -179,8 +395,15 @@ fn main() {
 
             let needs_repaint = egui_glium.run(&display, |egui_ctx| {
 
+                gui_data.update_model_load(&display);
                 gui_data.update_colors();
 
+                if !dropped_files.is_empty() && gui_data.open_files(&std::mem::take(&mut dropped_files)) {
+                    index = 0;
+                    toolpath_layer = usize::MAX;
+                    viewer_open = true;
+                }
+
                 plot_window_resp = None;
                 window_clicked = false;
                let resp = egui::SidePanel::left("my_side_panel").show(&egui_ctx, |ui| {
@@ -188,9 +411,12 @@ fn main() {
                    ui.horizontal(|ui| {
                        ui.label(&get_translated_string(&ctx, lang, "model_path"));
                        if ui.button(&get_translated_string(&ctx, lang,  "choose_model_button")).clicked() {
-                            gui_data.load_model(    &display);
+                            gui_data.load_model();
                        }
                    });
+                   if show_model_load(ui, &gui_data, &ctx, lang) {
+                       gui_data.cancel_model_load(&display);
+                   }
                    ui.horizontal(|ui| {
                        ui.label(&get_translated_string(&ctx, lang, "settings_path"));
                        let mut short = gui_data.get_settings_path().clone();
@@ -205,12 +431,32 @@ fn main() {
                             gui_data.load_settings_file();
                        }
                    });
+                   ui.horizontal(|ui| {
+                       if ui.button(&get_translated_string(&ctx, lang, "open_plate")).clicked() {
+                           gui_data.load_plate();
+                       }
+                       if ui.button(&get_translated_string(&ctx, lang, "save_plate")).clicked() {
+                           gui_data.save_plate();
+                       }
+                   });
+                   ui.horizontal(|ui| {
+                       //Opened G-code replaces the result the running slice writes to
+                       if ui.add_enabled(!gui_data.is_command_running(), egui::Button::new(&get_translated_string(&ctx, lang, "open_gcode"))).clicked() && gui_data.load_gcode_file() {
+                           index = 0;
+                           toolpath_layer = usize::MAX;
+                           viewer_open = true;
+                       }
+                   });
+                   let mut repair = None;
                    ui.group(|ui| {
 
                        gui_data.get_objects().iter().enumerate()
                            .for_each(|(i,mut obj)| {
                            ui.horizontal(|ui| {
-                               ui.label(obj.name.to_string());
+                               ui.label(obj.mesh.name.to_string());
+                               if show_health_badge(ui, &obj.mesh.health, &ctx, lang) {
+                                   repair = Some(i);
+                               }
                            });
 
                            let mut changed = false;
@@ -258,6 +504,9 @@ fn main() {
                            }
                        });
                    });
+                   if let Some(i) = repair {
+                       gui_data.repair_object(i, &display);
+                   }
 
                    ui.horizontal(|ui| {
 
@@ -277,6 +526,7 @@ fn main() {
                            if ui.add_enabled( gui_data.can_slice(), egui::Button::new(&get_translated_string(&ctx, lang, "slice"))).clicked() {
                                index = 0;
                                 layers = 0;
+                                toolpath_layer = usize::MAX;
                                 viewer_open = true;
 
                                gui_data.start_slice();
@@ -296,8 +546,29 @@ fn main() {
                            let (hour,min,_,_) = cv.get_hours_minutes_seconds_fract_time();
                            ui.label(get_translated_string_arguments(&ctx,lang,"print_time_msg",&[hour.to_string(), min.to_string()]));
                        });
+                   }
+                   else if let Some(stats) = gui_data.get_gcode_statistics(){
+                       ui.horizontal(|ui| {
+                           ui.label(get_translated_string_argument(&ctx,lang,"plastic_volume_msg",format!("{:.0}",stats.plastic_volume)));
+                       });
+                       ui.horizontal(|ui| {
+                           ui.label(get_translated_string_argument(&ctx,lang,"plastic_weight_msg",format!("{:.0}",stats.plastic_weight)));
+                       });
+                       ui.horizontal(|ui| {
+                           let (hour,min) = stats.get_hours_minutes();
+                           ui.label(get_translated_string_arguments(&ctx,lang,"print_time_msg",&[hour.to_string(), min.to_string()]));
+                       });
                    };
 
+                   if let Some(volume) = gui_data.get_extrusion_volume(){
+                       ui.group(|ui| {
+                           show_filament_usage(ui, &mut gui_data, volume, &ctx, lang);
+                           if ui.button(&get_translated_string(&ctx, lang, "edit_materials")).clicked(){
+                               materials_open = true;
+                           }
+                       });
+                   }
+
                    for err in gui_data.get_errors(){
 
                        let (code, message) = err.get_code_and_message();
@@ -318,7 +589,17 @@ fn main() {
                        });
                    }
 
-                   if let Some(str) = gui_data.get_gcode() {
+                   if gui_data.get_gcode().is_some() {
+                       let mut selected = gui_data.get_selected_export_preset_index();
+                       egui::ComboBox::from_label(&get_translated_string(&ctx, lang, "export_preset"))
+                           .selected_text(&gui_data.get_export_preset().name)
+                           .show_ui(ui, |ui| {
+                               for (i, preset) in gui_data.get_export_presets().iter().enumerate(){
+                                   ui.selectable_value(&mut selected, i, &preset.name);
+                               }
+                           });
+                       gui_data.set_selected_export_preset(selected);
+
                        ui.horizontal(|ui| {
                            ui.style_mut().spacing.button_padding = egui::Vec2::new(50., 20.);
                            //ui.style_mut().body_text_style = TextStyle::Heading;
@@ -327,23 +608,111 @@ fn main() {
 
                            ui.centered_and_justified(|ui| {
                                if ui.button("Save").clicked() {
-                                   let path = FileDialog::new()
-                                       .add_filter("gcode", &["gcode"])
-                                       .show_save_single_file()
-                                       .unwrap();
-
-                                   let path = match path {
-                                       Some(path) => path,
-                                       None => return,
-                                   };
-
-                                   let mut file = File::create(path).unwrap();
-                                   file.write_all(str.as_bytes()).unwrap();
+                                   gui_data.export_gcode_file(&display);
                                }
                            });
                        });
+
+                       if !gui_data.get_print_hosts().is_empty() {
+                           ui.separator();
+                           let mut selected = gui_data.get_selected_print_host_index();
+                           egui::ComboBox::from_label(&get_translated_string(&ctx, lang, "print_host"))
+                               .selected_text(gui_data.get_print_host().map(|host| host.name.as_str()).unwrap_or_default())
+                               .show_ui(ui, |ui| {
+                                   for (i, host) in gui_data.get_print_hosts().iter().enumerate(){
+                                       ui.selectable_value(&mut selected, i, &host.name);
+                                   }
+                               });
+                           gui_data.set_selected_print_host(selected);
+
+                           let mut start_print = gui_data.get_mut_preferences().start_print_after_upload;
+                           ui.checkbox(&mut start_print, get_translated_string(&ctx, lang, "start_print"));
+                           gui_data.get_mut_preferences().start_print_after_upload = start_print;
+
+                           match gui_data.get_upload_progress() {
+                               Some(progress) => {
+                                   ui.label(get_translated_string(&ctx, lang, "uploading"));
+                                   ui.add(egui::ProgressBar::new(progress.get_fraction()).show_percentage());
+                               }
+                               None => {
+                                   if ui.button(&get_translated_string(&ctx, lang, "send_to_printer")).clicked() {
+                                       gui_data.send_to_printer(Some(&display), start_print);
+                                   }
+                                   if let Some(message) = gui_data.get_upload_message() {
+                                       ui.label(get_translated_string_argument(&ctx, lang, "upload_complete_msg", message));
+                                   }
+                               }
+                           }
+                       }
                    }
-                   if let Some(cmds) = gui_data.get_commands() {
+
+                   ui.horizontal(|ui| {
+                       if gui_data.get_layers().is_some() && ui.button(&get_translated_string(&ctx, lang, "layer_statistics")).clicked() {
+                           statistics_open = true;
+                       }
+                       if !gui_data.get_print_hosts().is_empty() && ui.button(&get_translated_string(&ctx, lang, "monitor_printer")).clicked() {
+                           printer_open = true;
+                           gui_data.start_monitoring();
+                       }
+                       if gui_data.get_gcode_lines().is_some() && ui.button(&get_translated_string(&ctx, lang, "view_gcode")).clicked() {
+                           gcode_open = true;
+                       }
+                       if ui.button(&get_translated_string(&ctx, lang, "post_processing")).clicked() {
+                           post_processing_open = true;
+                       }
+                       if ui.button(&get_translated_string(&ctx, lang, "usb_printer")).clicked() {
+                           serial_open = true;
+                       }
+                       if ui.button(&get_translated_string(&ctx, lang, "preferences")).clicked() {
+                           preferences_open = true;
+                       }
+                   });
+
+                   ui.horizontal(|ui| {
+                       if gui_data.get_layers().is_some() && ui.button(&get_translated_string(&ctx, lang, "keep_for_comparison")).clicked() {
+                           gui_data.keep_for_comparison();
+                       }
+                       if gui_data.get_comparison().is_some() && ui.button(&get_translated_string(&ctx, lang, "clear_comparison")).clicked() {
+                           gui_data.clear_comparison();
+                       }
+                   });
+                   if let Some(toolpath) = gui_data.get_toolpath(&display) {
+                       let layer_count = toolpath.layers.len();
+                       let color_map = toolpath.color_map.clone();
+                       ui.horizontal(|ui| {
+                           ui.checkbox(&mut show_toolpath, &get_translated_string(&ctx, lang, "show_toolpath"));
+                       });
+                       if show_toolpath && layer_count > 0 {
+                           toolpath_layer = toolpath_layer.min(layer_count - 1);
+                           ui.horizontal(|ui| {
+                               ui.add(egui::Slider::new(&mut toolpath_layer, 0..=layer_count - 1)
+                                   .text(&get_translated_string(&ctx, lang, "layer")));
+                           });
+                       }
+
+                       let mut scheme = color_map.get_scheme();
+                       egui::ComboBox::from_label(&get_translated_string(&ctx, lang, "color_scheme"))
+                           .selected_text(&get_translated_string(&ctx, lang, scheme.get_translation_key()))
+                           .show_ui(ui, |ui| {
+                               for option in ColorScheme::ALL {
+                                   ui.selectable_value(&mut scheme, option, &get_translated_string(&ctx, lang, option.get_translation_key()));
+                               }
+                           });
+                       gui_data.set_color_scheme(scheme);
+
+                       if show_toolpath {
+                           show_color_legend(ui, &color_map, &ctx, lang);
+                       }
+
+                       show_overlay_checkboxes(ui, &mut overlay_visibility, &ctx, lang);
+                   }
+
+                   let color_map = gui_data.get_toolpath(&display).map(|toolpath| toolpath.color_map.clone());
+                   if let (Some(cmds), Some(layer_moves), Some(color_map)) = (gui_data.get_commands(), gui_data.get_layers(), color_map) {
+                        layers = layer_moves.len();
+                        let gcode_lines = gui_data.get_gcode_lines();
+                        let printer_position = gui_data.get_printer_position();
+
                         plot_window_resp = egui::Window::new(&get_translated_string(&ctx, lang, "viewer"))
                             .open(&mut viewer_open)
                             .default_size(egui::Vec2::new(400.0, 400.0))
@@ -352,74 +721,390 @@ fn main() {
 
                                 let line = Line::new(Values::from_values(vec![Value{x:0.0,y: 0.0},Value{x:0.0,y: build_y as f64 },Value{x:build_x as f64 ,y: build_y as f64 },Value{x:build_x as f64,y: 0.0  },Value{x:0.0,y: 0.0},Value{x:0.0,y: build_y as f64 }])).width(5.0);
 
-
-                                let mut layer_height = 0.0;
-
                                 ui.style_mut().spacing.slider_width = ui.available_width() - 100.0;
-                                let drag_resp = ui.add(egui::Slider::new(&mut index, 1..=layers-1)
+                                if let Some((layer, _)) = printer_position.filter(|_| follow_printer){
+                                    index = layer;
+                                }
+                                index = index.min(layers.saturating_sub(1));
+                                let drag_resp = ui.add(egui::Slider::new(&mut index, 0..=layers.saturating_sub(1))
                                     .prefix("x: "));
 
+                                show_layer_insertion_markers(ui, &drag_resp, &gui_data, &layer_moves);
+                                if let Some((layer, _)) = printer_position{
+                                    show_printer_layer_marker(ui, &drag_resp, layer, layer_moves.len());
+                                }
+
+                                //Right clicking moves the slider to the layer so the menu acts on it
+                                if let Some(layer) = layer_moves.get(index){
+                                    let z = layer.z as f64;
+                                    drag_resp.clone().context_menu(|ui| {
+                                        show_layer_insertion_menu(ui, &mut gui_data, z, &mut custom_insertion, &ctx, lang);
+                                    });
+                                }
+
+                                playback.set_layer(index);
+                                if let Some(layer) = layer_moves.get(index){
+                                    playback.advance(ui.input().unstable_dt as f64, layer);
+
+                                    ui.horizontal(|ui| {
+                                        let play_key = if playback.playing { "pause" } else { "play" };
+                                        if ui.button(&get_translated_string(&ctx, lang, play_key)).clicked(){
+                                            playback.toggle_playing(layer);
+                                        }
+                                        ui.add(egui::Slider::new(&mut playback.speed, 0.1..=100.0)
+                                            .logarithmic(true)
+                                            .suffix("x"));
+                                    });
+
+                                    if !layer.moves.is_empty(){
+                                        let mut move_index = playback.get_move_index(layer).unwrap_or(0);
+                                        if ui.add(egui::Slider::new(&mut move_index, 0..=layer.moves.len() - 1)
+                                            .text(&get_translated_string(&ctx, lang, "move")))
+                                            .changed()
+                                        {
+                                            playback.playing = false;
+                                            playback.seek_to_move(layer, move_index);
+                                        }
+                                    }
+
+                                    if playback.playing{
+                                        ui.ctx().request_repaint();
+                                    }
+                                }
+
+                                show_color_legend(ui, &color_map, &ctx, lang);
+                                ui.checkbox(&mut fit_layer, get_translated_string(&ctx, lang, "fit_layer"));
 
-                                let plot = Plot::new("items_demo")
+                                //A separate id per mode so toggling it resets the zoom
+                                let mut plot = Plot::new(("items_demo", fit_layer))
                                     .legend(Legend::default().position(Corner::RightBottom))
                                     .show_x(false)
                                     .show_y(false)
                                     .data_aspect(1.0);
 
+                                //Include the whole layer so the view doesn't move while it is played back
+                                if let Some((min, max)) = layer_moves.get(index).and_then(|layer| layer.bounding_box).filter(|_| fit_layer){
+                                    plot = plot.include_x(min.x).include_x(max.x).include_y(min.y).include_y(max.y);
+                                }
+
+
+                                let resp = ui.horizontal_top(|ui| {
+                                    ui.vertical(|ui| {
+                                        ui.set_width(180.0);
+                                        let current = layer_moves.get(index).and_then(|layer| playback.get_current_move(layer).map(|m| (layer, m)));
+                                        if let Some((layer, m)) = current {
+                                            show_move_readout(ui, layer, m, &cmds[m.command_index], &ctx, lang);
+                                        }
+                                    });
+
+                                    plot.show(ui, |plot_ui| {
+                                        if !fit_layer{
+                                            plot_ui.line(line.name(&get_translated_string(&ctx, lang, "Border")));
+                                        }
+
+                                        let p1 = plot_ui.screen_from_plot(Value{x:0.0,y:0.0});
+                                        let p2 = plot_ui.screen_from_plot(Value{x:0.0,y:1.0});
+
+
+                                        let pixels_per_plot_unit = (p2-p1).length();
+
+                                        if let Some(layer) = layer_moves.get(index){
+                                            let current_move = playback.get_current_move(layer);
+                                            let nozzle = playback.get_nozzle_position(layer);
+
+                                            for extrusion in &layer.extrusions{
+                                                //Only show what the nozzle has printed so far
+                                                let end = match current_move {
+                                                    Some(m) if extrusion.command_index > m.command_index => continue,
+                                                    Some(m) if extrusion.command_index == m.command_index => nozzle.unwrap_or(extrusion.end),
+                                                    _ => extrusion.end,
+                                                };
+                                                let move_line = Line::new(Values::from_values(vec![Value{x:extrusion.start.x as f64,y:extrusion.start.y as f64},Value{x:end.x as f64,y: end.y as f64 }]))
+                                                    .stroke(Stroke{width: extrusion.width * pixels_per_plot_unit* 0.95,color: color_map.get_color(layer, extrusion)});
+                                                plot_ui.line(move_line.name("Move"));
+                                            }
 
-                                let resp = plot.show(ui, |plot_ui| {
-                                    plot_ui.line(line.name(&get_translated_string(&ctx, lang, "Border")));
-
-                                    let p1 = plot_ui.screen_from_plot(Value{x:0.0,y:0.0});
-                                    let p2 = plot_ui.screen_from_plot(Value{x:0.0,y:1.0});
-
-
-                                    let pixels_per_plot_unit = (p2-p1).length();
+                                            if overlay_visibility.is_visible(Overlay::Travel){
+                                                for (start, end) in &layer.travels{
+                                                    let travel_line = Line::new(Values::from_values(vec![Value{x:start.x as f64,y:start.y as f64},Value{x:end.x as f64,y: end.y as f64 }]))
+                                                        .stroke(Stroke{width: 1.0,color: Overlay::Travel.get_color()});
+                                                    plot_ui.line(travel_line.name(&get_translated_string(&ctx, lang, Overlay::Travel.get_translation_key())));
+                                                }
+                                            }
 
+                                            for overlay in Overlay::ALL.into_iter().filter(|overlay| *overlay != Overlay::Travel && overlay_visibility.is_visible(*overlay)){
+                                                let points : Vec<_> = layer.markers.iter()
+                                                    .filter(|(marker, _)| *marker == overlay)
+                                                    .map(|(_, p)| Value{x: p.x as f64, y: p.y as f64})
+                                                    .collect();
 
-                                    let mut moves : Vec<_>= (&cmds
-                                        .iter()
-                                        .group_by(|cmd|{
-                                            if let gladius_shared::types::Command::LayerChange { z, index: _index } = cmd{
-                                                let r = *z == layer_height;
-                                                layer_height = *z;
-                                                r
-                                            }else{
-                                                true
+                                                plot_ui.points(Points::new(Values::from_values(points))
+                                                    .radius(3.0)
+                                                    .color(overlay.get_color())
+                                                    .name(&get_translated_string(&ctx, lang, overlay.get_translation_key())));
                                             }
-                                        }))
-                                        .into_iter()
-                                        .filter_map(|(change,layer)|{
-                                            if !change{
-                                                None
-                                            }else{
-                                                let lines :Vec<_> = layer.into_iter().filter_map(|cmd|{
-
-                                                    if let gladius_shared::types::Command::MoveAndExtrude{start,end,width,..} = cmd{
-                                                        Some(Line::new(Values::from_values(vec![Value{x:start.x,y:start.y},Value{x:end.x,y: end.y }]))
-                                                                 .stroke(Stroke{width: *width as f32 * pixels_per_plot_unit* 0.95,color: Color32::BLUE}))
-
-                                                    }else{
-                                                        None
-                                                    }
-                                                }).collect();
-
-                                                Some(lines)
+
+                                            if let Some(nozzle) = nozzle{
+                                                plot_ui.points(Points::new(Values::from_values(vec![Value{x: nozzle.x as f64, y: nozzle.y as f64}]))
+                                                    .radius(6.0)
+                                                    .color(Color32::WHITE)
+                                                    .name(&get_translated_string(&ctx, lang, "nozzle")));
                                             }
-                                        })
-                                        .collect();
 
-                                    layers = moves.len();
+                                            if let Some((_, Some(printer))) = printer_position.filter(|(layer, _)| *layer == index){
+                                                plot_ui.points(Points::new(Values::from_values(vec![Value{x: printer.x as f64, y: printer.y as f64}]))
+                                                    .radius(6.0)
+                                                    .color(Color32::LIGHT_GREEN)
+                                                    .name(&get_translated_string(&ctx, lang, "printer")));
+                                            }
 
-                                    for move_line in moves.remove(index){
-                                        plot_ui.line(move_line.name("Move"));
+                                            if let Some(m) = layer.moves.iter().find(|m| Some(m.command_index) == selected_command){
+                                                let highlight = Line::new(Values::from_values(vec![Value{x:m.start.x as f64,y:m.start.y as f64},Value{x:m.end.x as f64,y: m.end.y as f64 }]))
+                                                    .stroke(Stroke{width: 3.0,color: Color32::YELLOW});
+                                                plot_ui.line(highlight.name(&get_translated_string(&ctx, lang, "selected_move")));
+                                            }
+                                        }
+
+                                        plot_ui.pointer_coordinate()
+                                    })
+                                }).inner;
+
+                                //Clicking a move selects it and scrolls the G-code viewer to its line
+                                if resp.response.interact(Sense::click()).clicked() {
+                                    let clicked = resp.inner
+                                        .zip(layer_moves.get(index))
+                                        .and_then(|(pointer, layer)| layer.get_closest_move(Vec2::new(pointer.x as f32, pointer.y as f32)));
+                                    if let Some(m) = clicked {
+                                        selected_command = Some(m.command_index);
+                                        if let Some(line) = gcode_lines.as_ref().and_then(|lines| lines.get_command_line(m.command_index)) {
+                                            selected_line = Some(line);
+                                            scroll_to_line = Some(line);
+                                            gcode_open = true;
+                                        }
                                     }
-                                });
+                                }
 
                                 drag_resp.union(resp.response)
                             });
                    }
 
+                   if let (Some(layer_moves), Some(layer_statistics)) = (gui_data.get_layers(), gui_data.get_layer_statistics()) {
+                       let mut jump_to_layer = None;
+
+                       egui::Window::new(&get_translated_string(&ctx, lang, "layer_statistics"))
+                           .open(&mut statistics_open)
+                           .default_size(egui::Vec2::new(400.0, 600.0))
+                           .show(&egui_ctx, |ui| {
+                               egui::ScrollArea::vertical().show(ui, |ui| {
+                                   for stat in LayerStatistic::ALL {
+                                       ui.label(format!("{} ({})", get_translated_string(&ctx, lang, stat.get_translation_key()), stat.get_unit()));
+
+                                       let values = layer_statistics.get_values(stat);
+                                       let to_plot_values = || Values::from_values_iter(values.iter().enumerate().map(|(i, v)| Value{x: i as f64, y: *v}));
+
+                                       let resp = Plot::new(stat.get_translation_key())
+                                           .height(120.0)
+                                           .show(ui, |plot_ui| {
+                                               plot_ui.line(Line::new(to_plot_values()));
+                                               plot_ui.points(Points::new(to_plot_values()).radius(2.0));
+                                               plot_ui.vline(VLine::new(index as f64).color(Color32::GRAY));
+
+                                               //Point of the layer under the pointer if the pointer is on it
+                                               plot_ui.pointer_coordinate().and_then(|pointer| {
+                                                   let layer = pointer.x.round().max(0.0) as usize;
+                                                   let point = plot_ui.screen_from_plot(Value{x: layer as f64, y: *values.get(layer)?});
+                                                   (point.distance(plot_ui.screen_from_plot(pointer)) <= PLOT_POINT_HIT_RADIUS).then(|| layer)
+                                               })
+                                           });
+
+                                       //Clicking a point jumps the viewers to that layer
+                                       if let (true, Some(layer)) = (resp.response.interact(Sense::click()).clicked(), resp.inner) {
+                                           jump_to_layer = Some(layer);
+                                       }
+                                   }
+                               });
+                           });
+
+                       if let Some(layer) = jump_to_layer {
+                           index = layer.min(layer_moves.len().saturating_sub(1));
+                           toolpath_layer = index;
+                           viewer_open = true;
+                       }
+                   }
+
+                   if let (Some(gcode), Some(gcode_lines)) = (gui_data.get_gcode(), gui_data.get_gcode_lines()) {
+                       let mut clicked_line = None;
+
+                       egui::Window::new(&get_translated_string(&ctx, lang, "gcode"))
+                           .open(&mut gcode_open)
+                           .default_size(egui::Vec2::new(500.0, 600.0))
+                           .show(&egui_ctx, |ui| {
+                               ui.horizontal(|ui| {
+                                   let search_resp = ui.text_edit_singleline(&mut gcode_search);
+                                   let submitted = search_resp.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+                                   let previous = ui.button(&get_translated_string(&ctx, lang, "previous")).clicked();
+                                   let next = ui.button(&get_translated_string(&ctx, lang, "next")).clicked() || submitted;
+                                   if previous || next {
+                                       let found = gcode_lines.find(&gcode, &gcode_search, selected_line, next)
+                                           //Wrap around once the start or end of the file is reached
+                                           .or_else(|| gcode_lines.find(&gcode, &gcode_search, None, next));
+                                       if let Some(line) = found {
+                                           clicked_line = Some(line);
+                                           scroll_to_line = Some(line);
+                                       }
+                                   }
+                               });
+                               ui.label(get_translated_string_argument(&ctx, lang, "line_count_msg", gcode_lines.len().to_string()));
+
+                               let row_height = ui.text_style_height(&TextStyle::Monospace);
+                               let number_width = gcode_lines.len().to_string().len();
+                               let mut scroll_area = egui::ScrollArea::both().auto_shrink([false, false]);
+                               if let Some(line) = scroll_to_line.take() {
+                                   //Center the line in the view
+                                   let offset = line as f32 * (row_height + ui.spacing().item_spacing.y) - ui.available_height() / 2.0;
+                                   scroll_area = scroll_area.vertical_scroll_offset(offset.max(0.0));
+                               }
+
+                               scroll_area.show_rows(ui, row_height, gcode_lines.len(), |ui, rows| {
+                                   for line in rows {
+                                       ui.horizontal(|ui| {
+                                           ui.label(RichText::new(format!("{:>width$}", line + 1, width = number_width)).monospace().weak());
+                                           let mut text = RichText::new(gcode_lines.get_line(&gcode, line)).monospace();
+                                           if selected_line == Some(line) {
+                                               text = text.background_color(Color32::from_rgb(90, 90, 0));
+                                           }
+                                           if ui.add(egui::Label::new(text).wrap(false).sense(Sense::click())).clicked() {
+                                               clicked_line = Some(line);
+                                           }
+                                       });
+                                   }
+                               });
+                           });
+
+                       //Selecting a line shows the move it generated in the viewers
+                       if let Some(line) = clicked_line {
+                           selected_line = Some(line);
+                           selected_command = gcode_lines.get_line_command(line);
+                           let found = selected_command.zip(gui_data.get_layers()).and_then(|(command, layer_moves)| find_move(&layer_moves, command));
+                           if let Some((layer, _)) = found {
+                               index = layer;
+                               toolpath_layer = layer;
+                               viewer_open = true;
+                           }
+                       }
+                   }
+
+                   egui::Window::new(&get_translated_string(&ctx, lang, "post_processing"))
+                       .open(&mut post_processing_open)
+                       .show(&egui_ctx, |ui| {
+                           show_post_processor_editor(ui, &mut gui_data, &ctx, lang);
+                       });
+
+                   egui::Window::new(&get_translated_string(&ctx, lang, "preferences"))
+                       .open(&mut preferences_open)
+                       .show(&egui_ctx, |ui| {
+                           show_preferences(ui, &mut gui_data, &ctx, lang);
+                       });
+
+                   egui::Window::new(&get_translated_string(&ctx, lang, "printer"))
+                       .open(&mut printer_open)
+                       .show(&egui_ctx, |ui| {
+                           show_printer_status(ui, &gui_data, &mut follow_printer, &ctx, lang);
+                       });
+                   if !printer_open && gui_data.is_monitoring() {
+                       gui_data.stop_monitoring();
+                       follow_printer = false;
+                   }
+
+                   egui::Window::new(&get_translated_string(&ctx, lang, "usb_printer"))
+                       .open(&mut serial_open)
+                       .default_size(egui::Vec2::new(400.0, 500.0))
+                       .show(&egui_ctx, |ui| {
+                           show_serial_console(ui, &mut gui_data, &mut serial_ports, &mut console_input, &ctx, lang);
+                       });
+
+                   egui::Window::new(&get_translated_string(&ctx, lang, "materials"))
+                       .open(&mut materials_open)
+                       .show(&egui_ctx, |ui| {
+                           show_material_editor(ui, &mut gui_data, &ctx, lang);
+                       });
+
+                   let color_map = gui_data.get_comparison_color_map(&display);
+                   let mut comparison_open = true;
+                   if let (Some(kept), Some(current_layers), Some(color_map)) = (gui_data.get_comparison(), gui_data.get_layers(), color_map) {
+                       let kept_layers = kept.layers.clone().unwrap_or_default();
+
+                       egui::Window::new(&get_translated_string(&ctx, lang, "comparison"))
+                           .open(&mut comparison_open)
+                           .default_size(egui::Vec2::new(800.0, 600.0))
+                           .show(&egui_ctx, |ui| {
+                               egui::Grid::new("comparison_diff").striped(true).show(ui, |ui| {
+                                   ui.label("");
+                                   ui.label(get_translated_string(&ctx, lang, "kept_result"));
+                                   ui.label(get_translated_string(&ctx, lang, "current_result"));
+                                   ui.label(get_translated_string(&ctx, lang, "difference"));
+                                   ui.end_row();
+
+                                   let time = (kept.get_print_time(), gui_data.get_print_time());
+                                   ui.label(get_translated_string(&ctx, lang, "print_time"));
+                                   show_comparison_row(ui, time, format_duration);
+                                   ui.end_row();
+
+                                   let weight = (kept.get_plastic_weight(), gui_data.get_plastic_weight());
+                                   ui.label(get_translated_string(&ctx, lang, "filament_weight"));
+                                   show_comparison_row(ui, weight, |w| format!("{:.1} g", w));
+                                   ui.end_row();
+                               });
+
+                               let layer_count = current_layers.len().max(kept_layers.len());
+                               index = index.min(layer_count.saturating_sub(1));
+                               ui.add(egui::Slider::new(&mut index, 0..=layer_count.saturating_sub(1))
+                                   .text(&get_translated_string(&ctx, lang, "layer")));
+
+                               let layer_time = |layers: &[LayerMoves]| layers.get(index).map(|layer| layer.time);
+                               ui.horizontal(|ui| {
+                                   ui.label(get_translated_string(&ctx, lang, "layer_time"));
+                                   show_comparison_row(ui, (layer_time(&kept_layers[..]), layer_time(&current_layers[..])), format_duration);
+                               });
+
+                               let time_line = |layers: &[LayerMoves]| Line::new(Values::from_values(
+                                   layers.iter().enumerate().map(|(i, layer)| Value{x: i as f64, y: layer.time}).collect()
+                               ));
+                               Plot::new("comparison_layer_times")
+                                   .height(120.0)
+                                   .legend(Legend::default().position(Corner::LeftTop))
+                                   .show(ui, |plot_ui| {
+                                       plot_ui.line(time_line(&kept_layers[..]).name(get_translated_string(&ctx, lang, "kept_result")));
+                                       plot_ui.line(time_line(&current_layers[..]).name(get_translated_string(&ctx, lang, "current_result")));
+                                       plot_ui.vline(VLine::new(index as f64).color(Color32::GRAY));
+                                   });
+
+                               //Both views share their axes so panning and zooming stay in sync
+                               let view_width = ui.available_width() / 2.0 - ui.spacing().item_spacing.x;
+                               ui.horizontal_top(|ui| {
+                                   for (id, layers) in [("comparison_kept", &kept_layers[..]), ("comparison_current", &current_layers[..])] {
+                                       Plot::new(id)
+                                           .width(view_width)
+                                           .data_aspect(1.0)
+                                           .show_x(false)
+                                           .show_y(false)
+                                           .link_axis(comparison_axes.clone())
+                                           .show(ui, |plot_ui| {
+                                               if let Some(layer) = layers.get(index){
+                                                   plot_layer_extrusions(plot_ui, layer, &color_map);
+                                               }
+                                           });
+                                   }
+                               });
+
+                               show_color_legend(ui, &color_map, &ctx, lang);
+                           });
+                   }
+                   //Closing the window drops the kept result
+                   if !comparison_open {
+                       gui_data.clear_comparison();
+                   }
+
 
 
                 });
@@ -436,6 +1121,15 @@ fn main() {
                     }
                 };
 
+               //Highlight the whole window as the drop target while files are dragged over it
+               if file_hovered {
+                   let painter = egui_ctx.layer_painter(LayerId::new(Order::Foreground, Id::new("drop_target")));
+                   let rect = egui_ctx.input().screen_rect();
+                   painter.rect_filled(rect, 0.0, Color32::from_black_alpha(160));
+                   painter.rect_stroke(rect.shrink(4.0), 8.0, Stroke::new(4.0, Color32::LIGHT_BLUE));
+                   painter.text(rect.center(), Align2::CENTER_CENTER, get_translated_string(&ctx, lang, "drop_files"), FontId::proportional(32.0), Color32::WHITE);
+               }
+
                on_render_screen = !egui_ctx.wants_pointer_input();
             });
 
@@ -447,9 +1141,12 @@ fn main() {
                 display.gl_window().window().request_redraw();
 
                 glutin::event_loop::ControlFlow::Poll
-            } else if gui_data.is_command_running(){
-                //If command is running keep refreshing
+            } else if gui_data.is_command_running() || gui_data.get_upload_progress().is_some() || gui_data.get_model_load().is_some(){
+                //If command, upload or model load is running keep refreshing
                 glutin::event_loop::ControlFlow::Poll
+            } else if gui_data.is_monitoring() || gui_data.get_serial().is_some(){
+                //Redraw now and then to show the latest printer state
+                glutin::event_loop::ControlFlow::WaitUntil(Instant::now() + MONITOR_REDRAW_INTERVAL)
             }else{
                 glutin::event_loop::ControlFlow::Wait
             };
@@ -481,11 +1178,37 @@ fn main() {
                 gui_data.update_colors();
                 gui_data.update_screen_dimensions(Vec2::new(target.get_dimensions().0 as f32,target.get_dimensions().1 as f32 ));
 
-                for obj in gui_data.get_objects(){
-                    let model = obj.get_model_matrix().to_cols_array_2d();
-                    let color = obj.color.to_array();
-                    let (positions, indices) = (&obj.vert_buff,&obj.index_buff);//create_mesh(&display);
-                    target.draw(positions, indices, &model_program, &uniform! {color: color,  model: model, view: view, perspective: perspective }, &params).unwrap();
+                let toolpath = if show_toolpath { gui_data.get_toolpath(&display) } else { None };
+
+                if let Some(toolpath) = toolpath {
+                    for layer in toolpath.layers.iter().take(toolpath_layer.saturating_add(1)){
+                        if !layer.is_empty(){
+                            target.draw((&layer.vert_buff, &layer.color_buff), &layer.index_buff, &toolpath_program, &uniform! { model: line_model, view: view, perspective: perspective }, &params).unwrap();
+                        }
+
+                        for (overlay, vert_buff) in layer.overlays.iter().filter(|(overlay, _)| overlay_visibility.is_visible(*overlay)){
+                            let primitive = if *overlay == Overlay::Travel {
+                                glium::index::PrimitiveType::LinesList
+                            } else {
+                                glium::index::PrimitiveType::Points
+                            };
+                            let overlay_params = glium::DrawParameters {
+                                point_size: Some(6.0),
+                                .. params.clone()
+                            };
+                            let color = overlay.get_color();
+                            let color = [color.r() as f32 / 255.0, color.g() as f32 / 255.0, color.b() as f32 / 255.0];
+                            target.draw(vert_buff, glium::index::NoIndices(primitive), &overlay_program, &uniform! { color: color, model: line_model, view: view, perspective: perspective }, &overlay_params).unwrap();
+                        }
+                    }
+                }
+                else{
+                    for obj in gui_data.get_objects(){
+                        let model = obj.mesh.get_model_matrix().to_cols_array_2d();
+                        let color = obj.color.to_array();
+                        let (positions, indices) = (&obj.render.vert_buff,&obj.render.index_buff);//create_mesh(&display);
+                        target.draw(positions, indices, &model_program, &uniform! {color: color,  model: model, view: view, perspective: perspective }, &params).unwrap();
+                    }
                 }
 
 
@@ -512,6 +1235,9 @@ fn main() {
             // See: https://github.com/rust-windowing/winit/issues/1619
             glutin::event::Event::RedrawEventsCleared if cfg!(windows) => redraw(),
             glutin::event::Event::RedrawRequested(_) if !cfg!(windows) => redraw(),
+            glutin::event::Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
+                display.gl_window().window().request_redraw();
+            }
 
             glutin::event::Event::WindowEvent { event, .. } => {
 
@@ -537,6 +1263,16 @@ fn main() {
                     WindowEvent::CursorLeft {..} =>{
                         in_window = false;
                     }
+                    WindowEvent::HoveredFile(_) => {
+                        file_hovered = true;
+                    }
+                    WindowEvent::HoveredFileCancelled => {
+                        file_hovered = false;
+                    }
+                    WindowEvent::DroppedFile(ref path) => {
+                        file_hovered = false;
+                        dropped_files.push(path.clone());
+                    }
                     _ => {}
                 }
 
@@ -601,4 +1337,644 @@ fn get_translated_string_arguments(ctx:& JSONGetText, lang: &str,index_str: &str
     }
 
     ret_string
-}
\ No newline at end of file
+}
+
+fn show_color_legend(ui: &mut egui::Ui, color_map: &ColorMap, ctx: &JSONGetText, lang: &str){
+    let scheme = color_map.get_scheme();
+    ui.horizontal(|ui| {
+        ui.label(get_translated_string(ctx, lang, scheme.get_translation_key()));
+    });
+
+    if scheme.is_categorical(){
+        for (color, key) in color_map.get_legend(){
+            ui.horizontal(|ui| {
+                let (rect, _) = ui.allocate_exact_size(egui::Vec2::new(12.0, 12.0), Sense::hover());
+                ui.painter().rect_filled(rect, 2.0, color);
+                ui.label(get_translated_string(ctx, lang, key));
+            });
+        }
+    }
+    else if let Some((min, max)) = color_map.get_range(){
+        ui.horizontal(|ui| {
+            ui.label(format!("{:.1} {}", min, scheme.get_unit()));
+
+            let (rect, _) = ui.allocate_exact_size(egui::Vec2::new(120.0, 12.0), Sense::hover());
+            let steps = 32;
+            for step in 0..steps{
+                let t0 = step as f32 / steps as f32;
+                let t1 = (step + 1) as f32 / steps as f32;
+                let slice = egui::Rect::from_min_max(
+                    Pos2{x: rect.left() + rect.width() * t0, y: rect.top()},
+                    Pos2{x: rect.left() + rect.width() * t1, y: rect.bottom()},
+                );
+                ui.painter().rect_filled(slice, 0.0, gradient_color((t0 + t1) / 2.0));
+            }
+
+            ui.label(format!("{:.1} {}", max, scheme.get_unit()));
+        });
+    }
+}
+
+fn show_overlay_checkboxes(ui: &mut egui::Ui, visibility: &mut OverlayVisibility, ctx: &JSONGetText, lang: &str){
+    let toggles = [
+        (&mut visibility.travel, "overlay_travel", vec![Overlay::Travel]),
+        (&mut visibility.retractions, "overlay_retractions", vec![Overlay::Retraction, Overlay::Unretraction]),
+        (&mut visibility.z_hops, "overlay_z_hop", vec![Overlay::ZHop]),
+        (&mut visibility.seams, "overlay_seam", vec![Overlay::Seam]),
+    ];
+
+    for (visible, key, overlays) in toggles{
+        ui.horizontal(|ui| {
+            ui.checkbox(visible, &get_translated_string(ctx, lang, key));
+            for overlay in overlays{
+                let (rect, _) = ui.allocate_exact_size(egui::Vec2::new(12.0, 12.0), Sense::hover());
+                ui.painter().rect_filled(rect, 2.0, overlay.get_color());
+            }
+        });
+    }
+}
+
+fn show_move_readout(ui: &mut egui::Ui, layer: &LayerMoves, current: &ToolpathMove, cmd: &gladius_shared::types::Command, ctx: &JSONGetText, lang: &str){
+    let kind = if current.width.is_some() { "extrude" } else { "travel" };
+    ui.heading(get_translated_string(ctx, lang, kind));
+    ui.label(format!("X: {:.3} -> {:.3}", current.start.x, current.end.x));
+    ui.label(format!("Y: {:.3} -> {:.3}", current.start.y, current.end.y));
+    ui.label(format!("Z: {:.3}", layer.z));
+    ui.label(get_translated_string_argument(ctx, lang, "feedrate_msg", format!("{:.1}", current.speed)));
+    if let Some(width) = current.width{
+        ui.label(get_translated_string_argument(ctx, lang, "width_msg", format!("{:.3}", width)));
+    }
+    ui.separator();
+    ui.label(format!("{:?}", cmd));
+}
+
+fn plot_layer_extrusions(plot_ui: &mut PlotUi, layer: &LayerMoves, color_map: &ColorMap){
+    let p1 = plot_ui.screen_from_plot(Value{x:0.0,y:0.0});
+    let p2 = plot_ui.screen_from_plot(Value{x:0.0,y:1.0});
+    let pixels_per_plot_unit = (p2-p1).length();
+
+    for extrusion in &layer.extrusions{
+        let move_line = Line::new(Values::from_values(vec![Value{x:extrusion.start.x as f64,y:extrusion.start.y as f64},Value{x:extrusion.end.x as f64,y: extrusion.end.y as f64 }]))
+            .stroke(Stroke{width: extrusion.width * pixels_per_plot_unit* 0.95,color: color_map.get_color(layer, extrusion)});
+        plot_ui.line(move_line);
+    }
+}
+
+///Show the kept value, the current value and their difference as grid cells
+fn show_comparison_row(ui: &mut egui::Ui, (kept, current): (Option<f64>, Option<f64>), format: impl Fn(f64) -> String){
+    let cell = |value: Option<f64>| value.map(&format).unwrap_or_else(|| String::from("-"));
+    ui.label(cell(kept));
+    ui.label(cell(current));
+
+    match (kept, current){
+        (Some(kept), Some(current)) => {
+            let diff = current - kept;
+            let sign = if diff < 0.0 { "-" } else { "+" };
+            ui.label(format!("{}{}", sign, format(diff.abs())));
+        }
+        _ => {
+            ui.label("-");
+        }
+    }
+}
+
+fn format_duration(seconds: f64) -> String{
+    let total = seconds.round() as u64;
+    let (hours, minutes, seconds) = (total / 3600, (total / 60) % 60, total % 60);
+    if hours > 0 {
+        format!("{}h {}m {}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+fn show_filament_usage(ui: &mut egui::Ui, gui_data: &mut GUIData, volume: f64, ctx: &JSONGetText, lang: &str){
+    let mut selected = gui_data.get_selected_material_index();
+    egui::ComboBox::from_label(&get_translated_string(ctx, lang, "material"))
+        .selected_text(&gui_data.get_selected_material().name)
+        .show_ui(ui, |ui| {
+            for (i, material) in gui_data.get_materials().iter().enumerate(){
+                ui.selectable_value(&mut selected, i, &material.name);
+            }
+        });
+    gui_data.set_selected_material(selected);
+
+    let material = gui_data.get_selected_material().clone();
+    let usage = material.get_usage(volume);
+    ui.label(get_translated_string_argument(ctx, lang, "filament_length_msg", format!("{:.2}", usage.length / 1000.0)));
+    ui.label(get_translated_string_argument(ctx, lang, "filament_weight_msg", format!("{:.1}", usage.weight)));
+    ui.label(get_translated_string_argument(ctx, lang, "filament_cost_msg", format!("{:.2}", usage.cost)));
+
+    let remaining = material.spool_remaining - usage.weight;
+    ui.label(get_translated_string_arguments(ctx, lang, "spool_remaining_msg", &[format!("{:.0}", material.spool_remaining), format!("{:.0}", remaining)]));
+    if remaining < 0.0{
+        ui.colored_label(Color32::RED, get_translated_string(ctx, lang, "spool_insufficient"));
+    }
+    if ui.button(&get_translated_string(ctx, lang, "mark_as_printed")).clicked(){
+        gui_data.consume_filament(usage.weight);
+    }
+
+    if let Some(object_volumes) = gui_data.get_object_volumes(){
+        //Only worth showing if the slicer marked which object is being printed
+        if object_volumes.keys().any(|object| object.is_some()){
+            ui.collapsing(get_translated_string(ctx, lang, "per_object_usage"), |ui| {
+                for (object, volume) in object_volumes{
+                    let name = object
+                        .and_then(|index| gui_data.get_objects().get(index))
+                        .map(|obj| obj.mesh.name.clone())
+                        .unwrap_or_else(|| get_translated_string(ctx, lang, "other"));
+                    let usage = material.get_usage(volume);
+                    ui.label(format!("{}: {:.1} g, {:.2}", name, usage.weight, usage.cost));
+                }
+            });
+        }
+    }
+}
+
+fn show_material_editor(ui: &mut egui::Ui, gui_data: &mut GUIData, ctx: &JSONGetText, lang: &str){
+    let mut remove = None;
+
+    for (i, material) in gui_data.get_mut_materials().iter_mut().enumerate(){
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut material.name);
+                if ui.button(&get_translated_string(ctx, lang, "remove")).clicked(){
+                    remove = Some(i);
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut material.density).speed(0.01).clamp_range(0.1..=f64::INFINITY).prefix("g/cm^3: "));
+                ui.add(egui::DragValue::new(&mut material.diameter).speed(0.01).clamp_range(0.1..=f64::INFINITY).prefix("mm: "));
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut material.price_per_kg).speed(0.1).clamp_range(0.0..=f64::INFINITY).prefix(get_translated_string(ctx, lang, "price_per_kg")));
+                ui.add(egui::DragValue::new(&mut material.spool_remaining).speed(1.0).clamp_range(0.0..=f64::INFINITY).prefix(get_translated_string(ctx, lang, "spool_grams")));
+            });
+        });
+    }
+
+    if let Some(i) = remove{
+        gui_data.remove_material(i);
+    }
+
+    ui.horizontal(|ui| {
+        if ui.button(&get_translated_string(ctx, lang, "add")).clicked(){
+            gui_data.add_material();
+        }
+        if ui.button(&get_translated_string(ctx, lang, "save_materials")).clicked(){
+            gui_data.save_materials();
+        }
+    });
+}
+
+fn show_post_processor_editor(ui: &mut egui::Ui, gui_data: &mut GUIData, ctx: &JSONGetText, lang: &str){
+    let mut remove = None;
+    let mut move_up = None;
+
+    ui.label(get_translated_string(ctx, lang, "post_processing_msg"));
+
+    for (i, processor) in gui_data.get_mut_post_processors().iter_mut().enumerate(){
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.strong(get_translated_string(ctx, lang, processor.get_translation_key()));
+                if i > 0 && ui.small_button("^").clicked(){
+                    move_up = Some(i);
+                }
+                if ui.small_button(&get_translated_string(ctx, lang, "remove")).clicked(){
+                    remove = Some(i);
+                }
+            });
+
+            match processor{
+                PostProcessor::Script { command } => {
+                    ui.text_edit_singleline(command);
+                }
+                PostProcessor::PauseAtLayer { layer } => {
+                    ui.add(egui::DragValue::new(layer).clamp_range(1..=usize::MAX).prefix(get_translated_string(ctx, lang, "layer_prefix")));
+                }
+                PostProcessor::FilamentChangeAtHeight { height } => {
+                    ui.add(egui::DragValue::new(height).speed(0.1).clamp_range(0.0..=f64::INFINITY).suffix(" mm"));
+                }
+                PostProcessor::TemperatureTower { start_height, step_height, start_temperature, step_temperature } => {
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(start_height).speed(0.1).clamp_range(0.0..=f64::INFINITY).prefix(get_translated_string(ctx, lang, "start_prefix")).suffix(" mm"));
+                        ui.add(egui::DragValue::new(step_height).speed(0.1).clamp_range(0.1..=f64::INFINITY).prefix(get_translated_string(ctx, lang, "step_prefix")).suffix(" mm"));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(start_temperature).speed(1.0).prefix(get_translated_string(ctx, lang, "start_prefix")).suffix(" C"));
+                        ui.add(egui::DragValue::new(step_temperature).speed(1.0).prefix(get_translated_string(ctx, lang, "step_prefix")).suffix(" C"));
+                    });
+                }
+                PostProcessor::HeaderFooter { header, footer } => {
+                    ui.label(get_translated_string(ctx, lang, "header"));
+                    ui.text_edit_multiline(header);
+                    ui.label(get_translated_string(ctx, lang, "footer"));
+                    ui.text_edit_multiline(footer);
+                }
+            }
+        });
+    }
+
+    let processors = gui_data.get_mut_post_processors();
+    if let Some(i) = remove{
+        processors.remove(i);
+    }
+    if let Some(i) = move_up{
+        processors.swap(i - 1, i);
+    }
+
+    ui.horizontal_wrapped(|ui| {
+        for processor in PostProcessor::get_defaults(){
+            let text = get_translated_string_argument(ctx, lang, "add_msg", get_translated_string(ctx, lang, processor.get_translation_key()));
+            if ui.button(text).clicked(){
+                gui_data.get_mut_post_processors().push(processor);
+            }
+        }
+    });
+
+    if ui.button(&get_translated_string(ctx, lang, "save_post_processing")).clicked(){
+        gui_data.save_post_processors();
+    }
+}
+
+///Mark the layers with insertions on the layer slider
+fn show_layer_insertion_markers(ui: &mut egui::Ui, slider: &egui::Response, gui_data: &GUIData, layer_moves: &[LayerMoves]){
+    if layer_moves.len() < 2 {
+        return;
+    }
+
+    for insertion in gui_data.get_layer_insertions(){
+        let layer = layer_moves.partition_point(|layer| (layer.z as f64) < insertion.z - 0.001);
+        let x = get_slider_layer_x(ui, slider, layer, layer_moves.len());
+        ui.painter().circle_filled(Pos2::new(x, slider.rect.top() + 3.0), 3.0, insertion.action.get_color());
+    }
+}
+
+fn show_printer_layer_marker(ui: &mut egui::Ui, slider: &egui::Response, layer: usize, layer_count: usize){
+    if layer_count < 2 {
+        return;
+    }
+
+    let x = get_slider_layer_x(ui, slider, layer, layer_count);
+    ui.painter().circle_filled(Pos2::new(x, slider.rect.bottom() - 3.0), 3.0, Color32::LIGHT_GREEN);
+}
+
+///Horizontal position of a layer on the layer slider, needs at least two layers
+fn get_slider_layer_x(ui: &egui::Ui, slider: &egui::Response, layer: usize, layer_count: usize) -> f32{
+    //The slider rail is the first part of the response, the handle stops its radius from the ends
+    let handle_radius = slider.rect.height() / 2.5;
+    let left = slider.rect.left() + handle_radius;
+    let width = ui.spacing().slider_width - 2.0 * handle_radius;
+    left + width * layer.min(layer_count - 1) as f32 / (layer_count - 1) as f32
+}
+
+fn show_printer_status(ui: &mut egui::Ui, gui_data: &GUIData, follow_printer: &mut bool, ctx: &JSONGetText, lang: &str){
+    if let Some(host) = gui_data.get_print_host(){
+        ui.heading(&host.name);
+    }
+
+    let status = match gui_data.get_printer_status(){
+        Some(Ok(status)) => status,
+        Some(Err(e)) => {
+            ui.label(get_translated_string_argument(ctx, lang, "printer_unreachable_msg", e));
+            return;
+        }
+        None => {
+            ui.label(get_translated_string(ctx, lang, "connecting"));
+            return;
+        }
+    };
+
+    ui.label(get_translated_string_argument(ctx, lang, "printer_state_msg", status.state.clone()));
+
+    egui::Grid::new("printer_temperatures").show(ui, |ui| {
+        for temperature in &status.temperatures{
+            ui.label(&temperature.name);
+            ui.label(format!("{:.1} °C", temperature.actual));
+            ui.label(format!("/ {:.0} °C", temperature.target));
+            ui.end_row();
+        }
+    });
+
+    if let Some(file_name) = status.file_name.as_ref(){
+        ui.separator();
+        ui.label(file_name);
+        if let Some(progress) = status.progress{
+            ui.add(egui::ProgressBar::new(progress).show_percentage());
+        }
+        if let Some(time) = status.print_time{
+            ui.label(get_translated_string_argument(ctx, lang, "printing_time_msg", format_duration(time)));
+        }
+        if let Some(time) = status.time_left{
+            ui.label(get_translated_string_argument(ctx, lang, "time_left_msg", format_duration(time)));
+        }
+
+        if let (Some((layer, _)), Some(layers)) = (gui_data.get_printer_position(), gui_data.get_layers()){
+            ui.label(get_translated_string_arguments(ctx, lang, "printer_layer_msg", &[(layer + 1).to_string(), layers.len().to_string()]));
+            ui.checkbox(follow_printer, get_translated_string(ctx, lang, "follow_printer"));
+        }
+    }
+
+    ui.separator();
+    ui.horizontal(|ui| {
+        for command in [JobCommand::Pause, JobCommand::Resume, JobCommand::Cancel]{
+            let button = egui::Button::new(get_translated_string(ctx, lang, command.get_translation_key()));
+            if ui.add_enabled(command.is_available(status.activity), button).clicked(){
+                gui_data.send_printer_command(command);
+            }
+        }
+    });
+}
+
+fn show_layer_insertion_menu(ui: &mut egui::Ui, gui_data: &mut GUIData, z: f64, custom: &mut String, ctx: &JSONGetText, lang: &str){
+    ui.label(get_translated_string_argument(ctx, lang, "layer_height_msg", format!("{:.2}", z)));
+
+    for action in [LayerAction::Pause, LayerAction::FilamentChange]{
+        if ui.button(&get_translated_string(ctx, lang, action.get_translation_key())).clicked(){
+            gui_data.add_layer_insertion(z, action);
+            ui.close_menu();
+        }
+    }
+
+    ui.horizontal(|ui| {
+        ui.text_edit_singleline(custom);
+        if ui.button(&get_translated_string(ctx, lang, "insert_custom")).clicked() && !custom.is_empty(){
+            gui_data.add_layer_insertion(z, LayerAction::Custom(custom.clone()));
+            ui.close_menu();
+        }
+    });
+
+    let existing: Vec<_> = gui_data.get_layer_insertions().iter()
+        .filter(|insertion| insertion.is_at_height(z))
+        .map(|insertion| insertion.action.get_gcode().to_string())
+        .collect();
+    if !existing.is_empty(){
+        ui.separator();
+        for gcode in existing{
+            ui.monospace(gcode);
+        }
+        if ui.button(&get_translated_string(ctx, lang, "remove_insertions")).clicked(){
+            gui_data.remove_layer_insertions_at(z);
+            ui.close_menu();
+        }
+    }
+}
+
+fn show_preferences(ui: &mut egui::Ui, gui_data: &mut GUIData, ctx: &JSONGetText, lang: &str){
+    let preferences = gui_data.get_mut_preferences();
+
+    ui.heading(get_translated_string(ctx, lang, "thumbnails"));
+    ui.checkbox(&mut preferences.embed_thumbnails, get_translated_string(ctx, lang, "embed_thumbnails"));
+
+    let mut remove = None;
+    for (i, [width, height]) in preferences.thumbnail_sizes.iter_mut().enumerate(){
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(width).clamp_range(1..=1024).suffix(" px"));
+            ui.label("x");
+            ui.add(egui::DragValue::new(height).clamp_range(1..=1024).suffix(" px"));
+            if ui.button(&get_translated_string(ctx, lang, "remove")).clicked(){
+                remove = Some(i);
+            }
+        });
+    }
+    if let Some(i) = remove{
+        preferences.thumbnail_sizes.remove(i);
+    }
+    if ui.button(&get_translated_string(ctx, lang, "add")).clicked(){
+        preferences.thumbnail_sizes.push([300, 300]);
+    }
+
+    ui.separator();
+    ui.heading(get_translated_string(ctx, lang, "models"));
+    ui.checkbox(&mut preferences.repair_models, get_translated_string(ctx, lang, "repair_models"));
+
+    ui.separator();
+    ui.heading(get_translated_string(ctx, lang, "export_presets"));
+    ui.label(get_translated_string(ctx, lang, "filename_template_msg"));
+
+    let mut remove = None;
+    for (i, preset) in preferences.export_presets.iter_mut().enumerate(){
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut preset.name);
+                if ui.button(&get_translated_string(ctx, lang, "remove")).clicked(){
+                    remove = Some(i);
+                }
+            });
+            egui::ComboBox::from_id_source(("export_format", i))
+                .selected_text(&get_translated_string(ctx, lang, preset.format.get_translation_key()))
+                .show_ui(ui, |ui| {
+                    for format in ExportFormat::ALL{
+                        ui.selectable_value(&mut preset.format, format, &get_translated_string(ctx, lang, format.get_translation_key()));
+                    }
+                });
+            ui.text_edit_singleline(&mut preset.filename_template);
+        });
+    }
+    //Keep at least one preset so one is always selected
+    if let Some(i) = remove.filter(|_| preferences.export_presets.len() > 1){
+        preferences.export_presets.remove(i);
+        preferences.selected_export_preset = preferences.selected_export_preset.min(preferences.export_presets.len() - 1);
+    }
+    if ui.button(&get_translated_string(ctx, lang, "add")).clicked(){
+        preferences.export_presets.push(ExportPreset::get_defaults().remove(0));
+    }
+
+    ui.separator();
+    ui.heading(get_translated_string(ctx, lang, "print_hosts"));
+
+    let mut remove = None;
+    for (i, host) in preferences.print_hosts.iter_mut().enumerate(){
+        ui.group(|ui| {
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut host.name);
+                if ui.button(&get_translated_string(ctx, lang, "remove")).clicked(){
+                    remove = Some(i);
+                }
+            });
+            egui::ComboBox::from_id_source(("host_type", i))
+                .selected_text(host.host_type.get_name())
+                .show_ui(ui, |ui| {
+                    for host_type in HostType::ALL{
+                        ui.selectable_value(&mut host.host_type, host_type, host_type.get_name());
+                    }
+                });
+            ui.horizontal(|ui| {
+                ui.label(get_translated_string(ctx, lang, "url"));
+                ui.text_edit_singleline(&mut host.url);
+            });
+            ui.horizontal(|ui| {
+                ui.label(get_translated_string(ctx, lang, "api_key"));
+                ui.add(egui::TextEdit::singleline(&mut host.api_key).password(true));
+            });
+        });
+    }
+    if let Some(i) = remove{
+        preferences.print_hosts.remove(i);
+        preferences.selected_print_host = preferences.selected_print_host.min(preferences.print_hosts.len().saturating_sub(1));
+    }
+    if ui.button(&get_translated_string(ctx, lang, "add")).clicked(){
+        preferences.print_hosts.push(PrintHost::default());
+    }
+
+    ui.separator();
+    if ui.button(&get_translated_string(ctx, lang, "save_preferences")).clicked(){
+        gui_data.save_preferences();
+    }
+}
+
+fn show_serial_console(ui: &mut egui::Ui, gui_data: &mut GUIData, ports: &mut Vec<String>, input: &mut String, ctx: &JSONGetText, lang: &str){
+    let connected = gui_data.get_serial().map_or(false, |serial| serial.is_connected());
+
+    if !connected {
+        if let Some(e) = gui_data.get_serial().and_then(|serial| serial.read_state().error.clone()){
+            ui.label(get_translated_string_argument(ctx, lang, "serial_disconnected_msg", e));
+        }
+
+        let preferences = gui_data.get_mut_preferences();
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_label(&get_translated_string(ctx, lang, "serial_port"))
+                .selected_text(preferences.serial_port.clone().unwrap_or_default())
+                .show_ui(ui, |ui| {
+                    for port in ports.iter(){
+                        ui.selectable_value(&mut preferences.serial_port, Some(port.clone()), port);
+                    }
+                });
+            if ui.button(&get_translated_string(ctx, lang, "refresh")).clicked(){
+                *ports = get_available_ports();
+            }
+        });
+        egui::ComboBox::from_label(&get_translated_string(ctx, lang, "baud_rate"))
+            .selected_text(preferences.baud_rate.to_string())
+            .show_ui(ui, |ui| {
+                for baud_rate in BAUD_RATES{
+                    ui.selectable_value(&mut preferences.baud_rate, baud_rate, baud_rate.to_string());
+                }
+            });
+
+        let has_port = preferences.serial_port.is_some();
+        if ui.add_enabled(has_port, egui::Button::new(get_translated_string(ctx, lang, "connect"))).clicked(){
+            gui_data.connect_serial();
+        }
+        return;
+    }
+
+    if ui.button(&get_translated_string(ctx, lang, "disconnect")).clicked(){
+        gui_data.disconnect_serial();
+        return;
+    }
+
+    let has_gcode = gui_data.get_gcode().is_some();
+    let mut print = false;
+    let mut command = None;
+    let mut sent = None;
+
+    if let Some(serial) = gui_data.get_serial(){
+        let state = serial.read_state();
+
+        egui::Grid::new("serial_temperatures").show(ui, |ui| {
+            for temperature in &state.temperatures{
+                ui.label(&temperature.name);
+                ui.label(format!("{:.1} °C", temperature.actual));
+                ui.label(format!("/ {:.0} °C", temperature.target));
+                ui.end_row();
+            }
+        });
+
+        ui.separator();
+        match state.stream {
+            Some(stream) => {
+                ui.add(egui::ProgressBar::new(stream.get_fraction()).show_percentage());
+                ui.label(get_translated_string_arguments(ctx, lang, "streamed_lines_msg", &[stream.sent.to_string(), stream.total.to_string()]));
+            }
+            None => {
+                print = ui.add_enabled(has_gcode, egui::Button::new(get_translated_string(ctx, lang, "print_over_usb"))).clicked();
+            }
+        }
+        ui.horizontal(|ui| {
+            for job_command in [JobCommand::Pause, JobCommand::Resume, JobCommand::Cancel]{
+                let button = egui::Button::new(get_translated_string(ctx, lang, job_command.get_translation_key()));
+                if ui.add_enabled(job_command.is_available(state.get_activity()), button).clicked(){
+                    command = Some(job_command);
+                }
+            }
+        });
+
+        ui.separator();
+        egui::ScrollArea::vertical()
+            .max_height(300.0)
+            .auto_shrink([false, false])
+            .stick_to_bottom()
+            .show(ui, |ui| {
+                for line in &state.console{
+                    let text = RichText::new(&line.text).monospace();
+                    ui.label(if line.sent { text.weak() } else { text });
+                }
+            });
+
+        ui.horizontal(|ui| {
+            let response = ui.text_edit_singleline(input);
+            let entered = response.lost_focus() && ui.input().key_pressed(egui::Key::Enter);
+            if (entered || ui.button(&get_translated_string(ctx, lang, "send")).clicked()) && !input.is_empty(){
+                sent = Some(std::mem::take(input));
+                response.request_focus();
+            }
+        });
+    }
+
+    if let Some(serial) = gui_data.get_serial(){
+        if let Some(line) = sent{
+            serial.send_command(&line);
+        }
+        if let Some(command) = command{
+            serial.control(command);
+        }
+    }
+    if print {
+        gui_data.print_over_serial();
+    }
+}
+
+///Progress of the models being read in the background. Returns whether cancel was clicked.
+fn show_model_load(ui: &mut egui::Ui, gui_data: &GUIData, ctx: &JSONGetText, lang: &str) -> bool{
+    let load = match gui_data.get_model_load(){
+        Some(load) => load,
+        None => return false,
+    };
+    let queue = load.read_queue();
+
+    ui.label(get_translated_string(ctx, lang, "loading_models"));
+    ui.add(egui::ProgressBar::new(queue.get_fraction()).show_percentage());
+    egui::Grid::new("model_load_files").show(ui, |ui| {
+        for file in &queue.files{
+            ui.label(file.path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default());
+            ui.label(match file.state{
+                FileLoadState::Waiting => get_translated_string(ctx, lang, "file_waiting"),
+                FileLoadState::Parsing => get_translated_string(ctx, lang, "file_parsing"),
+                FileLoadState::Loaded(count) => get_translated_string_argument(ctx, lang, "file_loaded", count.to_string()),
+                FileLoadState::Failed => get_translated_string(ctx, lang, "file_failed"),
+                FileLoadState::Cancelled => get_translated_string(ctx, lang, "file_cancelled"),
+            });
+            ui.end_row();
+        }
+    });
+    ui.button(&get_translated_string(ctx, lang, "cancel_load")).clicked()
+}
+
+///Mark the model as healthy or list its problems on hover. Returns whether repair was clicked.
+fn show_health_badge(ui: &mut egui::Ui, health: &MeshHealth, ctx: &JSONGetText, lang: &str) -> bool{
+    if health.is_healthy(){
+        ui.colored_label(Color32::GREEN, "✔").on_hover_text(get_translated_string(ctx, lang, "model_healthy"));
+        return false;
+    }
+
+    let issues = health.get_issues()
+        .into_iter()
+        .map(|(issue, count)| get_translated_string_argument(ctx, lang, issue, count.to_string()))
+        .join("\n");
+    ui.colored_label(Color32::from_rgb(255, 165, 0), "⚠").on_hover_text(issues);
+
+    health.is_repairable() && ui.button(&get_translated_string(ctx, lang, "repair")).clicked()
+}
@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+
+use crate::gcode_parser::{DEFAULT_FILAMENT_DENSITY, DEFAULT_FILAMENT_DIAMETER};
+
+///File the material profiles are stored in, relative to the working directory like the slicer
+pub const MATERIALS_PATH: &str = "materials.json";
+
+///Properties of a filament used to estimate usage and cost
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MaterialProfile {
+    pub name: String,
+    ///Density in g/cm^3
+    pub density: f64,
+    ///Filament diameter in mm
+    pub diameter: f64,
+    pub price_per_kg: f64,
+    ///Grams of filament left on the spool
+    pub spool_remaining: f64,
+}
+
+impl Default for MaterialProfile {
+    fn default() -> Self {
+        MaterialProfile {
+            name: String::from("PLA"),
+            density: DEFAULT_FILAMENT_DENSITY,
+            diameter: DEFAULT_FILAMENT_DIAMETER,
+            price_per_kg: 20.0,
+            spool_remaining: 1000.0,
+        }
+    }
+}
+
+///Amount of filament needed for a print or part of one
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FilamentUsage {
+    ///Length in mm
+    pub length: f64,
+    ///Weight in grams
+    pub weight: f64,
+    pub cost: f64,
+}
+
+impl MaterialProfile {
+    pub fn get_cross_section_area(&self) -> f64 {
+        std::f64::consts::PI * (self.diameter / 2.0).powi(2)
+    }
+
+    ///Usage for the given volume of plastic in mm^3
+    pub fn get_usage(&self, volume: f64) -> FilamentUsage {
+        let weight = volume / 1000.0 * self.density;
+        FilamentUsage {
+            length: volume / self.get_cross_section_area(),
+            weight,
+            cost: weight / 1000.0 * self.price_per_kg,
+        }
+    }
+}
+
+///Load the material profiles, falling back to a single default profile if there are none
+pub fn load_materials() -> Vec<MaterialProfile> {
+    File::open(MATERIALS_PATH)
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .filter(|materials: &Vec<MaterialProfile>| !materials.is_empty())
+        .unwrap_or_else(|| vec![MaterialProfile::default()])
+}
+
+pub fn save_materials(materials: &[MaterialProfile]) -> std::io::Result<()> {
+    let file = File::create(MATERIALS_PATH)?;
+    serde_json::to_writer_pretty(file, materials)?;
+    Ok(())
+}
@@ -0,0 +1,510 @@
+use crate::bvh::Bvh;
+use crate::loader::{AMFLoader, LoadProgress, OBJLoader, PLYLoader};
+use crate::mesh_repair::{analyze, repair, save_stl_copy, MeshHealth, StlCopy};
+use crate::Errors;
+use gladius_shared::loader::*;
+use glam::{Mat4, Vec3};
+use itertools::*;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+
+///Extensions of the model files that can be loaded
+pub const MODEL_EXTENSIONS: [&str; 5] = ["stl", "3mf", "obj", "ply", "amf"];
+
+///Extensions the slicer reads itself, other models are converted to STL for it
+const SLICER_EXTENSIONS: [&str; 2] = ["stl", "3mf"];
+
+#[derive(Clone, Debug)]
+pub struct AABB {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub min_z: f32,
+    pub max_x: f32,
+    pub max_y: f32,
+    pub max_z: f32,
+}
+
+impl AABB {
+    pub fn from_points<'a>(points: impl IntoIterator<Item = &'a Vec3>) -> Self {
+        let (min_x, max_x, min_y, max_y, min_z, max_z) = points.into_iter().fold(
+            (
+                f32::INFINITY,
+                f32::NEG_INFINITY,
+                f32::INFINITY,
+                f32::NEG_INFINITY,
+                f32::INFINITY,
+                f32::NEG_INFINITY,
+            ),
+            |a, b| {
+                (
+                    a.0.min(b.x),
+                    a.1.max(b.x),
+                    a.2.min(b.y),
+                    a.3.max(b.y),
+                    a.4.min(b.z),
+                    a.5.max(b.z),
+                )
+            },
+        );
+
+        AABB {
+            min_x,
+            max_x,
+            min_y,
+            max_y,
+            min_z,
+            max_z,
+        }
+    }
+
+    ///Whether the box lies within a print area of the size starting at the origin
+    pub fn is_inside(&self, size: Vec3) -> bool {
+        self.min_x >= 0.0
+            && self.min_y >= 0.0
+            && self.min_z >= 0.0
+            && self.max_x <= size.x
+            && self.max_y <= size.y
+            && self.max_z <= size.z
+    }
+
+    pub fn intersect_with_ray(&self, ray_origin: Vec3, ray_dir: Vec3) -> bool {
+        //https://gamedev.stackexchange.com/questions/18436/most-efficient-aabb-vs-ray-collision-algorithms
+        // r.dir is unit direction vector of ray
+        // lb is the corner of AABB with minimal coordinates - left bottom, rt is maximal corner
+        // r.org is origin of ray
+        let t1 = (self.min_x - ray_origin.x) / ray_dir.x;
+        let t2 = (self.max_x - ray_origin.x) / ray_dir.x;
+        let t3 = (self.min_y - ray_origin.y) / ray_dir.y;
+        let t4 = (self.max_y - ray_origin.y) / ray_dir.y;
+        let t5 = (self.min_z - ray_origin.z) / ray_dir.z;
+        let t6 = (self.max_z - ray_origin.z) / ray_dir.z;
+
+        let tmin = t1.min(t2).max(t3.min(t4)).max(t5.min(t6));
+        let tmax = t1.max(t2).min(t3.max(t4)).min(t5.max(t6));
+
+        // if tmax < 0, ray (line) is intersecting AABB, but the whole AABB is behind us
+        if tmax < 0.0 {
+            return false;
+        }
+
+        // if tmin > tmax, ray doesn't intersect AABB
+        if tmin > tmax {
+            return false;
+        }
+        true
+    }
+}
+
+///Geometry of a model and where it is placed. Kept on the CPU so picking, thumbnails and
+///repair never have to read anything back from the renderer.
+#[derive(Clone, Debug)]
+pub struct Mesh {
+    pub name: String,
+    pub file_path: String,
+    vertices: Vec<Vec3>,
+    indices: Vec<u32>,
+    ///Normal of each triangle
+    normals: Vec<Vec3>,
+    ///Bounds in model space
+    bounds: AABB,
+    bvh: Bvh,
+    location: Vec3,
+    scale: Vec3,
+    ///Centers the model and rests it on the plate
+    default_offset: Vec3,
+    pub health: MeshHealth,
+    ///STL copy of the model written for the slicer, if it was repaired or the slicer can't read its file.
+    ///Shared by the copies of the mesh.
+    pub stl_copy: Option<Arc<StlCopy>>,
+    ///Bounds in print space
+    pub aabb: Option<AABB>,
+}
+
+impl Mesh {
+    ///Mesh placed at the origin, analysed for problems
+    pub fn new(name: String, file_path: String, vertices: Vec<Vec3>, indices: Vec<u32>) -> Self {
+        let bounds = AABB::from_points(&vertices);
+
+        let mut mesh = Mesh {
+            name,
+            file_path,
+            vertices: vec![],
+            indices: vec![],
+            normals: vec![],
+            default_offset: Vec3::new(
+                -(bounds.max_x + bounds.min_x) / 2.0,
+                -(bounds.max_y + bounds.min_y) / 2.0,
+                -bounds.min_z,
+            ),
+            bounds,
+            bvh: Bvh::default(),
+            location: Vec3::new(0.0, 0.0, 0.0),
+            scale: Vec3::new(1.0, 1.0, 1.0),
+            health: MeshHealth::default(),
+            stl_copy: None,
+            aabb: None,
+        };
+        mesh.set_geometry(vertices, indices);
+        mesh
+    }
+
+    fn set_geometry(&mut self, vertices: Vec<Vec3>, indices: Vec<u32>) {
+        self.normals = indices
+            .chunks_exact(3)
+            .map(|tri| {
+                let [a, b, c] = [tri[0], tri[1], tri[2]].map(|v| vertices[v as usize]);
+                (b - a).cross(c - a).normalize_or_zero()
+            })
+            .collect();
+        self.health = analyze(&vertices, &indices);
+        self.bounds = AABB::from_points(&vertices);
+        self.bvh = Bvh::new(&vertices, &indices);
+        self.vertices = vertices;
+        self.indices = indices;
+        self.invalidate_cache();
+    }
+
+    pub fn get_vertices(&self) -> &[Vec3] {
+        &self.vertices
+    }
+
+    pub fn get_indices(&self) -> &[u32] {
+        &self.indices
+    }
+
+    ///Hierarchy over the triangles in model space, for picking, cutting and collision checks
+    pub fn get_bvh(&self) -> &Bvh {
+        &self.bvh
+    }
+
+    ///Normals in model space, one per triangle. Kept up to date when the mesh is repaired.
+    pub fn get_normals(&self) -> &[Vec3] {
+        &self.normals
+    }
+
+    pub fn set_scale(&mut self, scale: Vec3) {
+        self.scale = scale;
+    }
+
+    pub fn set_location(&mut self, location: Vec3) {
+        self.location = location;
+    }
+
+    pub fn get_mut_location(&mut self) -> &mut Vec3 {
+        &mut self.location
+    }
+
+    pub fn get_location(&self) -> &Vec3 {
+        &self.location
+    }
+
+    pub fn get_mut_scale(&mut self) -> &mut Vec3 {
+        &mut self.scale
+    }
+
+    pub fn get_scale(&self) -> &Vec3 {
+        &self.scale
+    }
+
+    pub fn get_model_matrix(&self) -> Mat4 {
+        get_model_matrix(self.location, self.scale, self.default_offset)
+    }
+
+    ///File the slicer reads the model from, the repaired copy if there is one
+    pub fn get_slice_path(&self) -> &str {
+        self.stl_copy.as_ref().map_or(&self.file_path, |copy| copy.get_path())
+    }
+
+    ///Weld, unify the normals and fill the holes, saving the result for the slicer
+    pub fn repair(&mut self) -> Result<(), Errors> {
+        let (vertices, indices) = repair(&self.vertices, &self.indices);
+
+        self.stl_copy = Some(Arc::new(save_stl_copy(&self.name, &vertices, &indices)?));
+        self.set_geometry(vertices, indices);
+        Ok(())
+    }
+
+    pub fn invalidate_cache(&mut self) {
+        self.aabb = None;
+    }
+
+    ///Place the model space bounds in print space. Models are only moved and scaled so the corners are enough.
+    pub fn revalidate_cache(&mut self) {
+        let mat = self.get_model_matrix();
+        let b = &self.bounds;
+        let corners = iproduct!([b.min_x, b.max_x], [b.min_y, b.max_y], [b.min_z, b.max_z])
+            .map(|(x, y, z)| mat.transform_point3(Vec3::new(x, y, z)))
+            .collect_vec();
+
+        self.aabb = Some(AABB::from_points(&corners));
+    }
+
+    ///Triangles in print space
+    pub fn get_triangles(&self) -> Vec<[Vec3; 3]> {
+        let mat = self.get_model_matrix();
+
+        self.indices
+            .chunks_exact(3)
+            .map(|tri| [0, 1, 2].map(|i| mat.transform_point3(self.vertices[tri[i] as usize])))
+            .collect()
+    }
+
+    ///Nearest point the ray hits and how far along it. The ray is moved into model space rather
+    ///than moving every vertex out of it.
+    pub fn intersect_with_ray(&mut self, ray_origin: Vec3, ray_dir: Vec3) -> Option<(f32, Vec3)> {
+        if self.aabb.is_none() {
+            self.revalidate_cache();
+        }
+        if !self.aabb.as_ref()?.intersect_with_ray(ray_origin, ray_dir) {
+            return None;
+        }
+
+        let mat = self.get_model_matrix();
+        if mat.determinant() == 0.0 {
+            return None;
+        }
+        let inverse = mat.inverse();
+        //The transform is affine so distances along the ray are the same in both spaces
+        let model_origin = inverse.transform_point3(ray_origin);
+        let model_dir = inverse.transform_vector3(ray_dir);
+
+        self.bvh
+            .intersect_ray(model_origin, model_dir, |tri| {
+                let [v0, v1, v2] = [0, 1, 2].map(|i| self.vertices[self.indices[tri * 3 + i] as usize]);
+                intersect_triangle(model_origin, model_dir, v0, v1, v2)
+            })
+            .map(|t| (t, ray_origin + ray_dir * t))
+    }
+}
+
+///Distance along the ray to the triangle if it is hit in front of the origin
+fn intersect_triangle(ray_origin: Vec3, ray_dir: Vec3, v0: Vec3, v1: Vec3, v2: Vec3) -> Option<f32> {
+    let edge1 = v1 - v0;
+    let edge2 = v2 - v0;
+
+    let h = ray_dir.cross(edge2);
+    let a = edge1.dot(h);
+
+    if a > -0.0001 && a < 0.0001 {
+        None
+    } else {
+        let f = 1.0 / a;
+        let s = ray_origin - v0;
+        let u = f * s.dot(h);
+        if u < 0.0 || u > 1.0 {
+            None
+        } else {
+            let q = s.cross(edge1);
+            let v = f * ray_dir.dot(q);
+            if v < 0.0 || u + v > 1.0 {
+                None
+            } else {
+                let t = f * edge2.dot(q);
+                if t > f32::EPSILON
+                // ray intersection
+                {
+                    Some(t)
+                } else // This means that there is a line intersection but not a ray intersection.
+                {
+                    None
+                }
+            }
+        }
+    }
+}
+
+pub fn get_model_matrix(location: Vec3, scale: Vec3, default_offset: Vec3) -> Mat4 {
+    glam::Mat4::from_translation(location)
+        * glam::Mat4::from_scale(scale)
+        * glam::Mat4::from_translation(default_offset)
+}
+
+///Read every model in the file without touching the GPU
+pub fn load_meshes(filepath: &str) -> Result<Vec<Mesh>, Errors> {
+    load_meshes_with_progress(filepath, &Arc::default())
+}
+
+///Read every model in the file, counting the bytes read into the progress. STL and 3MF files are
+///read by the slicer's loaders, which only take a path, so those count nothing until they are done.
+pub fn load_meshes_with_progress(filepath: &str, progress: &Arc<LoadProgress>) -> Result<Vec<Mesh>, Errors> {
+    let model_path = Path::new(filepath);
+    let name = model_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .ok_or_else(|| Errors::ModelFileNameInvalid(filepath.to_string()))?;
+    let extension = model_path
+        .extension()
+        .and_then(OsStr::to_str)
+        .ok_or_else(|| Errors::ModelFileNameInvalid(filepath.to_string()))?;
+
+    let extension = extension.to_lowercase();
+    let progress = progress.clone();
+    let loader: Box<dyn Loader> = match extension.as_str() {
+        "stl" => Box::new(STLLoader {}),
+        "3mf" => Box::new(ThreeMFLoader {}),
+        "obj" => Box::new(OBJLoader { progress }),
+        "ply" => Box::new(PLYLoader { progress }),
+        "amf" => Box::new(AMFLoader { progress }),
+        _ => return Err(Errors::ModelFormatNotSupported(filepath.to_string())),
+    };
+
+    let convert = !SLICER_EXTENSIONS.contains(&extension.as_str());
+
+    loader
+        .load(filepath)
+        .map_err(|e| Errors::ModelFileNotLoadable(filepath.to_string(), e))?
+        .into_iter()
+        .map(|(vertices, triangles)| {
+            let vertices: Vec<Vec3> = vertices
+                .into_iter()
+                .map(|v| Vec3::new(v.x as f32, v.y as f32, v.z as f32))
+                .collect();
+
+            let indices: Vec<u32> = triangles
+                .into_iter()
+                .flat_map(|tri| tri.verts.into_iter())
+                .map(|u| u as u32)
+                .collect();
+
+            let mut mesh = Mesh::new(name.clone(), filepath.to_string(), vertices, indices);
+            if convert {
+                mesh.stl_copy = Some(Arc::new(save_stl_copy(&mesh.name, &mesh.vertices, &mesh.indices)?));
+            }
+            Ok(mesh)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    ///Corners of a box from the origin, numbered x + 2y + 4z
+    pub(crate) fn get_box_corners(size: Vec3) -> Vec<Vec3> {
+        (0..8)
+            .map(|i| Vec3::new((i & 1) as f32, ((i >> 1) & 1) as f32, (i >> 2) as f32) * size)
+            .collect()
+    }
+
+    ///Triangles of the box corners facing outwards
+    pub(crate) const BOX_INDICES: [u32; 36] = [
+        0, 2, 3, 0, 3, 1, //bottom
+        4, 5, 7, 4, 7, 6, //top
+        0, 1, 5, 0, 5, 4, //front
+        2, 7, 3, 2, 6, 7, //back
+        0, 4, 6, 0, 6, 2, //left
+        1, 3, 7, 1, 7, 5, //right
+    ];
+
+    pub(crate) fn get_box(size: Vec3) -> Mesh {
+        Mesh::new(String::from("box.stl"), String::from("box.stl"), get_box_corners(size), BOX_INDICES.to_vec())
+    }
+
+    ///A 10mm cube from the origin
+    fn get_cube() -> Mesh {
+        get_box(Vec3::splat(10.0))
+    }
+
+    fn get_extents(aabb: &AABB) -> [f32; 6] {
+        [aabb.min_x, aabb.max_x, aabb.min_y, aabb.max_y, aabb.min_z, aabb.max_z]
+    }
+
+    fn place(mesh: &mut Mesh) {
+        mesh.set_location(Vec3::new(50.0, 60.0, 0.0));
+        mesh.set_scale(Vec3::new(2.0, 1.0, 1.0));
+        mesh.revalidate_cache();
+    }
+
+    #[test]
+    fn bounds_are_kept_in_model_space() {
+        let mut mesh = get_cube();
+        assert_eq!(get_extents(&mesh.bounds), [0.0, 10.0, 0.0, 10.0, 0.0, 10.0]);
+        assert!(mesh.aabb.is_none());
+
+        //New meshes are centered on their location and rest on the plate
+        mesh.revalidate_cache();
+        assert_eq!(get_extents(mesh.aabb.as_ref().unwrap()), [-5.0, 5.0, -5.0, 5.0, 0.0, 10.0]);
+
+        place(&mut mesh);
+        assert_eq!(get_extents(&mesh.bounds), [0.0, 10.0, 0.0, 10.0, 0.0, 10.0]);
+    }
+
+    #[test]
+    fn revalidate_cache_follows_the_placement() {
+        let mut mesh = get_cube();
+        place(&mut mesh);
+        assert_eq!(get_extents(mesh.aabb.as_ref().unwrap()), [40.0, 60.0, 55.0, 65.0, 0.0, 10.0]);
+
+        mesh.get_mut_location().z = 5.0;
+        mesh.invalidate_cache();
+        assert!(mesh.aabb.is_none());
+        mesh.revalidate_cache();
+        assert_eq!(get_extents(mesh.aabb.as_ref().unwrap()), [40.0, 60.0, 55.0, 65.0, 5.0, 15.0]);
+    }
+
+    ///Every triangle of the cube faces away from its center along an axis
+    fn assert_normals_face_outwards(mesh: &Mesh) {
+        let center = Vec3::splat(5.0);
+        assert_eq!(mesh.get_normals().len(), mesh.get_indices().len() / 3);
+        for (tri, normal) in mesh.get_indices().chunks_exact(3).zip(mesh.get_normals()) {
+            let centroid = tri.iter().fold(Vec3::ZERO, |sum, v| sum + mesh.get_vertices()[*v as usize]) / 3.0;
+            assert!((normal.length() - 1.0).abs() < 0.001);
+            assert!(normal.dot(centroid - center) > 4.9);
+        }
+    }
+
+    #[test]
+    fn normals_are_computed_at_load_and_after_repair() {
+        let mesh = get_cube();
+        assert_normals_face_outwards(&mesh);
+        assert_eq!(mesh.get_normals()[0], Vec3::new(0.0, 0.0, -1.0));
+
+        let mut indices = BOX_INDICES.to_vec();
+        indices.swap(1, 2);
+        let mut mesh = Mesh::new(String::from("flipped.stl"), String::from("flipped.stl"), get_box_corners(Vec3::splat(10.0)), indices);
+        assert_eq!(mesh.get_normals()[0], Vec3::new(0.0, 0.0, 1.0));
+
+        mesh.repair().unwrap();
+        assert_normals_face_outwards(&mesh);
+    }
+
+    #[test]
+    fn bvh_covers_the_model_space_triangles() {
+        let mesh = get_cube();
+        let mut bottom = mesh.get_bvh().query_box(Vec3::new(2.0, 2.0, -1.0), Vec3::new(8.0, 8.0, 1.0));
+        bottom.retain(|tri| mesh.get_normals()[*tri] == Vec3::new(0.0, 0.0, -1.0));
+        bottom.sort_unstable();
+        assert_eq!(bottom, vec![0, 1]);
+    }
+
+    #[test]
+    fn rays_hit_the_placed_mesh() {
+        let mut mesh = get_cube();
+        place(&mut mesh);
+
+        let (distance, point) = mesh.intersect_with_ray(Vec3::new(58.0, 60.0, 100.0), Vec3::new(0.0, 0.0, -1.0)).unwrap();
+        assert!((distance - 90.0).abs() < 0.001);
+        assert!((point - Vec3::new(58.0, 60.0, 10.0)).length() < 0.001);
+
+        //The side the ray comes from is the one hit
+        let (distance, _) = mesh.intersect_with_ray(Vec3::new(0.0, 60.0, 5.0), Vec3::new(1.0, 0.0, 0.0)).unwrap();
+        assert!((distance - 40.0).abs() < 0.001);
+
+        //Inside the bounds of the model before it was scaled but outside now
+        assert!(mesh.intersect_with_ray(Vec3::new(62.0, 60.0, 100.0), Vec3::new(0.0, 0.0, -1.0)).is_none());
+        assert!(mesh.intersect_with_ray(Vec3::new(50.0, 60.0, 100.0), Vec3::new(0.0, 0.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn triangles_are_in_print_space() {
+        let mut mesh = get_cube();
+        place(&mut mesh);
+        let triangles = mesh.get_triangles();
+
+        assert_eq!(triangles.len(), 12);
+        assert_eq!(triangles[0], [Vec3::new(40.0, 55.0, 0.0), Vec3::new(40.0, 65.0, 0.0), Vec3::new(60.0, 65.0, 0.0)]);
+        let aabb = mesh.aabb.as_ref().unwrap();
+        assert_eq!(get_extents(&AABB::from_points(triangles.iter().flatten())), get_extents(aabb));
+    }
+}
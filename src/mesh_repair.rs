@@ -0,0 +1,590 @@
+use crate::export::sanitize_file_name;
+use crate::Errors;
+use glam::Vec3;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+///Vertices closer than this in mm are welded into one
+const WELD_TOLERANCE: f32 = 0.0001;
+
+///Triangles with less area than this in mm² have no surface to slice
+const DEGENERATE_AREA: f32 = 1e-10;
+
+///Grid cells a triangle may be listed in when looking for self-intersections
+const MAX_CELLS_PER_TRIANGLE: u64 = 64;
+
+///Model copies written so far, keeps the file names apart
+static COPY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+///Problems found in a mesh that can cause the slicer to fail or produce wrong layers
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MeshHealth {
+    ///Edges shared by more than two triangles
+    pub non_manifold_edges: usize,
+    ///Loops of edges with a triangle on only one side
+    pub holes: usize,
+    ///Triangles facing the other way to the rest of the surface
+    pub flipped_triangles: usize,
+    ///Triangles with no area
+    pub degenerate_triangles: usize,
+    ///Triangles with the same corners as another
+    pub duplicate_triangles: usize,
+    ///Pairs of triangles that cut through each other
+    pub self_intersections: usize,
+}
+
+impl MeshHealth {
+    pub fn is_healthy(&self) -> bool {
+        *self == MeshHealth::default()
+    }
+
+    ///Whether repair can fix anything. Non-manifold edges and self-intersections are left as they are.
+    pub fn is_repairable(&self) -> bool {
+        self.holes > 0 || self.flipped_triangles > 0 || self.degenerate_triangles > 0 || self.duplicate_triangles > 0
+    }
+
+    ///Each issue found with the key of its label and how often it occurs
+    pub fn get_issues(&self) -> Vec<(&'static str, usize)> {
+        [
+            ("non_manifold_edges", self.non_manifold_edges),
+            ("holes", self.holes),
+            ("flipped_triangles", self.flipped_triangles),
+            ("degenerate_triangles", self.degenerate_triangles),
+            ("duplicate_triangles", self.duplicate_triangles),
+            ("self_intersections", self.self_intersections),
+        ]
+        .into_iter()
+        .filter(|(_, count)| *count > 0)
+        .collect()
+    }
+}
+
+///Triangles of a mesh after welding, split by what is wrong with them
+struct Topology {
+    positions: Vec<Vec3>,
+    ///Triangles with area that aren't a copy of an earlier one
+    triangles: Vec<[u32; 3]>,
+    degenerate: usize,
+    duplicate: usize,
+}
+
+impl Topology {
+    fn new(positions: &[Vec3], indices: &[u32]) -> Self {
+        let (positions, remap) = weld(positions);
+
+        let mut seen = HashSet::new();
+        let mut triangles = vec![];
+        let mut degenerate = 0;
+        let mut duplicate = 0;
+
+        for tri in indices.chunks_exact(3) {
+            let tri = [remap[tri[0] as usize], remap[tri[1] as usize], remap[tri[2] as usize]];
+            let [a, b, c] = tri.map(|v| positions[v as usize]);
+
+            if tri[0] == tri[1] || tri[1] == tri[2] || tri[2] == tri[0] || (b - a).cross(c - a).length() / 2.0 < DEGENERATE_AREA {
+                degenerate += 1;
+                continue;
+            }
+
+            let mut key = tri;
+            key.sort_unstable();
+            if !seen.insert(key) {
+                duplicate += 1;
+                continue;
+            }
+            triangles.push(tri);
+        }
+
+        Topology {
+            positions,
+            triangles,
+            degenerate,
+            duplicate,
+        }
+    }
+
+    ///Triangles using each edge, with whether they run along it from the lower to the higher vertex
+    fn get_edges(&self) -> HashMap<(u32, u32), Vec<(usize, bool)>> {
+        let mut edges: HashMap<(u32, u32), Vec<(usize, bool)>> = HashMap::new();
+        for (index, tri) in self.triangles.iter().enumerate() {
+            for (a, b) in get_triangle_edges(tri) {
+                edges.entry((a.min(b), a.max(b))).or_default().push((index, a < b));
+            }
+        }
+        edges
+    }
+
+    ///Which triangles need flipping so every connected surface faces the same way, outwards
+    fn get_flips(&self, edges: &HashMap<(u32, u32), Vec<(usize, bool)>>) -> Vec<bool> {
+        let mut flips: Vec<Option<bool>> = vec![None; self.triangles.len()];
+
+        for seed in 0..self.triangles.len() {
+            if flips[seed].is_some() {
+                continue;
+            }
+            flips[seed] = Some(false);
+            let mut component = vec![seed];
+            let mut queue = VecDeque::from([seed]);
+
+            while let Some(index) = queue.pop_front() {
+                let flipped = flips[index].unwrap_or(false);
+                for (a, b) in get_triangle_edges(&self.triangles[index]) {
+                    //Only walk across edges between exactly two triangles
+                    let uses = match edges.get(&(a.min(b), a.max(b))) {
+                        Some(uses) if uses.len() == 2 => uses,
+                        _ => continue,
+                    };
+                    let forward = (a < b) != flipped;
+                    for &(other, other_forward) in uses {
+                        //Neighbours facing the same way run along the shared edge in opposite directions
+                        if other != index && flips[other].is_none() {
+                            flips[other] = Some(other_forward == forward);
+                            component.push(other);
+                            queue.push_back(other);
+                        }
+                    }
+                }
+            }
+
+            //A surface enclosing negative volume is facing inwards
+            let volume: f32 = component
+                .iter()
+                .map(|&index| {
+                    let [a, b, c] = self.triangles[index].map(|v| self.positions[v as usize]);
+                    let volume = a.dot(b.cross(c));
+                    if flips[index] == Some(true) {
+                        -volume
+                    } else {
+                        volume
+                    }
+                })
+                .sum();
+            if volume < 0.0 {
+                for &index in &component {
+                    flips[index] = flips[index].map(|flip| !flip);
+                }
+            }
+        }
+
+        flips.into_iter().map(|flip| flip.unwrap_or(false)).collect()
+    }
+}
+
+///Look for problems in the mesh that would trouble the slicer
+pub fn analyze(positions: &[Vec3], indices: &[u32]) -> MeshHealth {
+    let topology = Topology::new(positions, indices);
+    let edges = topology.get_edges();
+
+    let non_manifold_edges = edges.values().filter(|uses| uses.len() > 2).count();
+    let boundary: Vec<(u32, u32)> = edges
+        .iter()
+        .filter(|(_, uses)| uses.len() == 1)
+        .map(|(edge, _)| *edge)
+        .collect();
+
+    MeshHealth {
+        non_manifold_edges,
+        holes: count_loops(&boundary),
+        flipped_triangles: topology.get_flips(&edges).into_iter().filter(|flip| *flip).count(),
+        degenerate_triangles: topology.degenerate,
+        duplicate_triangles: topology.duplicate,
+        self_intersections: count_self_intersections(&topology.positions, &topology.triangles),
+    }
+}
+
+///Weld the vertices, drop degenerate and duplicate triangles, turn every triangle to face
+///outwards and fill the holes. Returns the new positions and indices.
+pub fn repair(positions: &[Vec3], indices: &[u32]) -> (Vec<Vec3>, Vec<u32>) {
+    let mut topology = Topology::new(positions, indices);
+
+    let flips = topology.get_flips(&topology.get_edges());
+    for (tri, flip) in topology.triangles.iter_mut().zip(flips) {
+        if flip {
+            tri.swap(1, 2);
+        }
+    }
+
+    //Boundary edges reversed go around the holes facing the same way as the surface
+    let mut next: HashMap<u32, Vec<u32>> = HashMap::new();
+    for ((low, high), uses) in topology.get_edges().into_iter().filter(|(_, uses)| uses.len() == 1) {
+        let (a, b) = if uses[0].1 { (low, high) } else { (high, low) };
+        next.entry(b).or_default().push(a);
+    }
+
+    let mut starts: Vec<u32> = next.keys().copied().collect();
+    starts.sort_unstable();
+    let mut filling = vec![];
+
+    for start in starts {
+        while let Some(mut current) = next.get_mut(&start).and_then(|ends| ends.pop()) {
+            let mut hole = vec![start];
+            while current != start {
+                hole.push(current);
+                match next.get_mut(&current).and_then(|ends| ends.pop()) {
+                    Some(end) => current = end,
+                    None => {
+                        //The edges don't close up so there is nothing to fill
+                        hole.clear();
+                        break;
+                    }
+                }
+            }
+            if hole.len() >= 3 {
+                filling.push(hole);
+            }
+        }
+    }
+
+    let existing: HashSet<[u32; 3]> = topology
+        .triangles
+        .iter()
+        .map(|tri| {
+            let mut key = *tri;
+            key.sort_unstable();
+            key
+        })
+        .collect();
+
+    for hole in filling {
+        if hole.len() == 3 {
+            //A lone triangle has itself as its hole, filling it would only add a copy facing the other way
+            let mut key = [hole[0], hole[1], hole[2]];
+            key.sort_unstable();
+            if !existing.contains(&key) {
+                topology.triangles.push([hole[0], hole[1], hole[2]]);
+            }
+            continue;
+        }
+
+        //Fan out from the middle of the hole
+        let center = hole.iter().map(|v| &topology.positions[*v as usize]).sum::<Vec3>() / hole.len() as f32;
+        let center_index = topology.positions.len() as u32;
+        topology.positions.push(center);
+        for i in 0..hole.len() {
+            topology.triangles.push([hole[i], hole[(i + 1) % hole.len()], center_index]);
+        }
+    }
+
+    let indices = topology.triangles.into_iter().flatten().collect();
+    (topology.positions, indices)
+}
+
+///Temporary STL written for the slicer, the file is deleted once no mesh uses it
+#[derive(Debug)]
+pub struct StlCopy {
+    path: String,
+}
+
+impl StlCopy {
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Drop for StlCopy {
+    fn drop(&mut self) {
+        //Anything left behind is in the temporary directory anyway
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+///Write the mesh to a temporary STL file for the slicer
+pub fn save_stl_copy(name: &str, positions: &[Vec3], indices: &[u32]) -> Result<StlCopy, Errors> {
+    let stem = Path::new(name)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let dir = std::env::temp_dir().join("gladius_models");
+    let path = dir.join(format!(
+        "{}-{}-{}.stl",
+        sanitize_file_name(&stem),
+        std::process::id(),
+        COPY_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let path_string = path.to_string_lossy().to_string();
+
+    std::fs::create_dir_all(&dir)
+        .and_then(|_| write_stl(&path, positions, indices))
+        .map_err(|_| Errors::ModelCopyNotWritable(path_string.clone()))?;
+    Ok(StlCopy { path: path_string })
+}
+
+///Save the mesh as binary STL, which every slicer reads
+pub fn write_stl(path: &Path, positions: &[Vec3], indices: &[u32]) -> std::io::Result<()> {
+    let count = indices.len() / 3;
+    let mut data = Vec::with_capacity(84 + count * 50);
+    data.extend_from_slice(&[0; 80]);
+    data.extend_from_slice(&(count as u32).to_le_bytes());
+
+    for tri in indices.chunks_exact(3) {
+        let [a, b, c] = [tri[0], tri[1], tri[2]].map(|v| positions[v as usize]);
+        let normal = (b - a).cross(c - a).normalize_or_zero();
+        for value in [normal, a, b, c].iter().flat_map(|v| v.to_array()) {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&[0; 2]);
+    }
+
+    std::fs::write(path, data)
+}
+
+fn weld(positions: &[Vec3]) -> (Vec<Vec3>, Vec<u32>) {
+    let mut welded = vec![];
+    let mut cells: HashMap<[i64; 3], u32> = HashMap::new();
+
+    let remap = positions
+        .iter()
+        .map(|p| {
+            let key = [p.x, p.y, p.z].map(|value| (value / WELD_TOLERANCE).round() as i64);
+            *cells.entry(key).or_insert_with(|| {
+                welded.push(*p);
+                (welded.len() - 1) as u32
+            })
+        })
+        .collect();
+
+    (welded, remap)
+}
+
+fn get_triangle_edges(tri: &[u32; 3]) -> impl Iterator<Item = (u32, u32)> {
+    [(tri[0], tri[1]), (tri[1], tri[2]), (tri[2], tri[0])].into_iter()
+}
+
+///Number of separate loops the edges form
+fn count_loops(edges: &[(u32, u32)]) -> usize {
+    let mut parents: HashMap<u32, u32> = HashMap::new();
+
+    fn find(parents: &mut HashMap<u32, u32>, vertex: u32) -> u32 {
+        let mut root = vertex;
+        while let Some(&parent) = parents.get(&root).filter(|parent| **parent != root) {
+            root = parent;
+        }
+        parents.insert(vertex, root);
+        root
+    }
+
+    for &(a, b) in edges {
+        let root_a = find(&mut parents, a);
+        let root_b = find(&mut parents, b);
+        parents.insert(root_a, root_b);
+    }
+
+    let vertices: Vec<u32> = parents.keys().copied().collect();
+    vertices
+        .into_iter()
+        .map(|vertex| find(&mut parents, vertex))
+        .collect::<HashSet<u32>>()
+        .len()
+}
+
+///Pairs of triangles without a shared corner that cut through each other.
+///Triangles are bucketed in a grid so only nearby pairs are tested.
+fn count_self_intersections(positions: &[Vec3], triangles: &[[u32; 3]]) -> usize {
+    if triangles.is_empty() {
+        return 0;
+    }
+
+    let bounds: Vec<(Vec3, Vec3)> = triangles
+        .iter()
+        .map(|tri| {
+            let [a, b, c] = tri.map(|v| positions[v as usize]);
+            (a.min(b).min(c), a.max(b).max(c))
+        })
+        .collect();
+    let cell_size = (bounds.iter().map(|(min, max)| (*max - *min).max_element()).sum::<f32>() / bounds.len() as f32).max(WELD_TOLERANCE);
+    let get_cell = |p: Vec3| [p.x, p.y, p.z].map(|value| (value / cell_size).floor() as i64);
+
+    let intersect = |first: usize, second: usize| {
+        let (min_a, max_a) = bounds[first];
+        let (min_b, max_b) = bounds[second];
+        let (tri_a, tri_b) = (triangles[first], triangles[second]);
+        min_a.max(min_b).cmple(max_a.min(max_b)).all()
+            && !tri_a.iter().any(|v| tri_b.contains(v))
+            && triangles_intersect(tri_a.map(|v| positions[v as usize]), tri_b.map(|v| positions[v as usize]))
+    };
+
+    //Triangles much bigger than the rest would fill a huge number of cells, they are tested against everything instead
+    let mut large = vec![false; triangles.len()];
+    let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    for (index, (min, max)) in bounds.iter().enumerate() {
+        let (min_cell, max_cell) = (get_cell(*min), get_cell(*max));
+        let cells = (0..3)
+            .map(|axis| (max_cell[axis] - min_cell[axis] + 1) as u64)
+            .fold(1u64, |cells, span| cells.saturating_mul(span));
+        if cells > MAX_CELLS_PER_TRIANGLE {
+            large[index] = true;
+            continue;
+        }
+        for x in min_cell[0]..=max_cell[0] {
+            for y in min_cell[1]..=max_cell[1] {
+                for z in min_cell[2]..=max_cell[2] {
+                    grid.entry([x, y, z]).or_default().push(index);
+                }
+            }
+        }
+    }
+
+    let mut count = 0;
+    for (cell, indices) in &grid {
+        for (i, &first) in indices.iter().enumerate() {
+            for &second in &indices[i + 1..] {
+                //Pairs sharing several cells are only tested in the first of them
+                let overlap_min = bounds[first].0.max(bounds[second].0);
+                if get_cell(overlap_min) == *cell && intersect(first, second) {
+                    count += 1;
+                }
+            }
+        }
+    }
+
+    for first in (0..triangles.len()).filter(|index| large[*index]) {
+        //Pairs of large triangles are counted once, from the first of them
+        count += (0..triangles.len())
+            .filter(|second| *second != first && (!large[*second] || *second > first))
+            .filter(|second| intersect(first, *second))
+            .count();
+    }
+    count
+}
+
+fn triangles_intersect(a: [Vec3; 3], b: [Vec3; 3]) -> bool {
+    (0..3).any(|i| segment_intersects_triangle(a[i], a[(i + 1) % 3], b)) || (0..3).any(|i| segment_intersects_triangle(b[i], b[(i + 1) % 3], a))
+}
+
+///Möller–Trumbore limited to the segment. Segments in the plane of the triangle don't count.
+fn segment_intersects_triangle(start: Vec3, end: Vec3, tri: [Vec3; 3]) -> bool {
+    let dir = end - start;
+    let edge1 = tri[1] - tri[0];
+    let edge2 = tri[2] - tri[0];
+
+    let h = dir.cross(edge2);
+    let a = edge1.dot(h);
+    if a.abs() < f32::EPSILON * edge1.length() * edge2.length() * dir.length() {
+        return false;
+    }
+
+    let f = 1.0 / a;
+    let s = start - tri[0];
+    let u = f * s.dot(h);
+    if !(0.0..=1.0).contains(&u) {
+        return false;
+    }
+    let q = s.cross(edge1);
+    let v = f * dir.dot(q);
+    if v < 0.0 || u + v > 1.0 {
+        return false;
+    }
+    let t = f * edge2.dot(q);
+    t > f32::EPSILON && t < 1.0 - f32::EPSILON
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::tests::{get_box_corners, BOX_INDICES};
+
+    ///Corners of a 10mm cube
+    fn get_cube_positions() -> Vec<Vec3> {
+        get_box_corners(Vec3::splat(10.0))
+    }
+
+    fn get_volume(positions: &[Vec3], indices: &[u32]) -> f32 {
+        indices
+            .chunks_exact(3)
+            .map(|tri| {
+                let [a, b, c] = [tri[0], tri[1], tri[2]].map(|v| positions[v as usize]);
+                a.dot(b.cross(c)) / 6.0
+            })
+            .sum()
+    }
+
+    ///Repair and check the cube comes back whole and facing outwards
+    fn assert_repaired(positions: &[Vec3], indices: &[u32]) {
+        let (positions, indices) = repair(positions, indices);
+        assert!(analyze(&positions, &indices).is_healthy());
+        assert!((get_volume(&positions, &indices) - 1000.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn closed_cube_is_healthy() {
+        let health = analyze(&get_cube_positions(), &BOX_INDICES);
+        assert!(health.is_healthy());
+        assert!(!health.is_repairable());
+    }
+
+    #[test]
+    fn missing_triangle_is_a_hole() {
+        let positions = get_cube_positions();
+        let health = analyze(&positions, &BOX_INDICES[3..]);
+        assert_eq!(health.holes, 1);
+        assert!(health.is_repairable());
+        assert_repaired(&positions, &BOX_INDICES[3..]);
+
+        //Without a whole side the hole has four edges
+        let health = analyze(&positions, &BOX_INDICES[6..]);
+        assert_eq!(health.holes, 1);
+        assert_repaired(&positions, &BOX_INDICES[6..]);
+    }
+
+    #[test]
+    fn flipped_triangle_is_turned_back() {
+        let positions = get_cube_positions();
+        let mut indices = BOX_INDICES.to_vec();
+        indices.swap(13, 14);
+
+        let health = analyze(&positions, &indices);
+        assert_eq!(health.flipped_triangles, 1);
+        assert_eq!(health.holes, 0);
+        assert_repaired(&positions, &indices);
+    }
+
+    #[test]
+    fn duplicate_triangle_is_dropped() {
+        //The copy starts from another corner and its vertices are separate but in the same place
+        let mut positions = get_cube_positions();
+        positions.push(positions[0]);
+        let mut indices = BOX_INDICES.to_vec();
+        indices.extend([2, 3, 8]);
+
+        let health = analyze(&positions, &indices);
+        assert_eq!(health.duplicate_triangles, 1);
+        assert_eq!(health.non_manifold_edges, 0);
+        let (_, repaired) = repair(&positions, &indices);
+        assert_eq!(repaired.len(), BOX_INDICES.len());
+        assert_repaired(&positions, &indices);
+    }
+
+    #[test]
+    fn self_intersections_match_testing_every_pair() {
+        //A floor of small triangles with a large one standing up through it
+        let mut positions = vec![];
+        let mut triangles = vec![];
+        for x in 0..10 {
+            for y in 0..10 {
+                let start = positions.len() as u32;
+                positions.extend([Vec3::new(x as f32, y as f32, 0.0), Vec3::new(x as f32 + 0.9, y as f32, 0.0), Vec3::new(x as f32, y as f32 + 0.9, 0.0)]);
+                triangles.push([start, start + 1, start + 2]);
+            }
+        }
+        let start = positions.len() as u32;
+        positions.extend([Vec3::new(-1.0, 0.2, -1.0), Vec3::new(11.0, 0.2, -1.0), Vec3::new(5.0, 0.2, 20.0)]);
+        triangles.push([start, start + 1, start + 2]);
+        //A second large one crossing the first
+        positions.extend([Vec3::new(5.0, -5.0, 5.0), Vec3::new(5.0, 5.0, 5.0), Vec3::new(5.0, 0.0, -5.0)]);
+        triangles.push([start + 3, start + 4, start + 5]);
+
+        let mut expected = 0;
+        for first in 0..triangles.len() {
+            for second in first + 1..triangles.len() {
+                let [a, b] = [triangles[first], triangles[second]].map(|tri| tri.map(|v| positions[v as usize]));
+                if triangles_intersect(a, b) {
+                    expected += 1;
+                }
+            }
+        }
+
+        assert!(expected > 2);
+        assert_eq!(count_self_intersections(&positions, &triangles), expected);
+    }
+}
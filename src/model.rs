@@ -1,15 +1,41 @@
+use std::collections::BTreeMap;
 use std::io::{BufRead, BufReader};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use gladius_shared::messages::Message;
 use gladius_shared::types::{CalculatedValues};
 use glam::{Mat4, Vec2, Vec3};
 use itertools::Itertools;
 use crate::Errors;
-use crate::object::{load, DisplayVertex, Object};
+use crate::mesh::{Mesh, MODEL_EXTENSIONS};
+use crate::object::{DisplayVertex, Object};
+use crate::model_load::ModelLoad;
+use crate::toolpath::{find_move, split_layers, FeatureSpeeds, LayerMoves, Toolpath};
+use crate::color::{ColorMap, ColorScheme};
+use crate::gcode_parser::GCodeStatistics;
+use crate::gcode_text::GCodeLines;
+use crate::materials::{load_materials, save_materials, MaterialProfile};
+use crate::plate::{Plate, PlateObject, PLATE_EXTENSION};
+use crate::export::{sanitize_file_name, ExportJob, ExportPreset};
+use crate::post_process::{fill_template, get_date, load_post_processors, save_post_processors, LayerAction, LayerInsertion, PostProcessor, TemplateValues};
+use crate::print_host::{JobCommand, PrintHost, PrinterStatus, UploadProgress};
+use crate::preferences::{load_preferences, save_preferences, Preferences};
+use crate::serial::SerialConnection;
+use crate::slice_result::SliceResult;
+use crate::statistics::LayerStatistics;
+use crate::thumbnail::{encode_png, get_bounds, render_gpu, render_software, Thumbnail, ThumbnailMesh};
 
 use native_dialog::FileDialog;
 
+///Extensions of G-code files that can be opened in the viewer
+const GCODE_EXTENSIONS: [&str; 3] = ["gcode", "gco", "g"];
+
+///How often the print host is asked for the printer state while monitoring
+const MONITOR_INTERVAL: Duration = Duration::from_secs(2);
+
 pub struct GUIData{
     objects: Vec<Object>,
     print_area_size: Vec3,
@@ -17,12 +43,34 @@ pub struct GUIData{
     dragging: bool,
     camera: Camera,
     settings_path: String,
-    calc_vals: Arc<RwLock<Option<CalculatedValues>>>,
-    gcode: Arc<RwLock<Option<String>>>,
-    commands: Arc<RwLock<Option<Vec<gladius_shared::types::Command>>>>,
+    result: Arc<RwLock<SliceResult>>,
+    comparison: Option<SliceResult>,
+    ///Color map of the kept result, built once per color scheme
+    comparison_color_map: Option<ColorMap>,
+    materials: Vec<MaterialProfile>,
+    selected_material: usize,
+    post_processors: Vec<PostProcessor>,
+    layer_insertions: Vec<LayerInsertion>,
+    preferences: Preferences,
+    toolpath: Option<Toolpath>,
+    color_scheme: ColorScheme,
     error: Arc<RwLock<Option<Errors>>>,
     command_running: Arc<RwLock<bool>>,
     command_state: Arc<RwLock<String>>,
+    upload_progress: Arc<RwLock<Option<UploadProgress>>>,
+    ///Message shown after the last upload finished
+    upload_message: Arc<RwLock<Option<String>>>,
+    ///Name the current result was last uploaded under, the monitor only maps jobs of this file onto it
+    uploaded_file_name: Arc<RwLock<Option<String>>>,
+    ///Last poll of the monitored printer, an error if the host couldn't be reached
+    printer_status: Arc<RwLock<Option<Result<PrinterStatus, String>>>>,
+    ///Changed to stop the polling thread, which exits once it no longer matches the id it started with
+    monitor_id: Arc<RwLock<usize>>,
+    monitoring: bool,
+    serial: Option<SerialConnection>,
+    model_load: Option<ModelLoad>,
+    ///Models of the plate being opened, placed once every file is read
+    plate_meshes: Vec<Mesh>,
     refresh: Arc<RwLock<bool>>
 
 }
@@ -40,12 +88,28 @@ impl GUIData{
             dragging: false,
             camera: Camera::new(screen_dimensions, Vec3::new(center_pos.0 ,center_pos.1 , 0.0)),
             settings_path: String::new(),
-            calc_vals: Arc::new(RwLock::new(None)),
-            gcode:Arc::new(RwLock::new(None)),
-            commands: Arc::new(RwLock::new(None)),
+            result: Arc::new(RwLock::new(SliceResult::default())),
+            comparison: None,
+            comparison_color_map: None,
+            materials: load_materials(),
+            selected_material: 0,
+            post_processors: load_post_processors(),
+            layer_insertions: vec![],
+            preferences: load_preferences(),
+            toolpath: None,
+            color_scheme: ColorScheme::FeatureType,
             error: Arc::new(RwLock::new(None)),
             command_running: Arc::new(RwLock::new(false)),
             command_state: Arc::new(RwLock::new(String::new())),
+            upload_progress: Arc::new(RwLock::new(None)),
+            upload_message: Arc::new(RwLock::new(None)),
+            uploaded_file_name: Arc::new(RwLock::new(None)),
+            printer_status: Arc::new(RwLock::new(None)),
+            monitor_id: Arc::new(RwLock::new(0)),
+            monitoring: false,
+            serial: None,
+            model_load: None,
+            plate_meshes: vec![],
             refresh: Arc::new(RwLock::new(false)),
         }
     }
@@ -62,9 +126,7 @@ impl GUIData{
 
     pub fn get_command_line_args(&self) -> Vec<String> {
         self.objects.iter()
-            .map(|obj|{
-               format!("{{\"Raw\":[\"{}\",{:?}]}} ", obj.file_path.replace('\\', "\\\\"),obj.get_model_matrix().transpose().to_cols_array_2d())
-            })
+            .map(|obj| get_object_arg(obj.mesh.get_slice_path(), obj.mesh.get_model_matrix()))
             .collect()
     }
 
@@ -73,9 +135,7 @@ impl GUIData{
             .iter_mut()
             .enumerate()
             .for_each(|(index,obj)| {
-                let in_build_area = obj.aabb.as_ref().map(|aabb| {
-                    !(aabb.min_x < 0.0 || aabb.min_y < 0.0 || aabb.min_z < 0.0 || aabb.max_x >  self.print_area_size.x || aabb.max_y >  self.print_area_size.y || aabb.max_z > self.print_area_size.z)
-                }).unwrap_or(false);
+                let in_build_area = obj.mesh.aabb.as_ref().map(|aabb| aabb.is_inside(self.print_area_size)).unwrap_or(false);
 
                 let this_selected = self.closest_object_point.map(|(i,_,_)| i== index).unwrap_or(false);
 
@@ -96,28 +156,34 @@ impl GUIData{
         self.settings_path = path;
     }
 
+    pub fn get_print_area_size(&self) -> Vec3{
+        self.print_area_size
+    }
+
     pub fn get_settings_path(&mut self ) -> &String{
         &self.settings_path
     }
 
 
     pub fn can_slice(&self) -> bool{
-        !self.objects.is_empty() && !self.settings_path.is_empty() && ! *self.command_running.read().unwrap()
+        !self.objects.is_empty() && !self.settings_path.is_empty() && ! *self.command_running.read().unwrap() && self.model_load.is_none()
     }
 
     pub fn start_slice(&mut self) {
-        *self.calc_vals.write().unwrap() = None;
-        *self.gcode.write().unwrap() = None;
+        let args = self.get_command_line_args();
+        self.start_slice_with_args(args);
+    }
+
+    ///Slice the models given as slicer arguments, see get_object_arg
+    pub fn start_slice_with_args(&mut self, args: Vec<String>) {
+        *self.result.write().unwrap() = SliceResult::default();
         *self.error.write().unwrap() = None;
-        *self.commands.write().unwrap() = None;
+        *self.uploaded_file_name.write().unwrap() = None;
+        self.toolpath = None;
         *self.command_running.write().unwrap() = true;
 
 
-        let args = self.get_command_line_args() ;
-
-        let calc_vals_clone = self.calc_vals.clone();
-        let commands_clone = self.commands.clone();
-        let gcode_clone = self.gcode.clone();
+        let result_clone = self.result.clone();
         let error_clone = self.error.clone();
         let command_running_clone = self.command_running.clone();
         let command_state_clone = self.command_state.clone();
@@ -126,6 +192,9 @@ impl GUIData{
 
 
         std::thread::spawn(move ||{
+           let feature_speeds = FeatureSpeeds::from_settings_file(&settings_path_clone);
+           //Built up here and swapped in whole so the viewers never wait on the indexing
+           let mut result = SliceResult::default();
 
            let mut command = if cfg!(target_os = "linux") {
                Command::new("./slicer/gladius_slicer")
@@ -164,14 +233,18 @@ impl GUIData{
                    while let Ok::<Message,_>( msg) = bincode::deserialize_from(&mut *stdout) {
                        match msg {
                            Message::CalculatedValues(cv) => {
-                               *calc_vals_clone.write().unwrap() = Some(cv);
+                               result.calc_vals = Some(cv);
+                               *result_clone.write().unwrap() = result.clone();
                            }
                            Message::Commands(cmds) => {
-
-                               *commands_clone.write().unwrap() = Some(cmds);
+                               //Index the layers once here so the viewers never walk the full command list
+                               let layers = split_layers(&cmds, &feature_speeds);
+                               result.set_commands(cmds, layers);
+                               *result_clone.write().unwrap() = result.clone();
                            }
                            Message::GCode(str) => {
-                               *gcode_clone.write().unwrap() = Some(str);
+                               result.set_gcode(str);
+                               *result_clone.write().unwrap() = result.clone();
                            }
                            Message::Error(err) => {
                                *error_clone.write().unwrap() = Some(Errors::SlicerError(err));
@@ -219,10 +292,10 @@ impl GUIData{
                 .enumerate()
                 .filter_map(|(en, obj)| {
                     obj.hovered = false;
-                    obj.intersect_with_ray(self.camera.location, cam_dir).map(|p| (en, obj, p))
+                    obj.mesh.intersect_with_ray(self.camera.location, cam_dir).map(|p| (en, obj, p))
                 })
                 .min_by(|(_, _, (ta, _)), (_, _, (tb, _))| ta.partial_cmp(tb).unwrap())
-                .map(|(index, obj, point)| (index, point.1,*obj.get_location()));
+                .map(|(index, obj, point)| (index, point.1,*obj.mesh.get_location()));
 
             if let Some((index, _,_)) = self.closest_object_point {
                 self.objects[index].hovered = true;
@@ -245,10 +318,11 @@ impl GUIData{
             let x_diff =  x_intercept - intersect_point.x;
             let y_diff =  y_intercept - intersect_point.y;
 
-            self.objects[index].get_mut_location().x = translation.x + x_diff;
-            self.objects[index].get_mut_location().y = translation.y + y_diff;
+            let mesh = &mut self.objects[index].mesh;
+            mesh.get_mut_location().x = translation.x + x_diff;
+            mesh.get_mut_location().y = translation.y + y_diff;
 
-            self.objects[index].revalidate_cache();
+            mesh.revalidate_cache();
             //*gcode.write().unwrap() = None;
             //*calc_vals.write().unwrap() = None;
 
@@ -303,38 +377,245 @@ impl GUIData{
     }
 
     pub fn load_settings_file(&mut self){
-        let path = FileDialog::new()
+        let path = match FileDialog::new()
             .add_filter("Supported settings Types", &["json"])
             .show_open_single_file()
-            .unwrap();
+        {
+            Ok(path) => path,
+            Err(_) => {
+                *self.error.write().unwrap() = Some(Errors::FileDialogFailed);
+                return;
+            }
+        };
 
-        let path = match path {
-            Some(path) => path,
+        let path = match path.map(|path| path.into_os_string().into_string()) {
+            Some(Ok(path)) => path,
+            Some(Err(_)) => {
+                *self.error.write().unwrap() = Some(Errors::FileDialogFailed);
+                return;
+            }
             None => return,
         };
 
-        self.set_settings_path(path.into_os_string().into_string().unwrap());
+        self.set_settings_path(path);
     }
-    pub fn load_model(&mut self, display: &glium::Display){
-        let paths = FileDialog::new()
-           .add_filter("Supported Model Types", &["stl", "3mf"])
+    pub fn load_model(&mut self){
+        let paths = match FileDialog::new()
+           .add_filter("Supported Model Types", &MODEL_EXTENSIONS)
            .show_open_multiple_file()
-           .unwrap();
+        {
+            Ok(paths) => paths,
+            Err(_) => {
+                *self.error.write().unwrap() = Some(Errors::FileDialogFailed);
+                return;
+            }
+        };
+
+        *self.error.write().unwrap() = None;
+        self.load_model_files(&paths);
+    }
+
+    ///Parse the files in the background, adding them to the running load if there is one
+    fn load_model_files(&mut self, paths: &[PathBuf]){
+        if paths.is_empty() {
+            return;
+        }
+        let added = self.model_load.as_ref()
+            .map(|load| load.add_files(paths))
+            .unwrap_or(false);
+
+        if !added {
+            let load = ModelLoad::new(vec![], self.preferences.repair_models, self.refresh.clone());
+            load.add_files(paths);
+            self.model_load = Some(load);
+        }
+    }
+
+    pub fn get_model_load(&self) -> Option<&ModelLoad>{
+        self.model_load.as_ref()
+    }
+
+    ///Upload the models parsed since the last frame and finish the load once every file is read
+    pub fn update_model_load(&mut self, display: &glium::Display){
+        let load = match self.model_load.as_ref(){
+            Some(load) => load,
+            None => return,
+        };
+
+        for mesh in load.take_meshes(){
+            if load.placements.iter().any(|placement| placement.file_path == mesh.file_path){
+                self.plate_meshes.push(mesh);
+                continue;
+            }
+            match Object::from_mesh(mesh, display){
+                Ok(obj) => self.objects.push(obj),
+                Err(e) => load.add_error(e),
+            }
+        }
+
+        if load.is_finished(){
+            self.finish_model_load(display);
+        }
+    }
+
+    ///Stop loading, keeping the models already read
+    pub fn cancel_model_load(&mut self, display: &glium::Display){
+        if let Some(load) = self.model_load.as_ref(){
+            load.cancel();
+        }
+        self.finish_model_load(display);
+    }
+
+    fn finish_model_load(&mut self, display: &glium::Display){
+        let load = match self.model_load.take(){
+            Some(load) => load,
+            None => return,
+        };
+
+        //Files can hold several models so match them up by file and name, copies share the model
+        let loaded = std::mem::take(&mut self.plate_meshes);
+        for placement in &load.placements{
+            let mut mesh = match loaded.iter().find(|mesh| mesh.file_path == placement.file_path && mesh.name == placement.name){
+                Some(mesh) => mesh.clone(),
+                None => continue,
+            };
+            mesh.set_location(Vec3::from(placement.location));
+            mesh.set_scale(Vec3::from(placement.scale));
+            mesh.revalidate_cache();
+            match Object::from_mesh(mesh, display){
+                Ok(obj) => self.objects.push(obj),
+                Err(e) => load.add_error(e),
+            }
+        }
+
+        self.report_model_errors(load.take_errors(), load.get_attempted_count());
+    }
+
+    ///Fix the problems found in the model so the slicer gets a clean mesh
+    pub fn repair_object(&mut self, index: usize, display: &glium::Display){
+        if let Some(obj) = self.objects.get_mut(index){
+            if let Err(e) = obj.repair(display){
+                *self.error.write().unwrap() = Some(e);
+            }
+        }
+    }
+
+    ///Open each file by its type, as models, settings, G-code or a plate. Returns whether G-code was opened.
+    pub fn open_files(&mut self, paths: &[PathBuf]) -> bool{
+        let mut models = vec![];
+        let mut failed = vec![];
+        let mut gcode_opened = false;
 
         for path in paths {
+            let extension = path.extension()
+                .and_then(OsStr::to_str)
+                .map(str::to_lowercase)
+                .unwrap_or_default();
+
+            match extension.as_str() {
+                extension if MODEL_EXTENSIONS.contains(&extension) => models.push(path.clone()),
+                extension if GCODE_EXTENSIONS.contains(&extension) && self.is_command_running() => {
+                    failed.push((path.clone(), Errors::GCodeNotOpenableWhileSlicing(path.to_string_lossy().to_string())));
+                }
+                extension if GCODE_EXTENSIONS.contains(&extension) => {
+                    self.load_gcode_path(path);
+                    gcode_opened = true;
+                }
+                "json" => self.set_settings_path(path.to_string_lossy().to_string()),
+                PLATE_EXTENSION => self.load_plate_file(&path.to_string_lossy()),
+                _ => failed.push((path.clone(), Errors::FileTypeNotSupported(path.to_string_lossy().to_string()))),
+            }
+        }
 
-           let model_path = path.into_os_string().into_string().unwrap();
+        self.load_model_files(&models);
+        match self.model_load.as_ref() {
+            //Report unsupported files along with the models once they are read
+            Some(load) if !models.is_empty() => {
+                for (path, e) in failed {
+                    load.add_failed_file(path, e);
+                }
+            }
+            _ => {
+                let total = failed.len();
+                self.report_model_errors(failed.into_iter().map(|(_, e)| e).collect(), total);
+            }
+        }
+        gcode_opened
+    }
 
-           match load(&model_path,display)
-           {
-               Ok(objs) => { self.extend_objects(objs) }
-               Err(e) => { *self.error.write().unwrap() = Some(Errors::SlicerError(e)) }
-           }
+    ///Show a failure on its own or a report of every file when several were loaded
+    fn report_model_errors(&self, mut failed: Vec<Errors>, total: usize){
+        let error = match failed.len(){
+            0 => return,
+            1 if total == 1 => failed.remove(0),
+            _ => Errors::ModelsNotLoadable(failed, total),
+        };
+        *self.error.write().unwrap() = Some(error);
+    }
+
+    ///Open a G-code file and show it in the viewers in place of the sliced result
+    pub fn load_gcode_file(&mut self) -> bool{
+        if self.is_command_running(){
+            return false;
         }
+
+        let path = match FileDialog::new()
+            .add_filter("G-code", &GCODE_EXTENSIONS)
+            .show_open_single_file()
+        {
+            Ok(path) => path,
+            Err(_) => {
+                *self.error.write().unwrap() = Some(Errors::FileDialogFailed);
+                return false;
+            }
+        };
+
+        match path {
+            Some(path) => {
+                self.load_gcode_path(&path);
+                true
+            }
+            None => false,
+        }
+    }
+
+    ///Read and parse the G-code in the background, the viewers show it once it is ready
+    fn load_gcode_path(&mut self, path: &Path){
+        *self.result.write().unwrap() = SliceResult::default();
+        *self.error.write().unwrap() = None;
+        *self.uploaded_file_name.write().unwrap() = None;
+        self.toolpath = None;
+        *self.command_running.write().unwrap() = true;
+
+        let path = path.to_path_buf();
+        let material = self.get_selected_material().clone();
+        let feature_speeds = FeatureSpeeds::from_settings_file(&self.settings_path);
+        let result_clone = self.result.clone();
+        let error_clone = self.error.clone();
+        let command_running_clone = self.command_running.clone();
+        let refresh_clone = self.refresh.clone();
+
+        std::thread::spawn(move ||{
+            match std::fs::read_to_string(&path) {
+                Ok(gcode) => {
+                    *result_clone.write().unwrap() = SliceResult::from_gcode(gcode, &material, &feature_speeds);
+                }
+                Err(_) => {
+                    *error_clone.write().unwrap() = Some(Errors::GCodeFileNotReadable(path.to_string_lossy().to_string()));
+                }
+            }
+
+            *command_running_clone.write().unwrap() = false;
+            *refresh_clone.write().unwrap() = true;
+        });
+    }
+
+    pub fn get_gcode_statistics(&self) -> Option<GCodeStatistics>{
+        self.result.read().unwrap().gcode_statistics
     }
 
     pub fn get_calculated_values(&self) -> Option<CalculatedValues>{
-        self.calc_vals.read().unwrap().clone()
+        self.result.read().unwrap().calc_vals.clone()
     }
 
     pub fn get_command_state(&self) -> String{
@@ -342,15 +623,643 @@ impl GUIData{
     }
 
     pub fn get_errors(&self) -> Vec<Errors>{
-        self.error.read().unwrap().clone().into_iter().collect_vec()
+        match self.error.read().unwrap().clone(){
+            //List every file that failed after the summary
+            Some(Errors::ModelsNotLoadable(failed, total)) => {
+                std::iter::once(Errors::ModelsNotLoadable(failed.clone(), total)).chain(failed).collect_vec()
+            }
+            error => error.into_iter().collect_vec(),
+        }
+    }
+
+    pub fn get_gcode_lines(&self) -> Option<Arc<GCodeLines>>{
+        self.result.read().unwrap().gcode_lines.clone()
+    }
+
+    pub fn get_gcode(&self) -> Option<Arc<String>>{
+        self.result.read().unwrap().gcode.clone()
+    }
+
+    pub fn get_commands(&self) ->  Option<Arc<Vec<gladius_shared::types::Command>>>{
+        self.result.read().unwrap().commands.clone()
+    }
+
+    pub fn get_layers(&self) -> Option<Arc<Vec<LayerMoves>>>{
+        self.result.read().unwrap().layers.clone()
+    }
+
+    pub fn get_layer_statistics(&self) -> Option<Arc<LayerStatistics>>{
+        self.result.read().unwrap().layer_statistics.clone()
+    }
+
+    pub fn get_extrusion_volume(&self) -> Option<f64>{
+        self.result.read().unwrap().get_extrusion_volume()
+    }
+
+    pub fn get_object_volumes(&self) -> Option<BTreeMap<Option<usize>, f64>>{
+        self.result.read().unwrap().object_volumes.clone()
+    }
+
+    pub fn get_print_time(&self) -> Option<f64>{
+        self.result.read().unwrap().get_print_time()
+    }
+
+    pub fn get_plastic_weight(&self) -> Option<f64>{
+        self.result.read().unwrap().get_plastic_weight()
     }
 
-    pub fn get_gcode(&self) -> Option<String>{
-        self.gcode.read().unwrap().clone()
+    ///Keep the current result so the next slice can be compared against it
+    pub fn keep_for_comparison(&mut self){
+        self.comparison = Some(self.result.read().unwrap().clone());
+        self.comparison_color_map = None;
     }
 
-    pub fn get_commands(&self) ->  Option<Vec<gladius_shared::types::Command>>{
-        self.commands.read().unwrap().clone()
+    pub fn clear_comparison(&mut self){
+        self.comparison = None;
+        self.comparison_color_map = None;
+    }
+
+    ///Color map covering both the kept and the current result, so a color means the same value in both
+    pub fn get_comparison_color_map(&mut self, display: &glium::Display) -> Option<ColorMap>{
+        let current = self.get_toolpath(display)?.color_map.clone();
+        let kept_layers = match self.comparison.as_ref()?.layers.clone(){
+            Some(layers) => layers,
+            None => return Some(current),
+        };
+
+        if self.comparison_color_map.as_ref().map_or(true, |map| map.get_scheme() != self.color_scheme){
+            self.comparison_color_map = Some(ColorMap::new(self.color_scheme, &kept_layers));
+        }
+        self.comparison_color_map.as_ref().map(|kept| current.merge(kept))
+    }
+
+    pub fn get_comparison(&self) -> Option<&SliceResult>{
+        self.comparison.as_ref()
+    }
+
+    pub fn get_materials(&self) -> &Vec<MaterialProfile>{
+        &self.materials
+    }
+
+    pub fn get_mut_materials(&mut self) -> &mut Vec<MaterialProfile>{
+        &mut self.materials
+    }
+
+    pub fn get_selected_material(&self) -> &MaterialProfile{
+        &self.materials[self.selected_material]
+    }
+
+    pub fn get_selected_material_index(&self) -> usize{
+        self.selected_material
+    }
+
+    pub fn set_selected_material(&mut self, index: usize){
+        self.selected_material = index.min(self.materials.len() - 1);
+    }
+
+    pub fn add_material(&mut self){
+        self.materials.push(MaterialProfile::default());
+    }
+
+    pub fn remove_material(&mut self, index: usize){
+        //Keep at least one profile so one is always selected
+        if self.materials.len() > 1{
+            self.materials.remove(index);
+            self.selected_material = self.selected_material.min(self.materials.len() - 1);
+        }
+    }
+
+    pub fn save_materials(&mut self){
+        if save_materials(&self.materials).is_err(){
+            *self.error.write().unwrap() = Some(Errors::MaterialsFileNotWritable);
+        }
+    }
+
+    ///Remove the filament used by the current print from the selected spool
+    pub fn consume_filament(&mut self, weight: f64){
+        let material = &mut self.materials[self.selected_material];
+        material.spool_remaining = (material.spool_remaining - weight).max(0.0);
+        self.save_materials();
+    }
+
+    pub fn get_mut_post_processors(&mut self) -> &mut Vec<PostProcessor>{
+        &mut self.post_processors
+    }
+
+    pub fn save_post_processors(&mut self){
+        if save_post_processors(&self.post_processors).is_err(){
+            *self.error.write().unwrap() = Some(Errors::PostProcessorsFileNotWritable);
+        }
+    }
+
+    ///Values of the current result used to fill in templates
+    pub fn get_template_values(&self) -> TemplateValues{
+        let result = self.result.read().unwrap();
+        let material = self.get_selected_material();
+        let usage = result.get_extrusion_volume().map(|volume| material.get_usage(volume));
+
+        TemplateValues{
+            print_time: result.get_print_time().unwrap_or(0.0),
+            filament_weight: usage.map(|usage| usage.weight).or_else(|| result.get_plastic_weight()).unwrap_or(0.0),
+            filament_length: usage.map(|usage| usage.length / 1000.0).unwrap_or(0.0),
+            layer_count: result.layers.as_ref().map(|layers| layers.len()).unwrap_or(0),
+            material: material.name.clone(),
+            object: self.objects.iter()
+                .map(|obj| Path::new(&obj.mesh.name).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_else(|| obj.mesh.name.clone()))
+                .unique()
+                .join("_"),
+            layer_height: result.layers.as_ref().map(|layers| match layers.as_slice(){
+                [first, second, ..] => (second.z - first.z) as f64,
+                [first] => first.z as f64,
+                [] => 0.0,
+            }).unwrap_or(0.0),
+            date: get_date(),
+        }
+    }
+
+    pub fn get_export_presets(&self) -> &Vec<ExportPreset>{
+        &self.preferences.export_presets
+    }
+
+    pub fn get_export_preset(&self) -> &ExportPreset{
+        &self.preferences.export_presets[self.preferences.selected_export_preset]
+    }
+
+    pub fn get_selected_export_preset_index(&self) -> usize{
+        self.preferences.selected_export_preset
+    }
+
+    pub fn set_selected_export_preset(&mut self, index: usize){
+        self.preferences.selected_export_preset = index.min(self.preferences.export_presets.len() - 1);
+    }
+
+    ///Default name for the exported file from the template of the selected preset
+    pub fn get_export_file_name(&self) -> String{
+        let preset = self.get_export_preset();
+        let name = sanitize_file_name(&fill_template(&preset.filename_template, &self.get_template_values()));
+        let name = if name.is_empty() { String::from("print") } else { name };
+        format!("{}.{}", name, preset.format.get_extension())
+    }
+
+    pub fn get_last_export_dir(&self) -> Option<&String>{
+        self.preferences.last_export_dir.as_ref()
+    }
+
+    ///Remember the directory of an export for the next one
+    pub fn set_last_export_path(&mut self, path: &Path){
+        let dir = path.parent().map(|dir| dir.to_string_lossy().to_string());
+        if dir.is_some() && dir != self.preferences.last_export_dir{
+            self.preferences.last_export_dir = dir;
+            self.save_preferences();
+        }
+    }
+
+    ///Copy of everything needed to export the current G-code, None if there is none.
+    ///Thumbnails are rendered here, in software without a display.
+    pub fn get_export_job(&self, display: Option<&glium::Display>) -> Option<ExportJob>{
+        let gcode = self.get_gcode()?;
+        let thumbnails = if self.preferences.embed_thumbnails { self.render_thumbnails(display) } else { vec![] };
+        Some(self.new_export_job(gcode, thumbnails))
+    }
+
+    fn new_export_job(&self, gcode: Arc<String>, thumbnails: Vec<Thumbnail>) -> ExportJob{
+        ExportJob{
+            gcode,
+            layer_insertions: self.layer_insertions.clone(),
+            post_processors: self.post_processors.clone(),
+            values: self.get_template_values(),
+            format: self.get_export_preset().format,
+            thumbnails,
+        }
+    }
+
+    ///G-code with the layer insertions, thumbnails and post-processing pipeline applied, encoded with
+    ///the selected export preset. None if there is no G-code or a step failed.
+    ///Thumbnails are rendered in software without a display.
+    pub fn get_exported_gcode(&self, display: Option<&glium::Display>) -> Option<Vec<u8>>{
+        match self.get_export_job(display)?.run(){
+            Ok(bytes) => Some(bytes),
+            Err(e) => {
+                *self.error.write().unwrap() = Some(e);
+                None
+            }
+        }
+    }
+
+    ///Export the G-code to the file in the background
+    ///Ask where to save the G-code, starting in the directory of the last export
+    pub fn export_gcode_file(&mut self, display: &glium::Display){
+        let file_name = self.get_export_file_name();
+        let extensions = [self.get_export_preset().format.get_filter_extension()];
+
+        let mut dialog = FileDialog::new()
+            .set_filename(&file_name)
+            .add_filter("gcode", &extensions);
+        if let Some(dir) = self.get_last_export_dir() {
+            dialog = dialog.set_location(dir);
+        }
+
+        match dialog.show_save_single_file() {
+            Ok(Some(path)) => self.export_to_file(path, Some(display)),
+            Ok(None) => {}
+            Err(_) => *self.error.write().unwrap() = Some(Errors::FileDialogFailed),
+        }
+    }
+
+    pub fn export_to_file(&mut self, path: PathBuf, display: Option<&glium::Display>){
+        let job = match self.get_export_job(display){
+            Some(job) => job,
+            None => return,
+        };
+        self.set_last_export_path(&path);
+
+        let error_clone = self.error.clone();
+        let refresh_clone = self.refresh.clone();
+
+        std::thread::spawn(move ||{
+            let written = job.run().and_then(|bytes| {
+                std::fs::write(&path, bytes).map_err(|_| Errors::GCodeFileNotWritable(path.to_string_lossy().to_string()))
+            });
+            if let Err(e) = written{
+                *error_clone.write().unwrap() = Some(e);
+            }
+            *refresh_clone.write().unwrap() = true;
+        });
+    }
+
+    ///Render a preview of the plate at every configured size
+    pub fn render_thumbnails(&self, display: Option<&glium::Display>) -> Vec<Thumbnail>{
+        let meshes: Vec<ThumbnailMesh> = self.objects.iter()
+            .map(|obj| ThumbnailMesh{ triangles: obj.mesh.get_triangles(), color: obj.color })
+            .collect();
+
+        let bounds = match get_bounds(&meshes){
+            Some(bounds) => bounds,
+            None => return vec![],
+        };
+
+        self.preferences.thumbnail_sizes.iter()
+            .filter(|[width, height]| *width > 0 && *height > 0)
+            .filter_map(|[width, height]| {
+                //Fall back to the software renderer if the GPU can't render offscreen
+                let pixels = display
+                    .and_then(|display| render_gpu(display, &self.objects, bounds, *width, *height))
+                    .unwrap_or_else(|| render_software(&meshes, bounds, *width, *height));
+                encode_png(&pixels, *width, *height).map(|png| Thumbnail{ width: *width, height: *height, png })
+            })
+            .collect()
+    }
+
+    pub fn get_print_hosts(&self) -> &Vec<PrintHost>{
+        &self.preferences.print_hosts
+    }
+
+    pub fn get_print_host(&self) -> Option<&PrintHost>{
+        self.preferences.print_hosts.get(self.preferences.selected_print_host)
+    }
+
+    pub fn get_selected_print_host_index(&self) -> usize{
+        self.preferences.selected_print_host
+    }
+
+    pub fn set_selected_print_host(&mut self, index: usize){
+        let index = index.min(self.preferences.print_hosts.len().saturating_sub(1));
+        let changed = index != self.preferences.selected_print_host;
+        self.preferences.selected_print_host = index;
+        if changed && self.monitoring{
+            self.stop_monitoring();
+            self.start_monitoring();
+        }
+    }
+
+    pub fn is_monitoring(&self) -> bool{
+        self.monitoring
+    }
+
+    ///Poll the selected print host in the background until monitoring is stopped
+    pub fn start_monitoring(&mut self){
+        if self.monitoring{
+            return;
+        }
+        let host = match self.get_print_host(){
+            Some(host) => host.clone(),
+            None => return,
+        };
+        self.monitoring = true;
+
+        let id = *self.monitor_id.read().unwrap();
+        let id_clone = self.monitor_id.clone();
+        let status_clone = self.printer_status.clone();
+        let refresh_clone = self.refresh.clone();
+
+        std::thread::spawn(move ||{
+            while *id_clone.read().unwrap() == id{
+                let status = host.get_status();
+                //Monitoring may have stopped while waiting on the host
+                if *id_clone.read().unwrap() != id{
+                    break;
+                }
+                *status_clone.write().unwrap() = Some(status);
+                *refresh_clone.write().unwrap() = true;
+                std::thread::sleep(MONITOR_INTERVAL);
+            }
+        });
+    }
+
+    pub fn stop_monitoring(&mut self){
+        self.monitoring = false;
+        *self.monitor_id.write().unwrap() += 1;
+        *self.printer_status.write().unwrap() = None;
+    }
+
+    pub fn get_printer_status(&self) -> Option<Result<PrinterStatus, String>>{
+        self.printer_status.read().unwrap().clone()
+    }
+
+    pub fn send_printer_command(&self, command: JobCommand){
+        let host = match self.get_print_host(){
+            Some(host) => host.clone(),
+            None => return,
+        };
+
+        let error_clone = self.error.clone();
+        let refresh_clone = self.refresh.clone();
+
+        std::thread::spawn(move ||{
+            if let Err(e) = host.send_command(command){
+                *error_clone.write().unwrap() = Some(Errors::PrinterCommandFailed(e));
+                *refresh_clone.write().unwrap() = true;
+            }
+        });
+    }
+
+    ///Layer of the sliced G-code the monitored printer is on and the nozzle position if it is known.
+    ///Uses the layer the host reports, else the height of the nozzle, else how far into the file the printer is.
+    pub fn get_printer_position(&self) -> Option<(usize, Option<Vec2>)>{
+        let status = match self.get_printer_status(){
+            Some(Ok(status)) => status,
+            _ => return None,
+        };
+        //Hosts may report the file with its folder
+        let job_file = status.file_name.as_ref()?.rsplit('/').next()?;
+        if self.uploaded_file_name.read().unwrap().as_deref() != Some(job_file){
+            return None;
+        }
+        let layers = self.get_layers()?;
+        if layers.is_empty(){
+            return None;
+        }
+        let nozzle = status.position.map(|[x, y, _]| Vec2::new(x, y));
+
+        if let Some(layer) = status.current_layer{
+            return Some((layer.min(layers.len() - 1), nozzle));
+        }
+
+        if let Some([_, _, z]) = status.position{
+            let layer = layers.partition_point(|layer| layer.z <= z + 0.001).saturating_sub(1);
+            return Some((layer, nozzle));
+        }
+
+        //The uploaded file has extra lines so the offset is only mapped proportionally
+        let fraction = status.file_position
+            .filter(|(_, size)| *size > 0)
+            .map(|(position, size)| position as f64 / size as f64)
+            .or_else(|| status.progress.map(|progress| progress as f64))?;
+        let gcode = self.get_gcode()?;
+        let lines = self.get_gcode_lines()?;
+        let line = lines.get_offset_line((fraction.clamp(0.0, 1.0) * gcode.len() as f64) as usize);
+        let (layer, move_index) = find_move(&layers, lines.get_line_command(line)?)?;
+        let nozzle = layers[layer].moves.get(move_index).map(|m| m.end);
+        Some((layer, nozzle))
+    }
+
+    pub fn get_upload_progress(&self) -> Option<UploadProgress>{
+        *self.upload_progress.read().unwrap()
+    }
+
+    pub fn get_upload_message(&self) -> Option<String>{
+        self.upload_message.read().unwrap().clone()
+    }
+
+    ///Export the G-code and upload it to the selected print host in the background
+    pub fn send_to_printer(&mut self, display: Option<&glium::Display>, start_print: bool){
+        if self.upload_progress.read().unwrap().is_some(){
+            return;
+        }
+        let host = match self.get_print_host(){
+            Some(host) => host.clone(),
+            None => return,
+        };
+        let job = match self.get_export_job(display){
+            Some(job) => job,
+            None => return,
+        };
+        let file_name = self.get_export_file_name();
+
+        *self.error.write().unwrap() = None;
+        *self.upload_message.write().unwrap() = None;
+        //Marked as uploading before the thread starts so a second click can't send the file twice
+        *self.upload_progress.write().unwrap() = Some(UploadProgress::default());
+
+        let error_clone = self.error.clone();
+        let progress_clone = self.upload_progress.clone();
+        let message_clone = self.upload_message.clone();
+        let uploaded_clone = self.uploaded_file_name.clone();
+        let refresh_clone = self.refresh.clone();
+
+        std::thread::spawn(move ||{
+            let uploaded = job.run().and_then(|data| {
+                host.upload(&file_name, data, start_print, progress_clone.clone()).map_err(Errors::UploadFailed)
+            });
+            *progress_clone.write().unwrap() = None;
+            match uploaded{
+                Ok(()) => {
+                    *message_clone.write().unwrap() = Some(format!("{} -> {}", file_name, host.name));
+                    *uploaded_clone.write().unwrap() = Some(file_name);
+                }
+                Err(e) => *error_clone.write().unwrap() = Some(e),
+            }
+            *refresh_clone.write().unwrap() = true;
+        });
+    }
+
+    pub fn get_serial(&self) -> Option<&SerialConnection>{
+        self.serial.as_ref()
+    }
+
+    ///Open the serial port from the preferences, closing any open connection first
+    pub fn connect_serial(&mut self){
+        self.serial = None;
+        let port = match self.preferences.serial_port.clone(){
+            Some(port) => port,
+            None => return,
+        };
+        match SerialConnection::connect(&port, self.preferences.baud_rate){
+            Ok(serial) => {
+                self.serial = Some(serial);
+                //Remember the port for next time
+                self.save_preferences();
+            }
+            Err(e) => *self.error.write().unwrap() = Some(Errors::SerialPortNotOpenable(format!("{}: {}", port, e))),
+        }
+    }
+
+    pub fn disconnect_serial(&mut self){
+        self.serial = None;
+    }
+
+    ///Apply the layer insertions and post-processing in the background, then stream the G-code
+    pub fn print_over_serial(&self){
+        let (sender, gcode) = match (self.serial.as_ref(), self.get_gcode()){
+            (Some(serial), Some(gcode)) => (serial.get_sender(), gcode),
+            _ => return,
+        };
+        //Thumbnails are only for screens showing files so they aren't sent
+        let job = self.new_export_job(gcode, vec![]);
+        let error_clone = self.error.clone();
+
+        std::thread::spawn(move ||{
+            match job.process(){
+                Ok(gcode) => sender.stream(&gcode),
+                Err(e) => *error_clone.write().unwrap() = Some(e),
+            }
+        });
+    }
+
+    pub fn get_mut_preferences(&mut self) -> &mut Preferences{
+        &mut self.preferences
+    }
+
+    pub fn save_preferences(&mut self){
+        if save_preferences(&self.preferences).is_err(){
+            *self.error.write().unwrap() = Some(Errors::PreferencesFileNotWritable);
+        }
+    }
+
+    pub fn get_layer_insertions(&self) -> &Vec<LayerInsertion>{
+        &self.layer_insertions
+    }
+
+    pub fn set_layer_insertions(&mut self, insertions: Vec<LayerInsertion>){
+        self.layer_insertions = insertions;
+    }
+
+    pub fn add_layer_insertion(&mut self, z: f64, action: LayerAction){
+        self.layer_insertions.push(LayerInsertion{ z, action });
+    }
+
+    pub fn remove_layer_insertions_at(&mut self, z: f64){
+        self.layer_insertions.retain(|insertion| !insertion.is_at_height(z));
+    }
+
+    pub fn get_plate(&self) -> Plate{
+        Plate{
+            settings_path: self.settings_path.clone(),
+            objects: self.objects.iter()
+                .map(|obj| PlateObject{
+                    file_path: obj.mesh.file_path.clone(),
+                    name: obj.mesh.name.clone(),
+                    location: obj.mesh.get_location().to_array(),
+                    scale: obj.mesh.get_scale().to_array(),
+                })
+                .collect(),
+            layer_insertions: self.layer_insertions.clone(),
+        }
+    }
+
+    pub fn save_plate(&mut self){
+        let path = match FileDialog::new()
+            .add_filter("Plate", &[PLATE_EXTENSION])
+            .show_save_single_file()
+        {
+            Ok(path) => path,
+            Err(_) => {
+                *self.error.write().unwrap() = Some(Errors::FileDialogFailed);
+                return;
+            }
+        };
+
+        if let Some(path) = path {
+            if let Err(e) = self.get_plate().save(&path.to_string_lossy()){
+                *self.error.write().unwrap() = Some(e);
+            }
+        }
+    }
+
+    pub fn load_plate(&mut self){
+        let path = match FileDialog::new()
+            .add_filter("Plate", &[PLATE_EXTENSION])
+            .show_open_single_file()
+        {
+            Ok(path) => path,
+            Err(_) => {
+                *self.error.write().unwrap() = Some(Errors::FileDialogFailed);
+                return;
+            }
+        };
+
+        if let Some(path) = path {
+            self.load_plate_file(&path.to_string_lossy());
+        }
+    }
+
+    ///Replace the plate with the one saved in the file, reloading every model from disk in the background
+    pub fn load_plate_file(&mut self, path: &str){
+        let plate = match Plate::load(path){
+            Ok(plate) => plate,
+            Err(e) => {
+                *self.error.write().unwrap() = Some(e);
+                return;
+            }
+        };
+
+        if let Some(load) = self.model_load.take(){
+            load.cancel();
+        }
+        self.plate_meshes.clear();
+        self.objects.clear();
+        self.closest_object_point = None;
+        self.dragging = false;
+
+        let file_paths = plate.objects.iter().map(|obj| PathBuf::from(&obj.file_path)).unique().collect_vec();
+        let load = ModelLoad::new(plate.objects, self.preferences.repair_models, self.refresh.clone());
+        load.add_files(&file_paths);
+        self.model_load = Some(load);
+
+        self.settings_path = plate.settings_path;
+        self.layer_insertions = plate.layer_insertions;
+    }
+
+    pub fn get_color_scheme(&self) -> ColorScheme{
+        self.color_scheme
+    }
+
+    ///The toolpath is recolored the next time it is drawn
+    pub fn set_color_scheme(&mut self, scheme: ColorScheme){
+        self.color_scheme = scheme;
+    }
+
+    ///Get the 3D toolpath, uploading the geometry built by the slice on first use
+    pub fn get_toolpath(&mut self, display: &glium::Display) -> Option<&Toolpath>{
+        let (layers, geometry) = {
+            let result = self.result.read().unwrap();
+            (result.layers.clone(), result.toolpath_layers.clone())
+        };
+
+        if let (Some(layers), Some(geometry)) = (layers, geometry){
+            match self.toolpath.as_mut(){
+                None => {
+                    let color_map = ColorMap::new(self.color_scheme, &layers);
+                    self.toolpath = Some(Toolpath::new(display, &geometry, &layers, color_map));
+                }
+                Some(toolpath) if toolpath.color_map.get_scheme() != self.color_scheme => {
+                    toolpath.recolor(display, &layers, ColorMap::new(self.color_scheme, &layers));
+                }
+                Some(_) => {}
+            }
+        }
+
+        self.toolpath.as_ref()
     }
 
 }
@@ -423,3 +1332,8 @@ impl  Camera{
     }
 
 }
+
+///Argument passing a model file and its placement to the slicer
+pub fn get_object_arg(file_path: &str, model_matrix: Mat4) -> String{
+    format!("{{\"Raw\":[\"{}\",{:?}]}} ", file_path.replace('\\', "\\\\"), model_matrix.transpose().to_cols_array_2d())
+}
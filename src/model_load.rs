@@ -0,0 +1,265 @@
+use crate::loader::LoadProgress;
+use crate::mesh::{load_meshes_with_progress, Mesh};
+use crate::plate::PlateObject;
+use crate::Errors;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+///Where a file is in the background load
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileLoadState {
+    Waiting,
+    Parsing,
+    ///Number of models read from the file
+    Loaded(usize),
+    Failed,
+    Cancelled,
+}
+
+impl FileLoadState {
+    pub fn is_done(&self) -> bool {
+        !matches!(self, FileLoadState::Waiting | FileLoadState::Parsing)
+    }
+}
+
+#[derive(Debug)]
+pub struct FileLoad {
+    pub path: PathBuf,
+    ///Size on disk, used to weigh the progress of each file
+    pub size: u64,
+    pub state: FileLoadState,
+}
+
+///State shared with the parsing thread
+#[derive(Default)]
+pub struct LoadQueue {
+    pub files: Vec<FileLoad>,
+    ///Parsed models waiting to be uploaded to the GPU on the main thread
+    meshes: Vec<Mesh>,
+    errors: Vec<Errors>,
+    running: bool,
+    cancelled: bool,
+    ///Bytes read of the file being parsed, counted without taking the lock
+    progress: Arc<LoadProgress>,
+}
+
+impl LoadQueue {
+    ///Fraction of the bytes queued that have been read
+    pub fn get_fraction(&self) -> f32 {
+        let total: u64 = self.files.iter().map(|file| file.size).sum();
+        let done: u64 = self
+            .files
+            .iter()
+            .map(|file| match file.state {
+                FileLoadState::Waiting => 0,
+                FileLoadState::Parsing => self.progress.get_bytes_read().min(file.size),
+                _ => file.size,
+            })
+            .sum();
+
+        if total == 0 {
+            let finished = self.files.iter().filter(|file| file.state.is_done()).count();
+            finished as f32 / self.files.len().max(1) as f32
+        } else {
+            done as f32 / total as f32
+        }
+    }
+}
+
+///Models being parsed in the background. Parsing happens on its own thread and the
+///meshes are handed back to be uploaded on the main thread, which owns the GL context.
+pub struct ModelLoad {
+    queue: Arc<RwLock<LoadQueue>>,
+    refresh: Arc<RwLock<bool>>,
+    ///Repair models with problems as they are read
+    repair: bool,
+    ///Placements of the plate being opened, its models are held back until every file is read
+    pub placements: Vec<PlateObject>,
+}
+
+impl ModelLoad {
+    pub fn new(placements: Vec<PlateObject>, repair: bool, refresh: Arc<RwLock<bool>>) -> Self {
+        ModelLoad {
+            queue: Arc::new(RwLock::new(LoadQueue::default())),
+            refresh,
+            repair,
+            placements,
+        }
+    }
+
+    ///Queue the files, starting the parsing thread if it isn't running. Returns false once the load was cancelled.
+    pub fn add_files(&self, paths: &[PathBuf]) -> bool {
+        let mut queue = self.queue.write().unwrap();
+        if queue.cancelled {
+            return false;
+        }
+
+        queue.files.extend(paths.iter().map(|path| FileLoad {
+            path: path.clone(),
+            size: std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or(0),
+            state: FileLoadState::Waiting,
+        }));
+
+        if !queue.running {
+            queue.running = true;
+            let queue_clone = self.queue.clone();
+            let refresh_clone = self.refresh.clone();
+            let repair = self.repair;
+            std::thread::spawn(move || parse_files(queue_clone, repair, refresh_clone));
+        }
+        true
+    }
+
+    ///List a file that couldn't be queued so it's reported with the rest
+    pub fn add_failed_file(&self, path: PathBuf, error: Errors) {
+        let mut queue = self.queue.write().unwrap();
+        queue.files.push(FileLoad {
+            path,
+            size: 0,
+            state: FileLoadState::Failed,
+        });
+        queue.errors.push(error);
+    }
+
+    pub fn add_error(&self, error: Errors) {
+        self.queue.write().unwrap().errors.push(error);
+    }
+
+    ///Stop parsing, dropping anything not yet uploaded. OBJ, PLY and AMF files stop part way
+    ///through, STL and 3MF files once they are read.
+    pub fn cancel(&self) {
+        let mut queue = self.queue.write().unwrap();
+        queue.cancelled = true;
+        queue.progress.cancel();
+        queue.meshes.clear();
+        for file in queue.files.iter_mut().filter(|file| file.state == FileLoadState::Waiting) {
+            file.state = FileLoadState::Cancelled;
+        }
+    }
+
+    pub fn read_queue(&self) -> RwLockReadGuard<'_, LoadQueue> {
+        self.queue.read().unwrap()
+    }
+
+    ///Take the meshes parsed since the last call
+    pub fn take_meshes(&self) -> Vec<Mesh> {
+        std::mem::take(&mut self.queue.write().unwrap().meshes)
+    }
+
+    pub fn take_errors(&self) -> Vec<Errors> {
+        std::mem::take(&mut self.queue.write().unwrap().errors)
+    }
+
+    ///Whether the thread has stopped and every mesh has been taken
+    pub fn is_finished(&self) -> bool {
+        let queue = self.queue.read().unwrap();
+        !queue.running && queue.meshes.is_empty()
+    }
+
+    ///Number of files that were read or failed, leaving out cancelled ones
+    pub fn get_attempted_count(&self) -> usize {
+        self.queue
+            .read()
+            .unwrap()
+            .files
+            .iter()
+            .filter(|file| !matches!(file.state, FileLoadState::Cancelled))
+            .count()
+    }
+}
+
+fn parse_files(queue: Arc<RwLock<LoadQueue>>, repair: bool, refresh: Arc<RwLock<bool>>) {
+    loop {
+        //Stopping is decided under the same lock files are queued under so none are missed
+        let (index, path, progress) = {
+            let mut queue = queue.write().unwrap();
+            let next = if queue.cancelled {
+                None
+            } else {
+                queue.files.iter().position(|file| file.state == FileLoadState::Waiting)
+            };
+            match next {
+                Some(index) => {
+                    queue.files[index].state = FileLoadState::Parsing;
+                    queue.progress.reset();
+                    (index, queue.files[index].path.clone(), queue.progress.clone())
+                }
+                None => {
+                    queue.running = false;
+                    break;
+                }
+            }
+        };
+
+        let mut loaded = path
+            .to_str()
+            .ok_or_else(|| Errors::ModelFileNameInvalid(path.to_string_lossy().to_string()))
+            .and_then(|path| load_meshes_with_progress(path, &progress));
+
+        //A model that can't be repaired is still loaded as it is, the error is reported with the rest
+        let mut repair_errors = vec![];
+        if let Ok(meshes) = loaded.as_mut() {
+            for mesh in meshes.iter_mut().filter(|mesh| repair && mesh.health.is_repairable()) {
+                if let Err(e) = mesh.repair() {
+                    repair_errors.push(e);
+                }
+            }
+        }
+
+        let mut queue = queue.write().unwrap();
+        if queue.cancelled {
+            queue.files[index].state = FileLoadState::Cancelled;
+            continue;
+        }
+        queue.errors.extend(repair_errors);
+        match loaded {
+            Ok(meshes) => {
+                queue.files[index].state = FileLoadState::Loaded(meshes.len());
+                queue.meshes.extend(meshes);
+            }
+            Err(e) => {
+                queue.files[index].state = FileLoadState::Failed;
+                queue.errors.push(e);
+            }
+        }
+        drop(queue);
+        *refresh.write().unwrap() = true;
+    }
+    *refresh.write().unwrap() = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_file(size: u64, state: FileLoadState) -> FileLoad {
+        FileLoad {
+            path: PathBuf::from("model.obj"),
+            size,
+            state,
+        }
+    }
+
+    #[test]
+    fn fraction_counts_bytes_read_of_the_current_file() {
+        let mut queue = LoadQueue {
+            files: vec![
+                get_file(100, FileLoadState::Loaded(1)),
+                get_file(300, FileLoadState::Parsing),
+                get_file(600, FileLoadState::Waiting),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(queue.get_fraction(), 0.1);
+
+        let mut reader = crate::loader::ProgressReader::new(&[0u8; 500][..], queue.progress.clone());
+        let mut data = vec![];
+        std::io::Read::read_to_end(&mut reader, &mut data).unwrap();
+        //Never more than the size of the file, in case it grew since it was queued
+        assert_eq!(queue.get_fraction(), 0.4);
+
+        queue.files[1].state = FileLoadState::Failed;
+        queue.files[2].state = FileLoadState::Cancelled;
+        assert_eq!(queue.get_fraction(), 1.0);
+    }
+}
@@ -0,0 +1,41 @@
+use crate::post_process::LayerInsertion;
+use crate::Errors;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+
+pub const PLATE_EXTENSION: &str = "gproj";
+
+///Placement of a model on the plate, the model itself is reloaded from its file
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PlateObject {
+    pub file_path: String,
+    pub name: String,
+    pub location: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+///Everything needed to restore a plate, stored as json
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Plate {
+    pub settings_path: String,
+    pub objects: Vec<PlateObject>,
+    #[serde(default)]
+    pub layer_insertions: Vec<LayerInsertion>,
+}
+
+impl Plate {
+    pub fn load(path: &str) -> Result<Plate, Errors> {
+        File::open(path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .ok_or_else(|| Errors::PlateFileNotReadable(path.to_string()))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Errors> {
+        File::create(path)
+            .ok()
+            .and_then(|file| serde_json::to_writer_pretty(file, self).ok())
+            .ok_or_else(|| Errors::PlateFileNotWritable(path.to_string()))
+    }
+}
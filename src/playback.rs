@@ -0,0 +1,179 @@
+use crate::toolpath::{LayerMoves, ToolpathMove};
+use glam::Vec2;
+
+///State of the move by move playback of a single layer
+#[derive(Clone, Debug)]
+pub struct Playback {
+    layer: usize,
+    time: f64,
+    pub playing: bool,
+    ///Multiplier applied to real time
+    pub speed: f64,
+}
+
+impl Playback {
+    pub fn new() -> Self {
+        Playback {
+            layer: 0,
+            time: f64::INFINITY,
+            playing: false,
+            speed: 1.0,
+        }
+    }
+
+    ///Restart playback if the viewed layer changed, showing the full layer when paused
+    pub fn set_layer(&mut self, layer: usize) {
+        if self.layer != layer {
+            self.layer = layer;
+            self.time = f64::INFINITY;
+            self.playing = false;
+        }
+    }
+
+    pub fn toggle_playing(&mut self, layer: &LayerMoves) {
+        if !self.playing && self.time >= layer.playback_time() {
+            self.time = 0.0;
+        }
+        self.playing = !self.playing;
+    }
+
+    ///Advance the playback by the real time elapsed since the last frame
+    pub fn advance(&mut self, dt: f64, layer: &LayerMoves) {
+        if self.playing {
+            self.time += dt * self.speed;
+            if self.time >= layer.playback_time() {
+                self.time = f64::INFINITY;
+                self.playing = false;
+            }
+        }
+    }
+
+    ///Index of the move being performed at the current time, the last move if playback finished
+    pub fn get_move_index(&self, layer: &LayerMoves) -> Option<usize> {
+        if layer.moves.is_empty() {
+            None
+        } else {
+            let index = layer.moves.partition_point(|m| m.start_time <= self.time);
+            Some(index.saturating_sub(1))
+        }
+    }
+
+    pub fn seek_to_move(&mut self, layer: &LayerMoves, move_index: usize) {
+        if let Some(m) = layer.moves.get(move_index) {
+            self.time = if move_index + 1 == layer.moves.len() {
+                f64::INFINITY
+            } else {
+                m.start_time
+            };
+        }
+    }
+
+    pub fn get_current_move<'a>(&self, layer: &'a LayerMoves) -> Option<&'a ToolpathMove> {
+        self.get_move_index(layer).map(|index| &layer.moves[index])
+    }
+
+    ///Interpolated nozzle position within the current move
+    pub fn get_nozzle_position(&self, layer: &LayerMoves) -> Option<Vec2> {
+        self.get_current_move(layer).map(|m| {
+            let fract = ((self.time - m.start_time) / m.duration).max(0.0).min(1.0);
+            m.start.lerp(m.end, fract as f32)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcode_parser::parse_gcode;
+    use crate::materials::MaterialProfile;
+    use crate::toolpath::{split_layers, FeatureSpeeds};
+
+    ///A single layer of three 10mm extrusions at 10mm/s, each taking one second
+    fn get_layer() -> LayerMoves {
+        let parsed = parse_gcode(
+            "G1 Z0.2 F600\n\
+             G1 X10 E1\n\
+             G1 Y10 E2\n\
+             G1 X0 E3\n",
+            &MaterialProfile::default(),
+        );
+        let mut layers = split_layers(&parsed.commands, &FeatureSpeeds::default());
+        assert_eq!(layers.len(), 1);
+        layers.remove(0)
+    }
+
+    #[test]
+    fn paused_playback_shows_the_full_layer() {
+        let layer = get_layer();
+        let mut playback = Playback::new();
+
+        playback.advance(1.0, &layer);
+
+        assert_eq!(playback.get_move_index(&layer), Some(2));
+        assert_eq!(playback.get_nozzle_position(&layer), Some(Vec2::new(0.0, 10.0)));
+    }
+
+    #[test]
+    fn playing_advances_through_the_moves_at_the_playback_speed() {
+        let layer = get_layer();
+        let mut playback = Playback::new();
+
+        playback.toggle_playing(&layer);
+        assert!(playback.playing);
+        assert_eq!(playback.get_move_index(&layer), Some(0));
+
+        playback.advance(0.5, &layer);
+        assert_eq!(playback.get_nozzle_position(&layer), Some(Vec2::new(5.0, 0.0)));
+
+        playback.speed = 2.0;
+        playback.advance(0.5, &layer);
+        assert_eq!(playback.get_move_index(&layer), Some(1));
+        assert_eq!(playback.get_nozzle_position(&layer), Some(Vec2::new(10.0, 5.0)));
+    }
+
+    #[test]
+    fn pausing_stops_and_finishing_resets_playback() {
+        let layer = get_layer();
+        let mut playback = Playback::new();
+
+        playback.toggle_playing(&layer);
+        playback.advance(1.5, &layer);
+        playback.toggle_playing(&layer);
+        playback.advance(10.0, &layer);
+        assert!(!playback.playing);
+        assert_eq!(playback.get_move_index(&layer), Some(1));
+
+        playback.toggle_playing(&layer);
+        playback.advance(10.0, &layer);
+        assert!(!playback.playing);
+        assert_eq!(playback.get_move_index(&layer), Some(2));
+
+        //Playing a finished layer starts it over
+        playback.toggle_playing(&layer);
+        assert_eq!(playback.get_move_index(&layer), Some(0));
+    }
+
+    #[test]
+    fn stepping_seeks_to_the_start_of_each_move() {
+        let layer = get_layer();
+        let mut playback = Playback::new();
+
+        playback.seek_to_move(&layer, 1);
+        assert_eq!(playback.get_move_index(&layer), Some(1));
+        assert_eq!(playback.get_nozzle_position(&layer), Some(Vec2::new(10.0, 0.0)));
+
+        playback.seek_to_move(&layer, 0);
+        assert_eq!(playback.get_move_index(&layer), Some(0));
+
+        //Moves past the end are ignored
+        playback.seek_to_move(&layer, 5);
+        assert_eq!(playback.get_move_index(&layer), Some(0));
+
+        playback.seek_to_move(&layer, 2);
+        assert_eq!(playback.get_nozzle_position(&layer), Some(Vec2::new(0.0, 10.0)));
+
+        playback.seek_to_move(&layer, 0);
+        playback.set_layer(1);
+        assert_eq!(playback.get_move_index(&layer), Some(2));
+    }
+}
@@ -0,0 +1,562 @@
+use crate::gcode_parser::parse_gcode;
+use crate::materials::MaterialProfile;
+use crate::Errors;
+use egui::Color32;
+use gladius_shared::types::Command;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+///File the post-processing pipeline is stored in, relative to the working directory like the slicer
+pub const POST_PROCESSORS_PATH: &str = "post_processors.json";
+
+///Pauses the print until the user resumes it on the printer
+pub const PAUSE_GCODE: &str = "M0";
+
+///Parks the head and waits for the filament to be swapped
+pub const FILAMENT_CHANGE_GCODE: &str = "M600";
+
+const EPSILON: f64 = 0.000_001;
+
+///Scripts run so far, keeps the temporary files of exports running at the same time apart
+static SCRIPT_RUN_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+///Layer heights from the viewers are stored as f32 so only match them up to rounding
+const LAYER_HEIGHT_TOLERANCE: f64 = 0.001;
+
+///A single step applied to the G-code before it is saved
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum PostProcessor {
+    ///External command run with the path of a temporary copy of the G-code as its last argument,
+    ///the script edits the file in place
+    Script { command: String },
+    ///Pause before printing the layer, layers are numbered from 1
+    PauseAtLayer { layer: usize },
+    ///Filament change before the first layer at or above the height in mm
+    FilamentChangeAtHeight { height: f64 },
+    ///Change the nozzle temperature every step of the tower
+    TemperatureTower {
+        start_height: f64,
+        step_height: f64,
+        start_temperature: f64,
+        step_temperature: f64,
+    },
+    ///Text added to the start and end of the file, see fill_template for the placeholders
+    HeaderFooter { header: String, footer: String },
+}
+
+impl PostProcessor {
+    ///One of each kind with reasonable values, used to add new steps
+    pub fn get_defaults() -> Vec<PostProcessor> {
+        vec![
+            PostProcessor::Script {
+                command: String::new(),
+            },
+            PostProcessor::PauseAtLayer { layer: 2 },
+            PostProcessor::FilamentChangeAtHeight { height: 5.0 },
+            PostProcessor::TemperatureTower {
+                start_height: 0.0,
+                step_height: 10.0,
+                start_temperature: 220.0,
+                step_temperature: -5.0,
+            },
+            PostProcessor::HeaderFooter {
+                header: String::from("; Print time: {print_time}\n; Filament: {filament_weight} g"),
+                footer: String::new(),
+            },
+        ]
+    }
+
+    pub fn get_translation_key(&self) -> &'static str {
+        match self {
+            PostProcessor::Script { .. } => "post_process_script",
+            PostProcessor::PauseAtLayer { .. } => "post_process_pause",
+            PostProcessor::FilamentChangeAtHeight { .. } => "post_process_filament_change",
+            PostProcessor::TemperatureTower { .. } => "post_process_temperature_tower",
+            PostProcessor::HeaderFooter { .. } => "post_process_header_footer",
+        }
+    }
+
+    ///Apply the step to the G-code. `starts` are the layer starts of the G-code passed in, found
+    ///on first use and moved along by the steps that only add lines so they aren't searched again.
+    pub fn apply(&self, gcode: String, values: &TemplateValues, starts: &mut Option<Vec<LayerStart>>) -> Result<String, Errors> {
+        match self {
+            PostProcessor::Script { command } => {
+                //The script can change anything so the layers are searched again by the next step
+                *starts = None;
+                run_script(command, gcode)
+            }
+            PostProcessor::PauseAtLayer { layer } => {
+                let layer_starts = starts.get_or_insert_with(|| get_layer_starts(&gcode));
+                let insertions = layer_starts
+                    .iter()
+                    .filter(|start| start.index == *layer)
+                    .map(|start| (start.line, PAUSE_GCODE.to_string()))
+                    .collect();
+                Ok(insert_tracked_lines(&gcode, insertions, layer_starts))
+            }
+            PostProcessor::FilamentChangeAtHeight { height } => {
+                let layer_starts = starts.get_or_insert_with(|| get_layer_starts(&gcode));
+                let insertions = layer_starts
+                    .iter()
+                    .find(|start| start.z >= height - EPSILON)
+                    .map(|start| (start.line, FILAMENT_CHANGE_GCODE.to_string()))
+                    .into_iter()
+                    .collect();
+                Ok(insert_tracked_lines(&gcode, insertions, layer_starts))
+            }
+            PostProcessor::TemperatureTower {
+                start_height,
+                step_height,
+                start_temperature,
+                step_temperature,
+            } => {
+                let layer_starts = starts.get_or_insert_with(|| get_layer_starts(&gcode));
+                let mut current_step = None;
+                let mut insertions = vec![];
+                for start in layer_starts.iter() {
+                    if start.z < start_height - EPSILON || *step_height <= 0.0 {
+                        continue;
+                    }
+                    let step = ((start.z - start_height + EPSILON) / step_height).floor() as usize;
+                    if current_step != Some(step) {
+                        current_step = Some(step);
+                        let temperature = start_temperature + step as f64 * step_temperature;
+                        insertions.push((start.line, format!("M104 S{:.0}", temperature)));
+                    }
+                }
+                Ok(insert_tracked_lines(&gcode, insertions, layer_starts))
+            }
+            PostProcessor::HeaderFooter { header, footer } => {
+                let mut output = String::with_capacity(gcode.len() + header.len() + footer.len() + 2);
+                if !header.is_empty() {
+                    let header = fill_template(header, values);
+                    if let Some(layer_starts) = starts.as_mut() {
+                        shift_layer_starts(layer_starts, &[(0, header.matches('\n').count() + 1)]);
+                    }
+                    output += &header;
+                    output.push('\n');
+                }
+                output += &gcode;
+                if !footer.is_empty() {
+                    if !output.ends_with('\n') {
+                        output.push('\n');
+                    }
+                    output += &fill_template(footer, values);
+                    output.push('\n');
+                }
+                Ok(output)
+            }
+        }
+    }
+}
+
+///G-code inserted at the start of a layer from the layer viewer
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum LayerAction {
+    Pause,
+    FilamentChange,
+    Custom(String),
+}
+
+impl LayerAction {
+    pub fn get_gcode(&self) -> &str {
+        match self {
+            LayerAction::Pause => PAUSE_GCODE,
+            LayerAction::FilamentChange => FILAMENT_CHANGE_GCODE,
+            LayerAction::Custom(gcode) => gcode,
+        }
+    }
+
+    pub fn get_translation_key(&self) -> &'static str {
+        match self {
+            LayerAction::Pause => "insert_pause",
+            LayerAction::FilamentChange => "insert_filament_change",
+            LayerAction::Custom(_) => "insert_custom",
+        }
+    }
+
+    pub fn get_color(&self) -> Color32 {
+        match self {
+            LayerAction::Pause => Color32::from_rgb(255, 200, 0),
+            LayerAction::FilamentChange => Color32::from_rgb(0, 200, 255),
+            LayerAction::Custom(_) => Color32::from_rgb(200, 80, 220),
+        }
+    }
+}
+
+///An action at a layer, kept by height so it still applies after reslicing
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct LayerInsertion {
+    pub z: f64,
+    pub action: LayerAction,
+}
+
+impl LayerInsertion {
+    pub fn is_at_height(&self, z: f64) -> bool {
+        (self.z - z).abs() < LAYER_HEIGHT_TOLERANCE
+    }
+}
+
+///Insert the actions before the first layer at or above their height
+pub fn apply_layer_insertions(gcode: &str, insertions: &[LayerInsertion]) -> String {
+    if insertions.is_empty() {
+        return gcode.to_string();
+    }
+
+    let starts = get_layer_starts(gcode);
+    let mut lines: Vec<(usize, String)> = insertions
+        .iter()
+        .filter_map(|insertion| {
+            starts
+                .iter()
+                .find(|start| start.z >= insertion.z - LAYER_HEIGHT_TOLERANCE)
+                .map(|start| (start.line, insertion.action.get_gcode().to_string()))
+        })
+        .collect();
+    //Stable so insertions at the same layer keep the order they were added in
+    lines.sort_by_key(|(line, _)| *line);
+
+    insert_lines(gcode, lines)
+}
+
+///Values describing the print that can be placed in templates
+#[derive(Clone, Debug, Default)]
+pub struct TemplateValues {
+    ///Print time in seconds
+    pub print_time: f64,
+    ///Weight in grams
+    pub filament_weight: f64,
+    ///Length in meters
+    pub filament_length: f64,
+    pub layer_count: usize,
+    pub material: String,
+    ///Names of the objects on the plate without their extensions
+    pub object: String,
+    ///Height in mm
+    pub layer_height: f64,
+    ///Date the print was exported, YYYY-MM-DD
+    pub date: String,
+}
+
+///Replace the placeholders {print_time}, {filament_weight}, {filament_length}, {layer_count},
+///{material}, {object}, {layer_height} and {date} with their values
+pub fn fill_template(template: &str, values: &TemplateValues) -> String {
+    let total = values.print_time.round() as u64;
+    let print_time = format!("{}h{:02}m", total / 3600, (total / 60) % 60);
+
+    template
+        .replace("{print_time}", &print_time)
+        .replace("{filament_weight}", &format!("{:.1}", values.filament_weight))
+        .replace("{filament_length}", &format!("{:.2}", values.filament_length))
+        .replace("{layer_count}", &values.layer_count.to_string())
+        .replace("{material}", &values.material)
+        .replace("{object}", &values.object)
+        .replace("{layer_height}", &format!("{:.2}", values.layer_height))
+        .replace("{date}", &values.date)
+}
+
+///Today's date in UTC as YYYY-MM-DD
+pub fn get_date() -> String {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+
+    //Convert days since the epoch to a civil date, from Howard Hinnant's date algorithms
+    let days = (seconds / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+///Run every step of the pipeline in order
+pub fn post_process(gcode: String, processors: &[PostProcessor], values: &TemplateValues) -> Result<String, Errors> {
+    let mut starts = None;
+    processors
+        .iter()
+        .try_fold(gcode, |gcode, processor| processor.apply(gcode, values, &mut starts))
+}
+
+///The first line of a layer
+pub struct LayerStart {
+    ///Layer number starting from 1
+    pub index: usize,
+    pub z: f64,
+    pub line: usize,
+}
+
+///Find where every layer starts by parsing the G-code. A layer starts with the move to the height
+///of the first extrusion above the previous layer, so z hops don't start a new layer.
+pub fn get_layer_starts(gcode: &str) -> Vec<LayerStart> {
+    let parsed = parse_gcode(gcode, &MaterialProfile::default());
+    let mut starts: Vec<LayerStart> = vec![];
+    let mut current = (0.0, 0);
+
+    for (command, line) in parsed.commands.iter().zip(parsed.command_lines.iter()) {
+        match command {
+            Command::LayerChange { z, .. } => current = (*z, *line),
+            Command::MoveAndExtrude { .. } => {
+                let (z, line) = current;
+                if starts.last().map_or(true, |start| z > start.z + EPSILON) {
+                    starts.push(LayerStart {
+                        index: starts.len() + 1,
+                        z,
+                        line,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    starts
+}
+
+///Move the layer starts down past lines added before them, given as line and number of lines added
+fn shift_layer_starts(starts: &mut [LayerStart], added: &[(usize, usize)]) {
+    for start in starts.iter_mut() {
+        start.line += added
+            .iter()
+            .filter(|(line, _)| *line <= start.line)
+            .map(|(_, count)| count)
+            .sum::<usize>();
+    }
+}
+
+///Insert the lines, moving the layer starts of the G-code along with them
+fn insert_tracked_lines(gcode: &str, insertions: Vec<(usize, String)>, starts: &mut [LayerStart]) -> String {
+    let added: Vec<(usize, usize)> = insertions
+        .iter()
+        .map(|(line, text)| (*line, text.matches('\n').count() + 1))
+        .collect();
+    shift_layer_starts(starts, &added);
+    insert_lines(gcode, insertions)
+}
+
+///Insert text before the given zero based lines, insertions must be sorted by line
+pub fn insert_lines(gcode: &str, insertions: Vec<(usize, String)>) -> String {
+    if insertions.is_empty() {
+        return gcode.to_string();
+    }
+
+    let mut insertions = insertions.into_iter().peekable();
+    let mut output = String::with_capacity(gcode.len() + 64);
+    for (line_index, line) in gcode.split_inclusive('\n').enumerate() {
+        while let Some((_, text)) = insertions.next_if(|(insert_line, _)| *insert_line <= line_index) {
+            output += &text;
+            output.push('\n');
+        }
+        output += line;
+    }
+    //Insertions past the end go at the end of the file
+    for (_, text) in insertions {
+        if !output.is_empty() && !output.ends_with('\n') {
+            output.push('\n');
+        }
+        output += &text;
+        output.push('\n');
+    }
+    output
+}
+
+///Split a command line into its program and arguments. Quotes group words containing spaces and
+///a backslash outside single quotes keeps the next character as it is.
+fn split_command(command: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut current: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => current.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                //Windows paths are full of backslashes so only quotes and backslashes are escaped
+                let word = current.get_or_insert_with(String::new);
+                match chars.clone().next() {
+                    Some(next) if next == '"' || next == '\'' || next == '\\' || (quote.is_none() && next.is_whitespace()) => {
+                        word.push(next);
+                        chars.next();
+                    }
+                    _ => word.push('\\'),
+                }
+            }
+            (Some(_), c) => current.get_or_insert_with(String::new).push(c),
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                current.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(current.take()),
+            (None, c) => current.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(current);
+    words
+}
+
+fn run_script(command: &str, gcode: String) -> Result<String, Errors> {
+    let mut words = split_command(command).into_iter();
+    let program = match words.next() {
+        Some(program) => program,
+        None => return Ok(gcode),
+    };
+
+    let path = std::env::temp_dir().join(format!(
+        "gladius_post_process_{}_{}.gcode",
+        std::process::id(),
+        SCRIPT_RUN_COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let error = || Errors::PostProcessScriptFailed(command.to_string());
+
+    std::fs::write(&path, gcode).map_err(|_| error())?;
+
+    let status = std::process::Command::new(&program)
+        .args(words)
+        .arg(&path)
+        .status();
+
+    let result = match status {
+        Ok(status) if status.success() => std::fs::read_to_string(&path).map_err(|_| error()),
+        _ => Err(error()),
+    };
+
+    //Leaving the temporary file behind is harmless
+    let _ = std::fs::remove_file(&path);
+    result
+}
+
+///Load the post-processing pipeline, empty if none was saved
+pub fn load_post_processors() -> Vec<PostProcessor> {
+    File::open(POST_PROCESSORS_PATH)
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_post_processors(processors: &[PostProcessor]) -> std::io::Result<()> {
+    let file = File::create(POST_PROCESSORS_PATH)?;
+    serde_json::to_writer_pretty(file, processors)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GCODE: &str = "G28\n\
+                         G1 Z0.2\n\
+                         G1 X10 E1\n\
+                         G1 Z0.4\n\
+                         G1 X0 E2\n\
+                         G1 Z0.6\n\
+                         G1 X10 E3\n\
+                         G1 Z0.8\n\
+                         G1 X0 E4\n";
+
+    fn starts_of(gcode: &str) -> Vec<(usize, usize)> {
+        get_layer_starts(gcode)
+            .iter()
+            .map(|start| (start.index, start.line))
+            .collect()
+    }
+
+    #[test]
+    fn split_command_handles_quotes_and_escapes() {
+        assert_eq!(split_command("python3 script.py"), vec!["python3", "script.py"]);
+        assert_eq!(
+            split_command("\"C:\\Program Files\\Python\\python.exe\" \"my script.py\" --flag"),
+            vec!["C:\\Program Files\\Python\\python.exe", "my script.py", "--flag"]
+        );
+        assert_eq!(split_command("echo 'a \"b\" c' \"\""), vec!["echo", "a \"b\" c", ""]);
+        assert_eq!(split_command("echo a\\ b \\\"c"), vec!["echo", "a b", "\"c"]);
+        assert!(split_command("   ").is_empty());
+    }
+
+    #[test]
+    fn tracked_layer_starts_match_reparsed_gcode() {
+        let processors = vec![
+            PostProcessor::HeaderFooter {
+                header: "; first\n; second".to_string(),
+                footer: "; end".to_string(),
+            },
+            PostProcessor::PauseAtLayer { layer: 2 },
+            PostProcessor::TemperatureTower {
+                start_height: 0.2,
+                step_height: 0.4,
+                start_temperature: 220.0,
+                step_temperature: -5.0,
+            },
+            PostProcessor::FilamentChangeAtHeight { height: 0.8 },
+        ];
+        let values = TemplateValues::default();
+
+        let mut starts = None;
+        let mut gcode = GCODE.to_string();
+        for processor in &processors {
+            gcode = processor.apply(gcode, &values, &mut starts).unwrap();
+            if let Some(tracked) = &starts {
+                let tracked: Vec<(usize, usize)> = tracked.iter().map(|start| (start.index, start.line)).collect();
+                assert_eq!(tracked, starts_of(&gcode));
+            }
+        }
+
+        assert_eq!(gcode, post_process(GCODE.to_string(), &processors, &values).unwrap());
+        let lines: Vec<&str> = gcode.lines().collect();
+        let line_of = |text: &str| lines.iter().position(|line| *line == text).unwrap();
+        assert_eq!(lines[line_of(PAUSE_GCODE) + 1], "G1 Z0.4");
+        assert_eq!(lines[line_of(FILAMENT_CHANGE_GCODE) + 1], "G1 Z0.8");
+        assert_eq!(lines[line_of("M104 S215") + 1], "G1 Z0.6");
+    }
+
+    fn insertion(z: f64, gcode: &str) -> LayerInsertion {
+        LayerInsertion {
+            z,
+            action: LayerAction::Custom(gcode.to_string()),
+        }
+    }
+
+    #[test]
+    fn layer_insertions_match_heights_within_tolerance() {
+        let gcode = apply_layer_insertions(
+            GCODE,
+            &[
+                insertion(0.4 + LAYER_HEIGHT_TOLERANCE / 2.0, "; just above"),
+                insertion(0.6 - LAYER_HEIGHT_TOLERANCE / 2.0, "; just below"),
+                insertion(0.5, "; between"),
+                insertion(2.0, "; above the print"),
+            ],
+        );
+
+        let lines: Vec<&str> = gcode.lines().collect();
+        let line_of = |text: &str| lines.iter().position(|line| *line == text).unwrap();
+        assert_eq!(lines[line_of("; just above") + 1], "G1 Z0.4");
+        assert_eq!(lines[line_of("; just below") + 1], "; between");
+        assert_eq!(lines[line_of("; between") + 1], "G1 Z0.6");
+        assert!(!gcode.contains("; above the print"));
+    }
+
+    #[test]
+    fn layer_insertions_at_the_same_layer_keep_their_order() {
+        let gcode = apply_layer_insertions(
+            GCODE,
+            &[
+                insertion(0.6, "; second layer 3"),
+                insertion(0.2, "; layer 1"),
+                insertion(0.6, "; first layer 3"),
+            ],
+        );
+
+        assert!(gcode.starts_with("G28\n; layer 1\nG1 Z0.2\n"));
+        assert!(gcode.contains("G1 X0 E2\n; second layer 3\n; first layer 3\nG1 Z0.6\n"));
+        assert_eq!(apply_layer_insertions(GCODE, &[]), GCODE);
+    }
+}
@@ -0,0 +1,71 @@
+use crate::export::ExportPreset;
+use crate::print_host::PrintHost;
+use crate::serial::DEFAULT_BAUD_RATE;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+
+///File the preferences are stored in, relative to the working directory like the slicer
+pub const PREFERENCES_PATH: &str = "preferences.json";
+
+///Settings of the GUI itself that are kept between sessions
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Preferences {
+    ///Embed previews in saved G-code
+    pub embed_thumbnails: bool,
+    ///Width and height of each embedded preview in pixels
+    pub thumbnail_sizes: Vec<[u32; 2]>,
+    pub export_presets: Vec<ExportPreset>,
+    pub selected_export_preset: usize,
+    ///Directory G-code was last saved to
+    pub last_export_dir: Option<String>,
+    pub print_hosts: Vec<PrintHost>,
+    pub selected_print_host: usize,
+    pub start_print_after_upload: bool,
+    ///Serial port of a printer connected over USB
+    pub serial_port: Option<String>,
+    pub baud_rate: u32,
+    ///Fix the problems found in models as they are loaded
+    pub repair_models: bool,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Preferences {
+            embed_thumbnails: true,
+            thumbnail_sizes: vec![[16, 16], [220, 124]],
+            export_presets: ExportPreset::get_defaults(),
+            selected_export_preset: 0,
+            last_export_dir: None,
+            print_hosts: vec![],
+            selected_print_host: 0,
+            start_print_after_upload: false,
+            serial_port: None,
+            baud_rate: DEFAULT_BAUD_RATE,
+            repair_models: true,
+        }
+    }
+}
+
+pub fn load_preferences() -> Preferences {
+    let mut preferences: Preferences = File::open(PREFERENCES_PATH)
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .unwrap_or_default();
+
+    //There must always be a preset to export with
+    if preferences.export_presets.is_empty() {
+        preferences.export_presets = ExportPreset::get_defaults();
+    }
+    preferences.selected_export_preset = preferences
+        .selected_export_preset
+        .min(preferences.export_presets.len() - 1);
+    preferences
+}
+
+pub fn save_preferences(preferences: &Preferences) -> std::io::Result<()> {
+    let file = File::create(PREFERENCES_PATH)?;
+    serde_json::to_writer_pretty(file, preferences)?;
+    Ok(())
+}
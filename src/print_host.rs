@@ -0,0 +1,613 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+///The HTTP API a print host speaks
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HostType {
+    OctoPrint,
+    Moonraker,
+}
+
+impl HostType {
+    pub const ALL: [HostType; 2] = [HostType::OctoPrint, HostType::Moonraker];
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            HostType::OctoPrint => "OctoPrint",
+            HostType::Moonraker => "Moonraker",
+        }
+    }
+
+    fn get_upload_path(&self) -> &'static str {
+        match self {
+            HostType::OctoPrint => "/api/files/local",
+            HostType::Moonraker => "/server/files/upload",
+        }
+    }
+}
+
+///A printer reachable over the network
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct PrintHost {
+    pub name: String,
+    pub host_type: HostType,
+    ///Base address such as http://octopi.local
+    pub url: String,
+    ///Sent as X-Api-Key, Moonraker only needs one if authorization is enabled
+    pub api_key: String,
+}
+
+impl Default for PrintHost {
+    fn default() -> Self {
+        PrintHost {
+            name: String::from("Printer"),
+            host_type: HostType::OctoPrint,
+            url: String::from("http://localhost:5000"),
+            api_key: String::new(),
+        }
+    }
+}
+
+///Bytes of an upload sent so far out of the total
+#[derive(Copy, Clone, Debug, Default)]
+pub struct UploadProgress {
+    pub sent: usize,
+    pub total: usize,
+}
+
+impl UploadProgress {
+    pub fn get_fraction(&self) -> f32 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.sent as f32 / self.total as f32
+        }
+    }
+}
+
+///What the printer is doing, used to decide which job commands make sense
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PrinterActivity {
+    Idle,
+    Printing,
+    Paused,
+}
+
+///Job control sent to the print host
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JobCommand {
+    Pause,
+    Resume,
+    Cancel,
+}
+
+impl JobCommand {
+    pub fn get_translation_key(&self) -> &'static str {
+        match self {
+            JobCommand::Pause => "pause_print",
+            JobCommand::Resume => "resume_print",
+            JobCommand::Cancel => "cancel_print",
+        }
+    }
+
+    pub fn is_available(&self, activity: PrinterActivity) -> bool {
+        match self {
+            JobCommand::Pause => activity == PrinterActivity::Printing,
+            JobCommand::Resume => activity == PrinterActivity::Paused,
+            JobCommand::Cancel => activity != PrinterActivity::Idle,
+        }
+    }
+}
+
+///Current and target temperature of a heater
+#[derive(Clone, Debug)]
+pub struct Temperature {
+    pub name: String,
+    pub actual: f64,
+    pub target: f64,
+}
+
+///A snapshot of the printer and its job as reported by the print host
+#[derive(Clone, Debug)]
+pub struct PrinterStatus {
+    ///State text as the host reports it
+    pub state: String,
+    pub activity: PrinterActivity,
+    pub temperatures: Vec<Temperature>,
+    pub file_name: Option<String>,
+    ///Fraction of the job done
+    pub progress: Option<f32>,
+    ///Byte offset reached in the printed file and the size of the file
+    pub file_position: Option<(usize, usize)>,
+    ///Zero based layer, only known if the G-code reports layer changes to the host
+    pub current_layer: Option<usize>,
+    pub position: Option<[f32; 3]>,
+    pub print_time: Option<f64>,
+    pub time_left: Option<f64>,
+}
+
+///Reader over the request body that reports how much has been read
+struct ProgressReader {
+    body: Cursor<Vec<u8>>,
+    progress: Arc<RwLock<Option<UploadProgress>>>,
+}
+
+impl Read for ProgressReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.body.read(buf)?;
+        if let Some(progress) = self.progress.write().unwrap().as_mut() {
+            progress.sent += read;
+        }
+        Ok(read)
+    }
+}
+
+impl PrintHost {
+    fn get_url(&self, path: &str) -> String {
+        format!("{}{}", self.url.trim_end_matches('/'), path)
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = ureq::AgentBuilder::new()
+            .timeout_connect(CONNECT_TIMEOUT)
+            .build()
+            .request(method, &self.get_url(path));
+
+        if self.api_key.is_empty() {
+            request
+        } else {
+            request.set("X-Api-Key", &self.api_key)
+        }
+    }
+
+    fn get_json(&self, path: &str) -> Result<Value, String> {
+        let response = self.request("GET", path).call().map_err(describe_error)?;
+        let body = response.into_string().map_err(|e| e.to_string())?;
+        serde_json::from_str(&body).map_err(|e| e.to_string())
+    }
+
+    fn post_json(&self, path: &str, body: &Value) -> Result<(), String> {
+        self.request("POST", path)
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string())
+            .map(|_| ())
+            .map_err(describe_error)
+    }
+
+    ///Poll the printer and job state. Blocks until the host answers.
+    pub fn get_status(&self) -> Result<PrinterStatus, String> {
+        match self.host_type {
+            HostType::OctoPrint => self.get_octoprint_status(),
+            HostType::Moonraker => self.get_moonraker_status(),
+        }
+    }
+
+    fn get_octoprint_status(&self) -> Result<PrinterStatus, String> {
+        //OctoPrint answers 409 while no printer is connected, which still tells us the job state
+        let printer = match self.get_json("/api/printer?exclude=sd") {
+            Ok(printer) => Some(printer),
+            Err(e) if e.starts_with("HTTP 409") => None,
+            Err(e) => return Err(e),
+        };
+        let job = self.get_json("/api/job")?;
+
+        let flags = printer.as_ref().map(|printer| &printer["state"]["flags"]);
+        let is_set = |flag: &str| flags.map_or(false, |flags| flags[flag].as_bool().unwrap_or(false));
+        let activity = if is_set("paused") || is_set("pausing") {
+            PrinterActivity::Paused
+        } else if is_set("printing") {
+            PrinterActivity::Printing
+        } else {
+            PrinterActivity::Idle
+        };
+
+        let temperatures = printer
+            .as_ref()
+            .and_then(|printer| printer["temperature"].as_object())
+            .map(|heaters| {
+                heaters
+                    .iter()
+                    .filter(|(_, heater)| heater["actual"].is_number())
+                    .map(|(name, heater)| Temperature {
+                        name: name.clone(),
+                        actual: heater["actual"].as_f64().unwrap_or_default(),
+                        target: heater["target"].as_f64().unwrap_or_default(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let state = printer
+            .as_ref()
+            .and_then(|printer| printer["state"]["text"].as_str())
+            .or_else(|| job["state"].as_str())
+            .unwrap_or("Unknown")
+            .to_string();
+
+        let file_position = job["progress"]["filepos"]
+            .as_u64()
+            .zip(job["job"]["file"]["size"].as_u64())
+            .map(|(position, size)| (position as usize, size as usize));
+
+        Ok(PrinterStatus {
+            state,
+            activity,
+            temperatures,
+            file_name: job["job"]["file"]["name"].as_str().map(String::from),
+            progress: job["progress"]["completion"].as_f64().map(|completion| completion as f32 / 100.0),
+            file_position,
+            current_layer: None,
+            position: None,
+            print_time: job["progress"]["printTime"].as_f64(),
+            time_left: job["progress"]["printTimeLeft"].as_f64(),
+        })
+    }
+
+    fn get_moonraker_status(&self) -> Result<PrinterStatus, String> {
+        let response = self.get_json("/printer/objects/query?print_stats&virtual_sdcard&extruder&heater_bed&toolhead")?;
+        let status = &response["result"]["status"];
+        let print_stats = &status["print_stats"];
+
+        let state = print_stats["state"].as_str().unwrap_or("unknown").to_string();
+        let activity = match state.as_str() {
+            "printing" => PrinterActivity::Printing,
+            "paused" => PrinterActivity::Paused,
+            _ => PrinterActivity::Idle,
+        };
+
+        let temperatures = ["extruder", "heater_bed"]
+            .iter()
+            .filter(|name| status[**name]["temperature"].is_number())
+            .map(|name| Temperature {
+                name: name.to_string(),
+                actual: status[*name]["temperature"].as_f64().unwrap_or_default(),
+                target: status[*name]["target"].as_f64().unwrap_or_default(),
+            })
+            .collect();
+
+        let position = status["toolhead"]["position"].as_array().and_then(|position| {
+            let axis = |i: usize| position.get(i).and_then(|v| v.as_f64()).map(|v| v as f32);
+            Some([axis(0)?, axis(1)?, axis(2)?])
+        });
+
+        let progress = status["virtual_sdcard"]["progress"].as_f64().map(|progress| progress as f32);
+        let print_time = print_stats["print_duration"].as_f64();
+        //Moonraker doesn't estimate the time left, extrapolate from the progress so far
+        let time_left = progress
+            .zip(print_time)
+            .filter(|(progress, _)| *progress > 0.0)
+            .map(|(progress, time)| time / progress as f64 - time);
+
+        let file_name = print_stats["filename"]
+            .as_str()
+            .filter(|name| !name.is_empty())
+            .map(String::from);
+
+        Ok(PrinterStatus {
+            state,
+            activity,
+            temperatures,
+            file_name,
+            progress,
+            //The file size isn't part of the printer objects so the offset can't be related to ours
+            file_position: None,
+            current_layer: get_moonraker_layer(print_stats),
+            position,
+            print_time,
+            time_left,
+        })
+    }
+
+    ///Pause, resume or cancel the running job
+    pub fn send_command(&self, command: JobCommand) -> Result<(), String> {
+        match self.host_type {
+            HostType::OctoPrint => {
+                let body = match command {
+                    JobCommand::Pause => serde_json::json!({"command": "pause", "action": "pause"}),
+                    JobCommand::Resume => serde_json::json!({"command": "pause", "action": "resume"}),
+                    JobCommand::Cancel => serde_json::json!({"command": "cancel"}),
+                };
+                self.post_json("/api/job", &body)
+            }
+            HostType::Moonraker => {
+                let path = match command {
+                    JobCommand::Pause => "/printer/print/pause",
+                    JobCommand::Resume => "/printer/print/resume",
+                    JobCommand::Cancel => "/printer/print/cancel",
+                };
+                self.post_json(path, &serde_json::json!({}))
+            }
+        }
+    }
+
+    ///Upload the file, optionally starting the print once it arrives. Blocks until the upload
+    ///finishes, updating the progress as the body is sent.
+    pub fn upload(&self, file_name: &str, data: Vec<u8>, start_print: bool, progress: Arc<RwLock<Option<UploadProgress>>>) -> Result<(), String> {
+        let start = if start_print { "true" } else { "false" };
+        let fields: Vec<(&str, &str)> = match self.host_type {
+            HostType::OctoPrint => vec![("select", start), ("print", start)],
+            HostType::Moonraker => vec![("root", "gcodes"), ("print", start)],
+        };
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos())
+            .unwrap_or_default();
+        let boundary = format!("----GladiusBoundary{:x}", nanos);
+        let body = build_multipart_body(&boundary, &fields, file_name, data);
+
+        *progress.write().unwrap() = Some(UploadProgress {
+            sent: 0,
+            total: body.len(),
+        });
+
+        let length = body.len().to_string();
+        let result = self
+            .request("POST", self.host_type.get_upload_path())
+            .set("Content-Type", &format!("multipart/form-data; boundary={}", boundary))
+            .set("Content-Length", &length)
+            .send(ProgressReader {
+                body: Cursor::new(body),
+                progress: progress.clone(),
+            });
+
+        *progress.write().unwrap() = None;
+        result.map(|_| ()).map_err(describe_error)
+    }
+}
+
+///Moonraker counts layers from 1 and reports 0 before the first layer starts
+fn get_moonraker_layer(print_stats: &Value) -> Option<usize> {
+    print_stats["info"]["current_layer"]
+        .as_u64()
+        .filter(|layer| *layer > 0)
+        .map(|layer| layer as usize - 1)
+}
+
+fn describe_error(error: ureq::Error) -> String {
+    match error {
+        ureq::Error::Status(code, response) => {
+            let status = response.status_text().to_string();
+            let body = response.into_string().unwrap_or_default();
+            format!("HTTP {} {} {}", code, status, body.trim())
+        }
+        ureq::Error::Transport(transport) => transport.to_string(),
+    }
+}
+
+fn build_multipart_body(boundary: &str, fields: &[(&str, &str)], file_name: &str, data: Vec<u8>) -> Vec<u8> {
+    let mut body = Vec::with_capacity(data.len() + 512);
+
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                boundary, name, value
+            )
+            .as_bytes(),
+        );
+    }
+
+    body.extend_from_slice(
+        format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary,
+            file_name.replace('"', "_")
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(&data);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    ///Answer one connection per response with a stand-in print host, returning the requests received
+    fn serve(responses: Vec<(u16, &'static str)>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = std::thread::spawn(move || {
+            responses
+                .into_iter()
+                .map(|(code, body)| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let request = read_request(&mut stream);
+                    write!(
+                        stream,
+                        "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        code,
+                        body.len(),
+                        body
+                    )
+                    .unwrap();
+                    request
+                })
+                .collect()
+        });
+
+        (url, handle)
+    }
+
+    fn read_request(stream: &mut impl Read) -> String {
+        let mut data = vec![];
+        let mut buf = [0; 4096];
+        loop {
+            let read = stream.read(&mut buf).unwrap();
+            data.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&data).to_string();
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                    .unwrap_or_default();
+                if data.len() >= end + 4 + length {
+                    return text;
+                }
+            }
+            if read == 0 {
+                return String::from_utf8_lossy(&data).to_string();
+            }
+        }
+    }
+
+    fn host(host_type: HostType, url: String, api_key: &str) -> PrintHost {
+        PrintHost {
+            name: String::from("Test"),
+            host_type,
+            url: url + "/",
+            api_key: api_key.to_string(),
+        }
+    }
+
+    #[test]
+    fn uploads_multipart_file_with_progress() {
+        let (url, server) = serve(vec![(201, "{}")]);
+        let progress = Arc::new(RwLock::new(None));
+        let host = host(HostType::OctoPrint, url, "secret");
+
+        host.upload("part \"a\".gcode", b"G28\nG1 X10\n".to_vec(), true, progress.clone())
+            .unwrap();
+
+        let request = server.join().unwrap().remove(0);
+        assert!(request.starts_with("POST /api/files/local HTTP/1.1"));
+        assert!(request.contains("X-Api-Key: secret"));
+        assert!(request.contains("name=\"select\"\r\n\r\ntrue\r\n"));
+        assert!(request.contains("name=\"print\"\r\n\r\ntrue\r\n"));
+        assert!(request.contains("filename=\"part _a_.gcode\""));
+        assert!(request.contains("\r\n\r\nG28\nG1 X10\n\r\n--"));
+        assert!(progress.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn moonraker_upload_goes_to_gcodes_root() {
+        let (url, server) = serve(vec![(201, "{}")]);
+        let host = host(HostType::Moonraker, url, "");
+
+        host.upload("part.gcode", b"G28\n".to_vec(), false, Arc::new(RwLock::new(None)))
+            .unwrap();
+
+        let request = server.join().unwrap().remove(0);
+        assert!(request.starts_with("POST /server/files/upload HTTP/1.1"));
+        assert!(!request.to_lowercase().contains("x-api-key"));
+        assert!(request.contains("name=\"root\"\r\n\r\ngcodes\r\n"));
+        assert!(request.contains("name=\"print\"\r\n\r\nfalse\r\n"));
+    }
+
+    #[test]
+    fn upload_reports_http_errors() {
+        let (url, server) = serve(vec![(401, "Invalid API key")]);
+        let progress = Arc::new(RwLock::new(None));
+        let host = host(HostType::OctoPrint, url, "wrong");
+
+        let error = host
+            .upload("part.gcode", b"G28\n".to_vec(), false, progress.clone())
+            .unwrap_err();
+
+        server.join().unwrap();
+        assert!(error.starts_with("HTTP 401"));
+        assert!(error.contains("Invalid API key"));
+        assert!(progress.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn job_commands_post_to_the_host_api() {
+        let (url, server) = serve(vec![(204, ""), (200, "{}")]);
+
+        host(HostType::OctoPrint, url.clone(), "").send_command(JobCommand::Pause).unwrap();
+        host(HostType::Moonraker, url, "").send_command(JobCommand::Cancel).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /api/job HTTP/1.1"));
+        assert!(requests[0].contains("\"action\":\"pause\""));
+        assert!(requests[1].starts_with("POST /printer/print/cancel HTTP/1.1"));
+    }
+
+    #[test]
+    fn octoprint_status_combines_printer_and_job() {
+        let (url, server) = serve(vec![
+            (
+                200,
+                r#"{"state": {"text": "Printing", "flags": {"printing": true, "paused": false}},
+                    "temperature": {"tool0": {"actual": 214.8, "target": 215.0}, "bed": {"actual": 60.1, "target": 60.0}}}"#,
+            ),
+            (
+                200,
+                r#"{"job": {"file": {"name": "part.gcode", "size": 2000}},
+                    "progress": {"completion": 25.0, "filepos": 500, "printTime": 60.0, "printTimeLeft": 180.0}}"#,
+            ),
+        ]);
+
+        let status = host(HostType::OctoPrint, url, "").get_status().unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("GET /api/printer?exclude=sd HTTP/1.1"));
+        assert!(requests[1].starts_with("GET /api/job HTTP/1.1"));
+        assert_eq!(status.state, "Printing");
+        assert_eq!(status.activity, PrinterActivity::Printing);
+        assert_eq!(status.temperatures.len(), 2);
+        assert_eq!(status.file_name.as_deref(), Some("part.gcode"));
+        assert_eq!(status.progress, Some(0.25));
+        assert_eq!(status.file_position, Some((500, 2000)));
+        assert_eq!(status.time_left, Some(180.0));
+    }
+
+    #[test]
+    fn octoprint_status_without_printer_uses_job_state() {
+        let (url, server) = serve(vec![
+            (409, "Printer is not operational"),
+            (200, r#"{"state": "Offline", "job": {"file": {"name": null}}, "progress": {}}"#),
+        ]);
+
+        let status = host(HostType::OctoPrint, url, "").get_status().unwrap();
+
+        server.join().unwrap();
+        assert_eq!(status.state, "Offline");
+        assert_eq!(status.activity, PrinterActivity::Idle);
+        assert!(status.temperatures.is_empty());
+        assert!(status.file_name.is_none());
+    }
+
+    #[test]
+    fn moonraker_status_reads_printer_objects() {
+        let (url, server) = serve(vec![(
+            200,
+            r#"{"result": {"status": {
+                "print_stats": {"state": "paused", "filename": "folder/part.gcode", "print_duration": 100.0, "info": {"current_layer": 3}},
+                "virtual_sdcard": {"progress": 0.5},
+                "extruder": {"temperature": 210.0, "target": 215.0},
+                "heater_bed": {"temperature": 60.0, "target": 60.0},
+                "toolhead": {"position": [10.0, 20.0, 0.6, 100.0]}}}}"#,
+        )]);
+
+        let status = host(HostType::Moonraker, url, "").get_status().unwrap();
+
+        server.join().unwrap();
+        assert_eq!(status.activity, PrinterActivity::Paused);
+        assert_eq!(status.file_name.as_deref(), Some("folder/part.gcode"));
+        assert_eq!(status.current_layer, Some(2));
+        assert_eq!(status.position, Some([10.0, 20.0, 0.6]));
+        assert_eq!(status.time_left, Some(100.0));
+        assert_eq!(status.temperatures.len(), 2);
+    }
+
+    #[test]
+    fn moonraker_layer_is_unknown_before_the_first() {
+        let layer = |value: Value| get_moonraker_layer(&serde_json::json!({ "info": { "current_layer": value } }));
+        assert_eq!(layer(serde_json::json!(1)), Some(0));
+        assert_eq!(layer(serde_json::json!(0)), None);
+        assert_eq!(layer(Value::Null), None);
+    }
+}
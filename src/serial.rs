@@ -0,0 +1,686 @@
+use crate::print_host::{JobCommand, PrinterActivity, Temperature};
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+//Talks to printers running Marlin style firmware over a serial port. Every line is numbered and
+//checksummed and the next one is only sent once the printer acknowledges the last with "ok".
+
+pub const BAUD_RATES: [u32; 8] = [250000, 230400, 115200, 76800, 57600, 38400, 19200, 9600];
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+
+const READ_TIMEOUT: Duration = Duration::from_millis(50);
+const TEMPERATURE_INTERVAL: Duration = Duration::from_secs(2);
+///Opening the port resets most boards, commands sent before they finish booting are lost
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+///Long moves and heating report back regularly, silence this long means an ok was lost
+const COMMUNICATION_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_CONSOLE_LINES: usize = 1000;
+///Lines kept for resends, firmware only asks for lines still in its small receive buffer
+const MAX_HISTORY_LINES: usize = 256;
+///Sent when a print is cancelled: heaters and fan off, then lift the nozzle off the print and park it
+const CANCEL_GCODE: [&str; 7] = ["M104 S0", "M140 S0", "M107", "G91", "G1 Z10", "G90", "G28 X Y"];
+
+///A line of the terminal console
+#[derive(Clone, Debug)]
+pub struct ConsoleLine {
+    ///Sent to the printer rather than received from it
+    pub sent: bool,
+    pub text: String,
+}
+
+///How far a print streamed over the connection has got
+#[derive(Copy, Clone, Debug)]
+pub struct StreamProgress {
+    pub sent: usize,
+    pub total: usize,
+    pub paused: bool,
+}
+
+impl StreamProgress {
+    pub fn get_fraction(&self) -> f32 {
+        if self.total == 0 {
+            0.0
+        } else {
+            self.sent as f32 / self.total as f32
+        }
+    }
+}
+
+///Everything the connection thread reports back to the interface
+#[derive(Debug, Default)]
+pub struct SerialState {
+    pub console: VecDeque<ConsoleLine>,
+    pub temperatures: Vec<Temperature>,
+    pub stream: Option<StreamProgress>,
+    pub connected: bool,
+    ///Why the connection was closed
+    pub error: Option<String>,
+}
+
+impl SerialState {
+    pub fn get_activity(&self) -> PrinterActivity {
+        match self.stream {
+            Some(stream) if stream.paused => PrinterActivity::Paused,
+            Some(_) => PrinterActivity::Printing,
+            None => PrinterActivity::Idle,
+        }
+    }
+
+    fn log(&mut self, sent: bool, text: &str) {
+        if self.console.len() == MAX_CONSOLE_LINES {
+            self.console.pop_front();
+        }
+        self.console.push_back(ConsoleLine {
+            sent,
+            text: text.to_string(),
+        });
+    }
+}
+
+enum SerialMessage {
+    Send(String),
+    Stream(Vec<String>),
+    Control(JobCommand),
+}
+
+///Serial port names that look like they could be a printer
+pub fn get_available_ports() -> Vec<String> {
+    serialport::available_ports()
+        .map(|ports| ports.into_iter().map(|port| port.port_name).collect())
+        .unwrap_or_default()
+}
+
+///An open connection to a printer, the port is handled on its own thread and closed when this is dropped
+pub struct SerialConnection {
+    state: Arc<RwLock<SerialState>>,
+    sender: SerialSender,
+}
+
+///Queues work on the connection thread, can be moved to other threads
+#[derive(Clone)]
+pub struct SerialSender {
+    sender: Sender<SerialMessage>,
+}
+
+impl SerialSender {
+    ///Print the G-code by sending it line by line
+    pub fn stream(&self, gcode: &str) {
+        let lines = gcode
+            .lines()
+            .map(strip_comment)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect();
+        self.sender.send(SerialMessage::Stream(lines)).ok();
+    }
+}
+
+impl SerialConnection {
+    pub fn connect(port_name: &str, baud_rate: u32) -> Result<Self, String> {
+        let port = serialport::new(port_name, baud_rate)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .map_err(|e| e.to_string())?;
+        Ok(Self::from_port(port))
+    }
+
+    ///Talk to the printer over any byte stream, such as a pseudo terminal
+    pub fn from_port<P: Read + Write + Send + 'static>(port: P) -> Self {
+        let state = Arc::new(RwLock::new(SerialState {
+            connected: true,
+            ..Default::default()
+        }));
+        let (sender, receiver) = channel();
+
+        let mut protocol = Protocol::new(port, state.clone(), receiver);
+        std::thread::spawn(move || protocol.run());
+
+        SerialConnection {
+            state,
+            sender: SerialSender { sender },
+        }
+    }
+
+    pub fn read_state(&self) -> RwLockReadGuard<'_, SerialState> {
+        self.state.read().unwrap()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state.read().unwrap().connected
+    }
+
+    ///Send a line typed into the console, queued behind the line waiting for an ok
+    pub fn send_command(&self, command: &str) {
+        let command = strip_comment(command);
+        if !command.is_empty() {
+            self.sender.sender.send(SerialMessage::Send(command.to_string())).ok();
+        }
+    }
+
+    pub fn get_sender(&self) -> SerialSender {
+        self.sender.clone()
+    }
+
+    pub fn control(&self, command: JobCommand) {
+        self.sender.sender.send(SerialMessage::Control(command)).ok();
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split(';').next().unwrap_or_default().trim()
+}
+
+fn get_checksum(line: &str) -> u8 {
+    line.bytes().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+///Read "T:210.0 /210.0 B:60.0 /60.0 @:0" style reports
+fn parse_temperatures(line: &str) -> Vec<Temperature> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let has_numbered_tools = tokens.iter().any(|token| token.starts_with("T0:"));
+
+    let mut temperatures = vec![];
+    for (i, token) in tokens.iter().enumerate() {
+        let (label, value) = match token.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let name = match label {
+            //With several tools T repeats the active one
+            "T" if has_numbered_tools => continue,
+            "T" => String::from("hotend"),
+            "B" => String::from("bed"),
+            "C" => String::from("chamber"),
+            label if label.starts_with('T') && label[1..].parse::<usize>().is_ok() => format!("hotend {}", &label[1..]),
+            _ => continue,
+        };
+
+        //The target is normally the next token but some firmware leaves out the space
+        let (actual, target) = match value.split_once('/') {
+            Some((actual, target)) => (actual, Some(target)),
+            None => (value, tokens.get(i + 1).and_then(|next| next.strip_prefix('/'))),
+        };
+
+        if let Ok(actual) = actual.parse::<f64>() {
+            temperatures.push(Temperature {
+                name,
+                actual,
+                target: target.and_then(|target| target.parse().ok()).unwrap_or_default(),
+            });
+        }
+    }
+    temperatures
+}
+
+fn parse_resend(line: &str) -> Option<usize> {
+    let rest = line.strip_prefix("Resend:").or_else(|| line.strip_prefix("rs"))?;
+    rest.trim().trim_start_matches('N').parse().ok()
+}
+
+struct Protocol<P: Read + Write> {
+    port: P,
+    state: Arc<RwLock<SerialState>>,
+    receiver: Receiver<SerialMessage>,
+    ///Bytes received that don't make a whole line yet
+    buffer: Vec<u8>,
+    ///Most recent commands sent, kept so lines can be resent
+    history: VecDeque<String>,
+    ///Line number of the first command in the history
+    history_start: usize,
+    ///Next line number to resend until the history is caught up again
+    resend_from: Option<usize>,
+    manual: VecDeque<String>,
+    stream: VecDeque<String>,
+    awaiting_ok: bool,
+    ///Whether the line waiting for an ok was typed in, its responses are only shown then
+    last_manual: bool,
+    last_received: Instant,
+    last_temperature: Instant,
+}
+
+impl<P: Read + Write> Protocol<P> {
+    fn new(port: P, state: Arc<RwLock<SerialState>>, receiver: Receiver<SerialMessage>) -> Self {
+        let now = Instant::now();
+        Protocol {
+            port,
+            state,
+            receiver,
+            buffer: vec![],
+            history: VecDeque::new(),
+            history_start: 0,
+            resend_from: None,
+            manual: VecDeque::new(),
+            stream: VecDeque::new(),
+            awaiting_ok: false,
+            last_manual: false,
+            last_received: now,
+            last_temperature: now,
+        }
+    }
+
+    fn run(&mut self) {
+        let result = self.wait_for_start().and_then(|_| self.reset_line_numbers()).and_then(|_| self.run_loop());
+
+        let mut state = self.state.write().unwrap();
+        state.connected = false;
+        state.stream = None;
+        if let Err(e) = result {
+            state.log(false, &e);
+            state.error = Some(e);
+        }
+    }
+
+    fn wait_for_start(&mut self) -> Result<(), String> {
+        let start = Instant::now();
+        while start.elapsed() < STARTUP_TIMEOUT {
+            let lines = self.read_lines()?;
+            let mut state = self.state.write().unwrap();
+            for line in &lines {
+                state.log(false, line);
+            }
+            if lines.iter().any(|line| line == "start") {
+                return Ok(());
+            }
+        }
+        //Boards that don't reset on connect never say start
+        Ok(())
+    }
+
+    fn reset_line_numbers(&mut self) -> Result<(), String> {
+        self.history.clear();
+        self.history_start = 0;
+        self.resend_from = None;
+        self.send_numbered(String::from("M110 N0"), false)
+    }
+
+    fn get_next_line_number(&self) -> usize {
+        self.history_start + self.history.len()
+    }
+
+    fn get_history_line(&self, line_number: usize) -> Option<&String> {
+        line_number
+            .checked_sub(self.history_start)
+            .and_then(|index| self.history.get(index))
+    }
+
+    fn run_loop(&mut self) -> Result<(), String> {
+        loop {
+            loop {
+                match self.receiver.try_recv() {
+                    Ok(message) => self.handle_message(message),
+                    Err(TryRecvError::Empty) => break,
+                    //The connection was dropped
+                    Err(TryRecvError::Disconnected) => return Ok(()),
+                }
+            }
+
+            if !self.awaiting_ok {
+                self.send_next()?;
+            } else if self.last_received.elapsed() > COMMUNICATION_TIMEOUT {
+                //Ask for the temperature to get things moving again
+                self.state.write().unwrap().log(false, "Communication timeout, polling the printer");
+                self.last_received = Instant::now();
+                self.send_unnumbered("M105")?;
+            }
+
+            for line in self.read_lines()? {
+                self.handle_line(&line);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: SerialMessage) {
+        let mut state = self.state.write().unwrap();
+        match message {
+            SerialMessage::Send(command) => self.manual.push_back(command),
+            SerialMessage::Stream(lines) => {
+                if state.stream.is_none() {
+                    state.stream = Some(StreamProgress {
+                        sent: 0,
+                        total: lines.len(),
+                        paused: false,
+                    });
+                    self.stream = lines.into();
+                }
+            }
+            SerialMessage::Control(command) => {
+                if let Some(stream) = state.stream.as_mut() {
+                    match command {
+                        JobCommand::Pause => stream.paused = true,
+                        JobCommand::Resume => stream.paused = false,
+                        JobCommand::Cancel => {
+                            state.stream = None;
+                            state.log(false, "Print cancelled");
+                            self.stream.clear();
+                            self.manual.extend(CANCEL_GCODE.iter().map(|line| line.to_string()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn send_next(&mut self) -> Result<(), String> {
+        if let Some(line_number) = self.resend_from {
+            if let Some(command) = self.get_history_line(line_number).cloned() {
+                self.resend_from = Some(line_number + 1);
+                return self.write_line(line_number, &command, false);
+            }
+            self.resend_from = None;
+        }
+
+        if let Some(command) = self.manual.pop_front() {
+            return self.send_numbered(command, true);
+        }
+
+        if self.last_temperature.elapsed() > TEMPERATURE_INTERVAL {
+            self.last_temperature = Instant::now();
+            return self.send_numbered(String::from("M105"), false);
+        }
+
+        let paused = self.state.read().unwrap().stream.map_or(true, |stream| stream.paused);
+        if !paused {
+            match self.stream.pop_front() {
+                Some(command) => {
+                    if let Some(stream) = self.state.write().unwrap().stream.as_mut() {
+                        stream.sent += 1;
+                    }
+                    return self.send_numbered(command, false);
+                }
+                None => {
+                    let mut state = self.state.write().unwrap();
+                    state.stream = None;
+                    state.log(false, "Print finished");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn send_numbered(&mut self, command: String, manual: bool) -> Result<(), String> {
+        let line_number = self.get_next_line_number();
+        self.history.push_back(command.clone());
+        if self.history.len() > MAX_HISTORY_LINES {
+            self.history.pop_front();
+            self.history_start += 1;
+        }
+        self.write_line(line_number, &command, manual)
+    }
+
+    fn write_line(&mut self, line_number: usize, command: &str, manual: bool) -> Result<(), String> {
+        let line = format!("N{} {}", line_number, command);
+        let line = format!("{}*{}\n", line, get_checksum(&line));
+
+        if manual {
+            self.state.write().unwrap().log(true, command);
+        }
+        self.last_manual = manual;
+        self.awaiting_ok = true;
+        self.last_received = Instant::now();
+        self.port.write_all(line.as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn send_unnumbered(&mut self, command: &str) -> Result<(), String> {
+        self.port.write_all(format!("{}\n", command).as_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn read_lines(&mut self) -> Result<Vec<String>, String> {
+        let mut chunk = [0u8; 256];
+        match self.port.read(&mut chunk) {
+            Ok(0) => return Err(String::from("The printer disconnected")),
+            Ok(read) => self.buffer.extend_from_slice(&chunk[..read]),
+            Err(e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.to_string()),
+        }
+
+        let mut lines = vec![];
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if !line.is_empty() {
+                lines.push(line);
+            }
+        }
+        if !lines.is_empty() {
+            self.last_received = Instant::now();
+        }
+        Ok(lines)
+    }
+
+    fn handle_line(&mut self, line: &str) {
+        let mut state = self.state.write().unwrap();
+
+        if line.contains("T:") {
+            let temperatures = parse_temperatures(line);
+            if !temperatures.is_empty() {
+                state.temperatures = temperatures;
+            }
+        }
+
+        if line.starts_with("ok") {
+            self.awaiting_ok = false;
+            if self.last_manual {
+                state.log(false, line);
+            }
+            return;
+        }
+
+        //Temperature reports and busy messages would drown out everything else
+        if line.starts_with("T:") || line.starts_with("echo:busy") {
+            return;
+        }
+        state.log(false, line);
+
+        if let Some(line_number) = parse_resend(line) {
+            if self.get_history_line(line_number).is_some() {
+                self.resend_from = Some(line_number);
+            } else {
+                state.log(false, "The printer asked for a line that is no longer kept, it can't be resent");
+            }
+        } else if line == "start" {
+            //The board reset so the print can't carry on
+            state.stream = None;
+            self.stream.clear();
+            self.awaiting_ok = false;
+            self.history.clear();
+            self.history_start = 0;
+            self.history.push_back(String::from("M110 N0"));
+            self.resend_from = Some(0);
+        } else if line.starts_with("Error:") && (line.contains("halted") || line.contains("kill")) {
+            state.stream = None;
+            self.stream.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    ///Stands in for the printer end of the port, written lines are collected and replies read back
+    #[derive(Clone, Default)]
+    struct FakePort {
+        input: Arc<Mutex<VecDeque<u8>>>,
+        output: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl FakePort {
+        fn reply(&self, text: &str) {
+            self.input.lock().unwrap().extend(text.bytes());
+        }
+
+        fn get_sent(&self) -> Vec<String> {
+            String::from_utf8_lossy(&self.output.lock().unwrap())
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+
+        ///Wait for the connection to have written the number of lines
+        fn wait_for_sent(&self, count: usize) -> Vec<String> {
+            wait_until(|| self.get_sent().len() >= count);
+            self.get_sent()
+        }
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let mut input = self.input.lock().unwrap();
+            if input.is_empty() {
+                drop(input);
+                std::thread::sleep(Duration::from_millis(1));
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let read = buf.len().min(input.len());
+            for (byte, value) in buf.iter_mut().zip(input.drain(..read)) {
+                *byte = value;
+            }
+            Ok(read)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(1), "timed out waiting for the connection");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn connect() -> (FakePort, SerialConnection) {
+        let port = FakePort::default();
+        port.reply("start\n");
+        let connection = SerialConnection::from_port(port.clone());
+        assert_eq!(port.wait_for_sent(1), vec!["N0 M110 N0*125"]);
+        (port, connection)
+    }
+
+    #[test]
+    fn lines_are_numbered_with_checksums() {
+        assert_eq!(get_checksum("N0 M110 N0"), 125);
+        assert_eq!(get_checksum("N1 G28"), 18);
+
+        let (port, connection) = connect();
+        connection.get_sender().stream("G28 ; home\n\n; comment only\nG1 X10\n");
+        port.reply("ok\n");
+        assert_eq!(port.wait_for_sent(2)[1], "N1 G28*18");
+
+        port.reply("ok\n");
+        assert_eq!(port.wait_for_sent(3)[2], "N2 G1 X10*83");
+        assert_eq!(connection.read_state().stream.unwrap().total, 2);
+
+        port.reply("ok\n");
+        wait_until(|| connection.read_state().stream.is_none());
+        assert_eq!(port.get_sent().len(), 3);
+    }
+
+    #[test]
+    fn cancel_stops_the_print_and_parks_the_head() {
+        let (port, connection) = connect();
+        connection.get_sender().stream("G28\nG1 X10\nG1 X20\n");
+        port.reply("ok\n");
+        port.wait_for_sent(2);
+
+        connection.control(JobCommand::Cancel);
+        wait_until(|| connection.read_state().stream.is_none());
+        for i in 0..CANCEL_GCODE.len() {
+            port.reply("ok\n");
+            port.wait_for_sent(3 + i);
+        }
+
+        let sent = port.get_sent();
+        let expected: Vec<String> = CANCEL_GCODE
+            .iter()
+            .enumerate()
+            .map(|(i, command)| {
+                let line = format!("N{} {}", i + 2, command);
+                format!("{}*{}", line, get_checksum(&line))
+            })
+            .collect();
+        assert_eq!(sent[2..], expected[..]);
+    }
+
+    #[test]
+    fn resend_repeats_from_the_asked_line() {
+        let (port, connection) = connect();
+        connection.get_sender().stream("G28\nG1 X10\n");
+        port.reply("ok\n");
+        port.wait_for_sent(2);
+
+        port.reply("Resend: 1\nok\n");
+        assert_eq!(port.wait_for_sent(3)[2], "N1 G28*18");
+        port.reply("ok\n");
+        assert_eq!(port.wait_for_sent(4)[3], "N2 G1 X10*83");
+    }
+
+    #[test]
+    fn board_reset_stops_the_print_and_restarts_numbering() {
+        let (port, connection) = connect();
+        connection.get_sender().stream("G28\nG1 X10\n");
+        port.reply("ok\n");
+        port.wait_for_sent(2);
+
+        port.reply("start\n");
+        assert_eq!(port.wait_for_sent(3)[2], "N0 M110 N0*125");
+        assert!(connection.read_state().stream.is_none());
+
+        //Nothing of the print is sent after the reset
+        port.reply("ok\n");
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(port.get_sent().len(), 3);
+    }
+
+    #[test]
+    fn temperatures_are_read_from_reports() {
+        let temperatures = parse_temperatures("ok T:210.5 /215.0 B:60.0 /60.0 @:0 B@:0");
+        let values: Vec<(&str, f64, f64)> = temperatures
+            .iter()
+            .map(|t| (t.name.as_str(), t.actual, t.target))
+            .collect();
+        assert_eq!(values, vec![("hotend", 210.5, 215.0), ("bed", 60.0, 60.0)]);
+
+        let temperatures = parse_temperatures("T:200.0/205.0 T0:200.0/205.0 T1:25.0/0.0 B:50.0 /60.0");
+        let names: Vec<&str> = temperatures.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["hotend 0", "hotend 1", "bed"]);
+        assert_eq!(temperatures[1].target, 0.0);
+
+        let (port, connection) = connect();
+        port.reply("ok T:180.0 /200.0 B:40.0 /60.0\n");
+        wait_until(|| !connection.read_state().temperatures.is_empty());
+        assert_eq!(connection.read_state().temperatures[0].actual, 180.0);
+    }
+
+    #[test]
+    fn history_only_keeps_recent_lines() {
+        let (_sender, receiver) = channel();
+        let mut protocol = Protocol::new(FakePort::default(), Arc::new(RwLock::new(SerialState::default())), receiver);
+        for _ in 0..MAX_HISTORY_LINES + 10 {
+            protocol.send_numbered(String::from("G1 X1"), false).unwrap();
+        }
+
+        assert_eq!(protocol.history.len(), MAX_HISTORY_LINES);
+        assert_eq!(protocol.get_next_line_number(), MAX_HISTORY_LINES + 10);
+        assert!(protocol.get_history_line(9).is_none());
+        assert!(protocol.get_history_line(10).is_some());
+
+        protocol.handle_line("Resend: 5");
+        assert_eq!(protocol.resend_from, None);
+        protocol.handle_line("Resend: 20");
+        assert_eq!(protocol.resend_from, Some(20));
+    }
+}
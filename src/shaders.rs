@@ -62,3 +62,57 @@ pub const LINE_FRAGMENT_SHADER_SRC: &str = r#"
 
         }
     "#;
+
+pub const TOOLPATH_VERTEX_SHADER_SRC: &str = r#"
+        #version 150
+        in vec3 position;
+        in vec3 normal;
+        in vec3 color;
+        out vec3 v_normal;
+        out vec3 v_color;
+        uniform mat4 perspective;
+        uniform mat4 view;
+        uniform mat4 model;
+        void main() {
+            mat4 modelview = view * model;
+            gl_Position = perspective * modelview * vec4(position, 1.0);
+            v_normal = normal;
+            v_color = color;
+        }
+    "#;
+
+pub const TOOLPATH_FRAGMENT_SHADER_SRC: &str = r#"
+        #version 150
+        in vec3 v_normal;
+        in vec3 v_color;
+        out vec4 color;
+        void main() {
+            vec3 light_dir = normalize(vec3(0.3, 0.2, 1.0));
+
+            float diffuse = abs(dot(normalize(v_normal), light_dir));
+            color = vec4(v_color * (0.3 + 0.7 * diffuse), 1.0);
+        }
+    "#;
+
+pub const OVERLAY_VERTEX_SHADER_SRC: &str = r#"
+        #version 150
+        in vec3 position;
+        uniform mat4 perspective;
+        uniform mat4 view;
+        uniform mat4 model;
+        void main() {
+            mat4 modelview = view * model;
+            gl_Position = perspective * modelview * vec4(position, 1.0);
+        }
+    "#;
+
+pub const OVERLAY_FRAGMENT_SHADER_SRC: &str = r#"
+        #version 140
+        out vec4 out_color;
+        uniform vec3 color;
+        void main() {
+
+            out_color = vec4(color, 1.0);
+
+        }
+    "#;
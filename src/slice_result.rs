@@ -0,0 +1,89 @@
+use crate::gcode_parser::{parse_gcode, GCodeStatistics};
+use crate::gcode_text::GCodeLines;
+use crate::materials::MaterialProfile;
+use crate::toolpath::{build_layers, split_layers, FeatureSpeeds, LayerMoves, ToolpathLayer};
+use crate::statistics::{get_volume_by_object, LayerStatistics};
+use gladius_shared::types::{CalculatedValues, Command};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+///Output of a single slice or an opened G-code file
+#[derive(Clone, Default)]
+pub struct SliceResult {
+    pub calc_vals: Option<CalculatedValues>,
+    pub gcode: Option<Arc<String>>,
+    ///Line index of the G-code for the text viewer
+    pub gcode_lines: Option<Arc<GCodeLines>>,
+    ///Statistics estimated while parsing, only set for opened G-code files
+    pub gcode_statistics: Option<GCodeStatistics>,
+    pub commands: Option<Arc<Vec<Command>>>,
+    pub layers: Option<Arc<Vec<LayerMoves>>>,
+    ///Geometry of the 3D toolpath, only uploaded by the main thread
+    pub toolpath_layers: Option<Arc<Vec<ToolpathLayer>>>,
+    ///Volume of plastic in mm^3 used by each object
+    pub object_volumes: Option<BTreeMap<Option<usize>, f64>>,
+    pub layer_statistics: Option<Arc<LayerStatistics>>,
+}
+
+impl SliceResult {
+    ///Result of an opened G-code file, its statistics estimated for the given material
+    pub fn from_gcode(gcode: String, material: &MaterialProfile, feature_speeds: &FeatureSpeeds) -> Self {
+        let parsed = parse_gcode(&gcode, material);
+
+        let mut result = SliceResult {
+            gcode_lines: Some(Arc::new(GCodeLines::new(&gcode, parsed.command_lines))),
+            gcode: Some(Arc::new(gcode)),
+            gcode_statistics: Some(parsed.statistics),
+            ..Default::default()
+        };
+        let layers = split_layers(&parsed.commands, feature_speeds);
+        result.set_commands(parsed.commands, layers);
+        result
+    }
+
+    ///Set the commands along with everything derived from them.
+    ///This builds the toolpath geometry so it should be called off the main thread
+    pub fn set_commands(&mut self, commands: Vec<Command>, layers: Vec<LayerMoves>) {
+        self.toolpath_layers = Some(Arc::new(build_layers(&layers)));
+        self.object_volumes = Some(get_volume_by_object(&layers));
+        self.layer_statistics = Some(Arc::new(LayerStatistics::new(&layers)));
+        self.layers = Some(Arc::new(layers));
+        self.commands = Some(Arc::new(commands));
+        self.index_gcode_lines();
+    }
+
+    ///Set G-code written by the slicer
+    pub fn set_gcode(&mut self, gcode: String) {
+        self.gcode = Some(Arc::new(gcode));
+        self.index_gcode_lines();
+    }
+
+    ///Map the commands to G-code lines once both have arrived from the slicer
+    fn index_gcode_lines(&mut self) {
+        if let (Some(gcode), Some(commands), None) = (&self.gcode, &self.commands, &self.gcode_lines) {
+            self.gcode_lines = Some(Arc::new(GCodeLines::from_commands(gcode, commands)));
+        }
+    }
+
+    ///Total volume of plastic in mm^3 extruded by the commands
+    pub fn get_extrusion_volume(&self) -> Option<f64> {
+        self.object_volumes
+            .as_ref()
+            .map(|volumes| volumes.values().sum())
+    }
+
+    pub fn get_plastic_weight(&self) -> Option<f64> {
+        self.calc_vals
+            .as_ref()
+            .map(|cv| cv.plastic_weight)
+            .or_else(|| self.gcode_statistics.map(|stats| stats.plastic_weight))
+    }
+
+    ///Print time in seconds, the same estimate the side panel shows
+    pub fn get_print_time(&self) -> Option<f64> {
+        self.calc_vals
+            .as_ref()
+            .map(|cv| cv.total_time)
+            .or_else(|| self.gcode_statistics.map(|stats| stats.total_time))
+    }
+}
@@ -0,0 +1,102 @@
+use crate::toolpath::{ExtrusionMove, LayerMoves};
+use std::collections::BTreeMap;
+
+///Per layer values derived from the command stream that can be charted across the print
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LayerStatistic {
+    PrintTime,
+    ExtrusionVolume,
+    TravelDistance,
+    MaxSpeed,
+}
+
+impl LayerStatistic {
+    pub const ALL: [LayerStatistic; 4] = [
+        LayerStatistic::PrintTime,
+        LayerStatistic::ExtrusionVolume,
+        LayerStatistic::TravelDistance,
+        LayerStatistic::MaxSpeed,
+    ];
+
+    pub fn get_translation_key(&self) -> &'static str {
+        match self {
+            LayerStatistic::PrintTime => "stat_print_time",
+            LayerStatistic::ExtrusionVolume => "stat_extrusion_volume",
+            LayerStatistic::TravelDistance => "stat_travel_distance",
+            LayerStatistic::MaxSpeed => "stat_max_speed",
+        }
+    }
+
+    pub fn get_unit(&self) -> &'static str {
+        match self {
+            LayerStatistic::PrintTime => "s",
+            LayerStatistic::ExtrusionVolume => "mm^3",
+            LayerStatistic::TravelDistance => "mm",
+            LayerStatistic::MaxSpeed => "mm/s",
+        }
+    }
+
+    pub fn get_value(&self, layer: &LayerMoves) -> f64 {
+        match self {
+            LayerStatistic::PrintTime => layer.time,
+            LayerStatistic::ExtrusionVolume => layer
+                .extrusions
+                .iter()
+                .map(get_extrusion_volume)
+                .sum(),
+            LayerStatistic::TravelDistance => layer
+                .travels
+                .iter()
+                .map(|(start, end)| start.distance(*end) as f64)
+                .sum(),
+            LayerStatistic::MaxSpeed => layer
+                .moves
+                .iter()
+                .map(|m| m.speed)
+                .fold(0.0, f64::max),
+        }
+    }
+
+    pub fn get_values(&self, layers: &[LayerMoves]) -> Vec<f64> {
+        layers.iter().map(|layer| self.get_value(layer)).collect()
+    }
+}
+
+///Every statistic for each layer, computed once per result rather than every time they are charted
+#[derive(Clone, Debug, Default)]
+pub struct LayerStatistics {
+    values: Vec<Vec<f64>>,
+}
+
+impl LayerStatistics {
+    pub fn new(layers: &[LayerMoves]) -> Self {
+        LayerStatistics {
+            values: LayerStatistic::ALL
+                .iter()
+                .map(|stat| stat.get_values(layers))
+                .collect(),
+        }
+    }
+
+    pub fn get_values(&self, stat: LayerStatistic) -> &[f64] {
+        LayerStatistic::ALL
+            .iter()
+            .position(|s| *s == stat)
+            .and_then(|index| self.values.get(index))
+            .map_or(&[], |values| values.as_slice())
+    }
+}
+
+///Volume of plastic in mm^3 deposited by an extrusion
+pub fn get_extrusion_volume(extrusion: &ExtrusionMove) -> f64 {
+    (extrusion.start.distance(extrusion.end) * extrusion.width * extrusion.thickness) as f64
+}
+
+///Volume of plastic in mm^3 for each object, None holds extrusions without an object marker
+pub fn get_volume_by_object(layers: &[LayerMoves]) -> BTreeMap<Option<usize>, f64> {
+    let mut volumes = BTreeMap::new();
+    for extrusion in layers.iter().flat_map(|layer| layer.extrusions.iter()) {
+        *volumes.entry(extrusion.state.object).or_insert(0.0) += get_extrusion_volume(extrusion);
+    }
+    volumes
+}
@@ -0,0 +1,309 @@
+use crate::object::Object;
+use crate::shaders::{FRAGMENT_SHADER_SRC, VERTEX_SHADER_SRC};
+use glam::{Mat4, Vec3, Vec4Swizzles};
+use glium::framebuffer::{DepthRenderBuffer, SimpleFrameBuffer};
+use glium::texture::{DepthFormat, MipmapsOption, RawImage2d, UncompressedFloatFormat};
+use glium::{uniform, Surface, Texture2d};
+
+///Images are rendered this many times larger in each direction and averaged down to smooth edges
+const SUPERSAMPLING: u32 = 2;
+
+///Length of the base64 lines in the comment block, matching other slicers
+const BASE64_LINE_LENGTH: usize = 78;
+
+const FIELD_OF_VIEW: f32 = 60.0;
+
+///Triangles of an object in print space, used by the software renderer
+pub struct ThumbnailMesh {
+    pub triangles: Vec<[Vec3; 3]>,
+    pub color: Vec3,
+}
+
+///A PNG encoded preview of the plate
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    pub png: Vec<u8>,
+}
+
+pub fn get_bounds(meshes: &[ThumbnailMesh]) -> Option<(Vec3, Vec3)> {
+    meshes
+        .iter()
+        .flat_map(|mesh| mesh.triangles.iter().flatten())
+        .fold(None, |bounds, point| match bounds {
+            Some((min, max)) => Some((point.min(min), point.max(max))),
+            None => Some((*point, *point)),
+        })
+}
+
+///View and projection looking at the bounds from the same angle as the default camera
+fn get_camera(bounds: (Vec3, Vec3), aspect_ratio: f32) -> (Mat4, Mat4) {
+    let (min, max) = bounds;
+    let center = (min + max) / 2.0;
+    let radius = ((max - min).length() / 2.0).max(1.0);
+
+    let pitch = std::f32::consts::FRAC_PI_4;
+    let yaw = -std::f32::consts::FRAC_PI_4 + 0.12;
+    let direction = Vec3::new(yaw.cos() * pitch.cos(), yaw.sin() * pitch.cos(), pitch.sin());
+
+    //Fit the bounding sphere in the narrower of the two fields of view
+    let half_fov = FIELD_OF_VIEW.to_radians() / 2.0;
+    let half_fov = half_fov.min((half_fov.tan() * aspect_ratio).atan());
+    let distance = radius / half_fov.sin();
+
+    let view = Mat4::look_at_rh(center + direction * distance, center, Vec3::Z);
+    let proj = Mat4::perspective_rh_gl(
+        FIELD_OF_VIEW.to_radians(),
+        aspect_ratio,
+        distance * 0.01,
+        distance * 4.0,
+    );
+    (view, proj)
+}
+
+///Same lighting as the model shader with the light at the camera
+fn shade(color: Vec3, normal: Vec3) -> [u8; 4] {
+    let diffuse = normal.dot(Vec3::Z).abs();
+    let specular = diffuse.powf(128.0);
+    let specular_color = Vec3::ONE - (Vec3::ONE - color) * 0.2;
+    let shaded = (color * 0.2 + color * diffuse + specular_color * specular).min(Vec3::ONE);
+
+    [
+        (shaded.x * 255.0) as u8,
+        (shaded.y * 255.0) as u8,
+        (shaded.z * 255.0) as u8,
+        255,
+    ]
+}
+
+///Rasterize the meshes on the CPU, for when there is no GPU or window. Returns RGBA rows from the top.
+pub fn render_software(meshes: &[ThumbnailMesh], bounds: (Vec3, Vec3), width: u32, height: u32) -> Vec<u8> {
+    let (render_width, render_height) = (width * SUPERSAMPLING, height * SUPERSAMPLING);
+    let (view, proj) = get_camera(bounds, width as f32 / height as f32);
+
+    let mut pixels = vec![0u8; (render_width * render_height * 4) as usize];
+    let mut depth = vec![f32::INFINITY; (render_width * render_height) as usize];
+
+    for mesh in meshes {
+        for triangle in &mesh.triangles {
+            let view_points = triangle.map(|p| view.transform_point3(p));
+            let normal = (view_points[1] - view_points[0])
+                .cross(view_points[2] - view_points[0])
+                .normalize_or_zero();
+            let color = shade(mesh.color, normal);
+
+            let clip = view_points.map(|p| proj * p.extend(1.0));
+            if clip.iter().any(|p| p.w <= 0.0) {
+                continue;
+            }
+            let screen = clip.map(|p| {
+                let ndc = p.xyz() / p.w;
+                Vec3::new(
+                    (ndc.x + 1.0) / 2.0 * render_width as f32,
+                    (1.0 - ndc.y) / 2.0 * render_height as f32,
+                    ndc.z,
+                )
+            });
+
+            let area = edge(screen[0], screen[1], screen[2]);
+            if area.abs() < f32::EPSILON {
+                continue;
+            }
+
+            let min_x = screen.iter().map(|p| p.x).fold(f32::INFINITY, f32::min).max(0.0) as u32;
+            let max_x = screen.iter().map(|p| p.x).fold(f32::NEG_INFINITY, f32::max).min(render_width as f32 - 1.0);
+            let min_y = screen.iter().map(|p| p.y).fold(f32::INFINITY, f32::min).max(0.0) as u32;
+            let max_y = screen.iter().map(|p| p.y).fold(f32::NEG_INFINITY, f32::max).min(render_height as f32 - 1.0);
+            if max_x < 0.0 || max_y < 0.0 {
+                continue;
+            }
+
+            for y in min_y..=max_y as u32 {
+                for x in min_x..=max_x as u32 {
+                    let p = Vec3::new(x as f32 + 0.5, y as f32 + 0.5, 0.0);
+                    let w0 = edge(screen[1], screen[2], p) / area;
+                    let w1 = edge(screen[2], screen[0], p) / area;
+                    let w2 = edge(screen[0], screen[1], p) / area;
+                    if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                        continue;
+                    }
+
+                    let z = w0 * screen[0].z + w1 * screen[1].z + w2 * screen[2].z;
+                    let index = (y * render_width + x) as usize;
+                    if z < depth[index] {
+                        depth[index] = z;
+                        pixels[index * 4..index * 4 + 4].copy_from_slice(&color);
+                    }
+                }
+            }
+        }
+    }
+
+    downsample(&pixels, width, height)
+}
+
+fn edge(a: Vec3, b: Vec3, p: Vec3) -> f32 {
+    (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x)
+}
+
+///Render the objects offscreen with the same shaders as the scene. Returns RGBA rows from the top.
+pub fn render_gpu(display: &glium::Display, objects: &[Object], bounds: (Vec3, Vec3), width: u32, height: u32) -> Option<Vec<u8>> {
+    let (render_width, render_height) = (width * SUPERSAMPLING, height * SUPERSAMPLING);
+    let (view, proj) = get_camera(bounds, width as f32 / height as f32);
+
+    let program = glium::Program::from_source(display, VERTEX_SHADER_SRC, FRAGMENT_SHADER_SRC, None).ok()?;
+    let texture = Texture2d::empty_with_format(display, UncompressedFloatFormat::U8U8U8U8, MipmapsOption::NoMipmap, render_width, render_height).ok()?;
+    let depth = DepthRenderBuffer::new(display, DepthFormat::I24, render_width, render_height).ok()?;
+    let mut target = SimpleFrameBuffer::with_depth_buffer(display, &texture, &depth).ok()?;
+
+    target.clear_color_and_depth((0.0, 0.0, 0.0, 0.0), 1.0);
+
+    let params = glium::DrawParameters {
+        depth: glium::Depth {
+            test: glium::draw_parameters::DepthTest::IfLess,
+            write: true,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let (view, perspective) = (view.to_cols_array_2d(), proj.to_cols_array_2d());
+    for obj in objects {
+        let model = obj.mesh.get_model_matrix().to_cols_array_2d();
+        let color = obj.color.to_array();
+        target
+            .draw(&obj.render.vert_buff, &obj.render.index_buff, &program, &uniform! {color: color, model: model, view: view, perspective: perspective}, &params)
+            .ok()?;
+    }
+
+    let image: RawImage2d<u8> = texture.read();
+
+    //OpenGL reads from the bottom row up
+    let row_length = (render_width * 4) as usize;
+    let pixels: Vec<u8> = image
+        .data
+        .chunks(row_length)
+        .rev()
+        .flatten()
+        .copied()
+        .collect();
+
+    Some(downsample(&pixels, width, height))
+}
+
+///Average each block of supersampled pixels
+fn downsample(pixels: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let render_width = width * SUPERSAMPLING;
+    let samples = SUPERSAMPLING * SUPERSAMPLING;
+    let mut output = Vec::with_capacity((width * height * 4) as usize);
+
+    for y in 0..height {
+        for x in 0..width {
+            for channel in 0..4 {
+                let sum: u32 = (0..SUPERSAMPLING)
+                    .flat_map(|dy| (0..SUPERSAMPLING).map(move |dx| (dx, dy)))
+                    .map(|(dx, dy)| {
+                        let index = ((y * SUPERSAMPLING + dy) * render_width + x * SUPERSAMPLING + dx) * 4 + channel;
+                        pixels[index as usize] as u32
+                    })
+                    .sum();
+                output.push((sum / samples) as u8);
+            }
+        }
+    }
+    output
+}
+
+pub fn encode_png(pixels: &[u8], width: u32, height: u32) -> Option<Vec<u8>> {
+    let mut png = vec![];
+    {
+        let mut encoder = png::Encoder::new(&mut png, width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().ok()?;
+        writer.write_image_data(pixels).ok()?;
+    }
+    Some(png)
+}
+
+///Comment blocks in the format printers and print hosts read previews from
+pub fn format_thumbnails(thumbnails: &[Thumbnail]) -> String {
+    let mut output = String::new();
+    for thumbnail in thumbnails {
+        let encoded = base64::encode(&thumbnail.png);
+        output += &format!("; thumbnail begin {}x{} {}\n", thumbnail.width, thumbnail.height, encoded.len());
+        //base64 is ascii so splitting the bytes keeps whole characters
+        for line in encoded.as_bytes().chunks(BASE64_LINE_LENGTH) {
+            output += "; ";
+            output += std::str::from_utf8(line).unwrap_or_default();
+            output.push('\n');
+        }
+        output += "; thumbnail end\n;\n";
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::tests::{get_box_corners, BOX_INDICES};
+
+    fn get_cube() -> ThumbnailMesh {
+        let corners = get_box_corners(Vec3::splat(10.0));
+        ThumbnailMesh {
+            triangles: BOX_INDICES
+                .chunks_exact(3)
+                .map(|tri| [corners[tri[0] as usize], corners[tri[1] as usize], corners[tri[2] as usize]])
+                .collect(),
+            color: Vec3::new(1.0, 0.5, 0.0),
+        }
+    }
+
+    fn get_alpha(pixels: &[u8], width: u32, x: u32, y: u32) -> u8 {
+        pixels[((y * width + x) * 4 + 3) as usize]
+    }
+
+    #[test]
+    fn software_render_draws_the_model_in_the_middle() {
+        let meshes = vec![get_cube()];
+        let bounds = get_bounds(&meshes).unwrap();
+        let (width, height) = (32, 24);
+        let pixels = render_software(&meshes, bounds, width, height);
+
+        assert_eq!(pixels.len(), (width * height * 4) as usize);
+        assert_eq!(get_alpha(&pixels, width, width / 2, height / 2), 255);
+        assert_eq!(get_alpha(&pixels, width, 0, 0), 0);
+        assert_eq!(get_alpha(&pixels, width, width - 1, height - 1), 0);
+
+        //Lit faces are shaded from the mesh color
+        let center = ((height / 2 * width + width / 2) * 4) as usize;
+        let [r, g, b] = [pixels[center], pixels[center + 1], pixels[center + 2]];
+        assert!(r > g && g > b);
+    }
+
+    #[test]
+    fn thumbnails_are_written_as_comment_blocks() {
+        let pixels = render_software(&[get_cube()], get_bounds(&[get_cube()]).unwrap(), 48, 48);
+        let png = encode_png(&pixels, 48, 48).unwrap();
+        let encoded = base64::encode(&png);
+        assert!(encoded.len() > BASE64_LINE_LENGTH);
+
+        let thumbnails = vec![
+            Thumbnail { width: 48, height: 48, png: png.clone() },
+            Thumbnail { width: 16, height: 8, png: vec![1, 2, 3] },
+        ];
+        let output = format_thumbnails(&thumbnails);
+        let lines: Vec<&str> = output.lines().collect();
+
+        assert_eq!(lines[0], format!("; thumbnail begin 48x48 {}", encoded.len()));
+        let end = lines.iter().position(|line| *line == "; thumbnail end").unwrap();
+        let data = &lines[1..end];
+        assert!(data.iter().all(|line| line.starts_with("; ") && line.len() <= BASE64_LINE_LENGTH + 2));
+        assert!(data[..data.len() - 1].iter().all(|line| line.len() == BASE64_LINE_LENGTH + 2));
+        let joined: String = data.iter().map(|line| &line[2..]).collect();
+        assert_eq!(base64::decode(&joined).unwrap(), png);
+
+        assert_eq!(lines[end + 1], ";");
+        assert_eq!(&lines[end + 2..], ["; thumbnail begin 16x8 4", "; AQID", "; thumbnail end", ";"]);
+    }
+}
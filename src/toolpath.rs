@@ -0,0 +1,770 @@
+use crate::color::ColorMap;
+use crate::object::DisplayVertex;
+use egui::Color32;
+use gladius_shared::types::Command;
+use glam::{Vec2, Vec3};
+use glium::implement_vertex;
+
+#[derive(Copy, Clone, Debug)]
+pub struct ToolpathVertex {
+    pub position: (f32, f32, f32),
+    pub normal: (f32, f32, f32),
+}
+
+implement_vertex!(ToolpathVertex, position, normal);
+
+///Color of a toolpath vertex, kept in its own buffer so the color scheme can change without
+///rebuilding the geometry
+#[derive(Copy, Clone, Debug)]
+pub struct ToolpathColor {
+    pub color: (f32, f32, f32),
+}
+
+implement_vertex!(ToolpathColor, color);
+
+///Part of the print an extrusion belongs to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FeatureType {
+    OuterPerimeter,
+    InnerPerimeter,
+    TopSolidInfill,
+    SolidInfill,
+    Infill,
+    Bridge,
+    Support,
+    ///Extrusions whose speed matches no feature or several features in the settings
+    Other,
+}
+
+impl Default for FeatureType {
+    fn default() -> Self {
+        FeatureType::Other
+    }
+}
+
+impl FeatureType {
+    pub const ALL: [FeatureType; 8] = [
+        FeatureType::OuterPerimeter,
+        FeatureType::InnerPerimeter,
+        FeatureType::TopSolidInfill,
+        FeatureType::SolidInfill,
+        FeatureType::Infill,
+        FeatureType::Bridge,
+        FeatureType::Support,
+        FeatureType::Other,
+    ];
+
+    pub fn get_translation_key(&self) -> &'static str {
+        match self {
+            FeatureType::OuterPerimeter => "feature_outer_perimeter",
+            FeatureType::InnerPerimeter => "feature_inner_perimeter",
+            FeatureType::TopSolidInfill => "feature_top_solid_infill",
+            FeatureType::SolidInfill => "feature_solid_infill",
+            FeatureType::Infill => "feature_infill",
+            FeatureType::Bridge => "feature_bridge",
+            FeatureType::Support => "feature_support",
+            FeatureType::Other => "feature_other",
+        }
+    }
+
+    ///Key of the speed of this feature in the speed section of the settings
+    fn get_setting_keys(&self) -> &'static [&'static str] {
+        match self {
+            FeatureType::OuterPerimeter => &["exterior_surface_perimeter", "interior_surface_perimeter"],
+            FeatureType::InnerPerimeter => &["exterior_inner_perimeter", "interior_inner_perimeter"],
+            FeatureType::TopSolidInfill => &["solid_top_infill"],
+            FeatureType::SolidInfill => &["solid_infill"],
+            FeatureType::Infill => &["infill"],
+            FeatureType::Bridge => &["bridge"],
+            FeatureType::Support => &["support"],
+            FeatureType::Other => &[],
+        }
+    }
+}
+
+///Speed the slicer prints each feature at. The commands don't name the feature being printed,
+///but the slicer sets the speed of the feature every time it switches to another one, so the
+///feature is estimated from the speed. Slowed down layers and features sharing a speed can't be
+///told apart this way and are shown as Other.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureSpeeds {
+    speeds: Vec<(f64, FeatureType)>,
+}
+
+impl FeatureSpeeds {
+    ///Read the speeds from the settings
+    pub fn from_settings(settings: &serde_json::Value) -> Self {
+        let speed = &settings["speed"];
+        FeatureSpeeds {
+            speeds: FeatureType::ALL
+                .iter()
+                .flat_map(|feature| {
+                    feature
+                        .get_setting_keys()
+                        .iter()
+                        .filter_map(move |key| speed[*key].as_f64().map(|speed| (speed, *feature)))
+                })
+                .collect(),
+        }
+    }
+
+    ///Speeds of the settings file, none if it can't be read
+    pub fn from_settings_file(path: &str) -> Self {
+        std::fs::File::open(path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(std::io::BufReader::new(file)).ok())
+            .map(|settings| Self::from_settings(&settings))
+            .unwrap_or_default()
+    }
+
+    ///The only feature printed at this speed, Other if there is none or more than one
+    pub fn get_feature(&self, speed: f64) -> FeatureType {
+        let mut matches = self
+            .speeds
+            .iter()
+            .filter(|(feature_speed, _)| (feature_speed - speed).abs() < SPEED_TOLERANCE)
+            .map(|(_, feature)| *feature);
+
+        match matches.next() {
+            Some(feature) if matches.all(|other| other == feature) => feature,
+            _ => FeatureType::Other,
+        }
+    }
+}
+
+const SPEED_TOLERANCE: f64 = 0.000_001;
+
+///Printer state in effect while a move is performed
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MoveState {
+    pub speed: f64,
+    pub feature: FeatureType,
+    pub fan_speed: f64,
+    pub temperature: f64,
+    ///Index of the object being printed if the slicer marked it
+    pub object: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ExtrusionMove {
+    pub command_index: usize,
+    pub start: Vec2,
+    pub end: Vec2,
+    pub width: f32,
+    pub thickness: f32,
+    pub state: MoveState,
+}
+
+///Non extrusion features of the toolpath that can be shown on top of the extrusions
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Overlay {
+    Travel,
+    Retraction,
+    Unretraction,
+    ZHop,
+    Seam,
+}
+
+impl Overlay {
+    pub const ALL: [Overlay; 5] = [
+        Overlay::Travel,
+        Overlay::Retraction,
+        Overlay::Unretraction,
+        Overlay::ZHop,
+        Overlay::Seam,
+    ];
+
+    pub fn get_translation_key(&self) -> &'static str {
+        match self {
+            Overlay::Travel => "overlay_travel",
+            Overlay::Retraction => "overlay_retraction",
+            Overlay::Unretraction => "overlay_unretraction",
+            Overlay::ZHop => "overlay_z_hop",
+            Overlay::Seam => "overlay_seam",
+        }
+    }
+
+    pub fn get_color(&self) -> Color32 {
+        match self {
+            Overlay::Travel => Color32::from_rgb(120, 120, 255),
+            Overlay::Retraction => Color32::from_rgb(255, 40, 40),
+            Overlay::Unretraction => Color32::from_rgb(40, 255, 40),
+            Overlay::ZHop => Color32::from_rgb(255, 0, 255),
+            Overlay::Seam => Color32::from_rgb(255, 255, 255),
+        }
+    }
+}
+
+///Which overlays are drawn in the previews
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OverlayVisibility {
+    pub travel: bool,
+    pub retractions: bool,
+    pub z_hops: bool,
+    pub seams: bool,
+}
+
+impl OverlayVisibility {
+    pub fn is_visible(&self, overlay: Overlay) -> bool {
+        match overlay {
+            Overlay::Travel => self.travel,
+            Overlay::Retraction | Overlay::Unretraction => self.retractions,
+            Overlay::ZHop => self.z_hops,
+            Overlay::Seam => self.seams,
+        }
+    }
+}
+
+///A travel or extrusion in print order, used to play back a layer
+#[derive(Clone, Debug)]
+pub struct ToolpathMove {
+    pub command_index: usize,
+    pub start: Vec2,
+    pub end: Vec2,
+    ///None for travel moves
+    pub width: Option<f32>,
+    pub speed: f64,
+    ///Time since the start of the layer at which this move starts
+    pub start_time: f64,
+    pub duration: f64,
+}
+
+///All moves of a single layer along with the time it takes to print it
+#[derive(Clone, Debug)]
+pub struct LayerMoves {
+    pub z: f32,
+    pub moves: Vec<ToolpathMove>,
+    pub extrusions: Vec<ExtrusionMove>,
+    pub travels: Vec<(Vec2, Vec2)>,
+    pub markers: Vec<(Overlay, Vec2)>,
+    pub time: f64,
+    ///Minimum and maximum corner of all moves in the layer
+    pub bounding_box: Option<(Vec2, Vec2)>,
+}
+
+impl LayerMoves {
+    fn new(z: f32) -> Self {
+        LayerMoves {
+            z,
+            moves: vec![],
+            extrusions: vec![],
+            travels: vec![],
+            markers: vec![],
+            time: 0.0,
+            bounding_box: None,
+        }
+    }
+
+    fn expand_bounding_box(&mut self, point: Vec2) {
+        self.bounding_box = Some(match self.bounding_box {
+            Some((min, max)) => (min.min(point), max.max(point)),
+            None => (point, point),
+        });
+    }
+
+    fn push_move(
+        &mut self,
+        command_index: usize,
+        start: Vec2,
+        end: Vec2,
+        width: Option<f32>,
+        speed: f64,
+    ) {
+        self.expand_bounding_box(start);
+        self.expand_bounding_box(end);
+
+        let start_time = self
+            .moves
+            .last()
+            .map(|m| m.start_time + m.duration)
+            .unwrap_or(0.0);
+
+        self.moves.push(ToolpathMove {
+            command_index,
+            start,
+            end,
+            width,
+            speed,
+            start_time,
+            duration: move_time(start, end, speed).max(MIN_PLAYBACK_DURATION),
+        });
+    }
+
+    ///Total time to play back every move of the layer
+    pub fn playback_time(&self) -> f64 {
+        self.moves
+            .last()
+            .map(|m| m.start_time + m.duration)
+            .unwrap_or(0.0)
+    }
+
+    ///Move passing closest to the point
+    pub fn get_closest_move(&self, point: Vec2) -> Option<&ToolpathMove> {
+        self.moves.iter().min_by(|a, b| {
+            distance_to_segment(point, a.start, a.end)
+                .total_cmp(&distance_to_segment(point, b.start, b.end))
+        })
+    }
+}
+
+fn distance_to_segment(point: Vec2, start: Vec2, end: Vec2) -> f32 {
+    let direction = end - start;
+    let length_squared = direction.length_squared();
+    if length_squared == 0.0 {
+        return point.distance(start);
+    }
+    let t = ((point - start).dot(direction) / length_squared).clamp(0.0, 1.0);
+    point.distance(start + direction * t)
+}
+
+///Layer and move index of the first move generated by the command or after it
+pub fn find_move(layers: &[LayerMoves], command_index: usize) -> Option<(usize, usize)> {
+    let layer = layers.iter().position(|layer| {
+        layer
+            .moves
+            .last()
+            .map_or(false, |m| m.command_index >= command_index)
+    })?;
+    let move_index = layers[layer]
+        .moves
+        .partition_point(|m| m.command_index < command_index);
+    Some((layer, move_index))
+}
+
+const SEAM_TOLERANCE: f32 = 0.01;
+
+///Moves without a known speed still take some time during playback
+const MIN_PLAYBACK_DURATION: f64 = 0.01;
+
+fn move_time(start: Vec2, end: Vec2, speed: f64) -> f64 {
+    if speed > 0.0 {
+        start.distance(end) as f64 / speed
+    } else {
+        0.0
+    }
+}
+
+///A layer change is a z hop if the head returns to the layer height before extruding again
+fn is_z_hop(remaining: &[Command], layer_z: f32) -> bool {
+    remaining
+        .iter()
+        .find_map(|cmd| match cmd {
+            Command::LayerChange { z, .. } => Some(*z as f32 == layer_z),
+            Command::MoveAndExtrude { .. } => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+///Split the command list into layers, tracking the printer state for each extrusion.
+///This walks the whole command list so it should be done once per slice
+pub fn split_layers(cmds: &[Command], feature_speeds: &FeatureSpeeds) -> Vec<LayerMoves> {
+    let mut layers = vec![];
+    let mut current: Option<LayerMoves> = None;
+    let mut state = MoveState::default();
+    let mut position = Vec2::ZERO;
+    //Start of the current chain of extrusions, closed chains have their seam here
+    let mut chain_start: Option<Vec2> = None;
+
+    for (cmd_index, cmd) in cmds.iter().enumerate() {
+        match cmd {
+            Command::LayerChange { z, .. } => {
+                let z = *z as f32;
+                match current.as_mut() {
+                    Some(layer) if layer.z == z => {}
+                    Some(layer) if z > layer.z && is_z_hop(&cmds[cmd_index + 1..], layer.z) => {
+                        layer.markers.push((Overlay::ZHop, position));
+                    }
+                    _ => {
+                        layers.extend(current.take());
+                        current = Some(LayerMoves::new(z));
+                    }
+                }
+                chain_start = None;
+            }
+            Command::SetState { new_state } => {
+                if let Some(speed) = new_state.movement_speed {
+                    state.speed = speed;
+                    state.feature = feature_speeds.get_feature(speed);
+                }
+                if let Some(fan_speed) = new_state.fan_speed {
+                    state.fan_speed = fan_speed;
+                }
+                if let Some(temperature) = new_state.extruder_temp {
+                    state.temperature = temperature;
+                }
+                if let (Some(retract), Some(layer)) = (new_state.retract, current.as_mut()) {
+                    let overlay = if retract {
+                        Overlay::Retraction
+                    } else {
+                        Overlay::Unretraction
+                    };
+                    layer.markers.push((overlay, position));
+                }
+            }
+            Command::MoveTo { end } => {
+                let end = Vec2::new(end.x as f32, end.y as f32);
+                if let Some(layer) = current.as_mut() {
+                    layer.time += move_time(position, end, state.speed);
+                    layer.travels.push((position, end));
+                    layer.push_move(cmd_index, position, end, None, state.speed);
+                }
+                position = end;
+                chain_start = None;
+            }
+            Command::MoveAndExtrude {
+                start,
+                end,
+                width,
+                thickness,
+            } => {
+                let start = Vec2::new(start.x as f32, start.y as f32);
+                let end = Vec2::new(end.x as f32, end.y as f32);
+                if let Some(layer) = current.as_mut() {
+                    layer.time += move_time(start, end, state.speed);
+                    layer.push_move(cmd_index, start, end, Some(*width as f32), state.speed);
+                    layer.extrusions.push(ExtrusionMove {
+                        command_index: cmd_index,
+                        start,
+                        end,
+                        width: *width as f32,
+                        thickness: *thickness as f32,
+                        state,
+                    });
+
+                    let chain = *chain_start.get_or_insert(start);
+                    if chain.distance(start) > SEAM_TOLERANCE && chain.distance(end) < SEAM_TOLERANCE
+                    {
+                        layer.markers.push((Overlay::Seam, chain));
+                        chain_start = None;
+                    }
+                }
+                position = end;
+            }
+            Command::ChangeObject { object } => {
+                state.object = Some(*object);
+            }
+            Command::Delay { msec } => {
+                if let Some(layer) = current.as_mut() {
+                    layer.time += *msec as f64 / 1000.0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    layers.extend(current);
+    layers
+}
+
+///CPU side geometry of a single layer of the toolpath
+#[derive(Debug)]
+pub struct ToolpathLayer {
+    pub z: f32,
+    pub vertices: Vec<ToolpathVertex>,
+    pub indices: Vec<u32>,
+    pub overlays: Vec<(Overlay, Vec<DisplayVertex>)>,
+}
+
+impl ToolpathLayer {
+    fn new(z: f32) -> Self {
+        ToolpathLayer {
+            z,
+            vertices: vec![],
+            indices: vec![],
+            overlays: vec![],
+        }
+    }
+
+    ///Travels are stored as line pairs and markers as points, both slightly above the layer
+    fn add_overlays(&mut self, layer: &LayerMoves) {
+        let z = layer.z + 0.05;
+        let to_vertex = |p: &Vec2| DisplayVertex {
+            position: (p.x, p.y, z),
+        };
+
+        for overlay in Overlay::ALL {
+            let vertices: Vec<DisplayVertex> = if overlay == Overlay::Travel {
+                layer
+                    .travels
+                    .iter()
+                    .flat_map(|(start, end)| [to_vertex(start), to_vertex(end)])
+                    .collect()
+            } else {
+                layer
+                    .markers
+                    .iter()
+                    .filter(|(marker, _)| *marker == overlay)
+                    .map(|(_, p)| to_vertex(p))
+                    .collect()
+            };
+
+            if !vertices.is_empty() {
+                self.overlays.push((overlay, vertices));
+            }
+        }
+    }
+
+    fn add_quad(&mut self, corners: [Vec3; 4], normal: Vec3) {
+        let index_pos = self.vertices.len() as u32;
+
+        self.vertices.extend(corners.iter().map(|c| ToolpathVertex {
+            position: (c.x, c.y, c.z),
+            normal: (normal.x, normal.y, normal.z),
+        }));
+
+        self.indices.extend(
+            [0, 1, 2, 0, 2, 3]
+                .into_iter()
+                .map(|offset| index_pos + offset),
+        );
+    }
+
+    ///Add a box shaped extrusion from start to end. Start and end are at the top of the extrusion,
+    ///adding EXTRUSION_VERTICES vertices
+    fn add_extrusion(&mut self, start: Vec3, end: Vec3, width: f32, thickness: f32) {
+        let dir = Vec3::new(end.x - start.x, end.y - start.y, 0.0);
+        let len = dir.length();
+        let side_normal = Vec3::new(-dir.y, dir.x, 0.0) / len;
+        let side = side_normal * (width / 2.0);
+        let down = Vec3::new(0.0, 0.0, thickness);
+
+        //top
+        self.add_quad(
+            [start + side, end + side, end - side, start - side],
+            Vec3::Z,
+        );
+
+        //left side
+        self.add_quad(
+            [start + side - down, end + side - down, end + side, start + side],
+            side_normal,
+        );
+
+        //right side
+        self.add_quad(
+            [start - side, end - side, end - side - down, start - side - down],
+            -side_normal,
+        );
+    }
+}
+
+///Top, left and right side of an extrusion
+const EXTRUSION_VERTICES: usize = 12;
+
+///Zero length extrusions have no direction to extrude along and are not drawn
+fn is_drawn(extrusion: &ExtrusionMove) -> bool {
+    extrusion.start.distance(extrusion.end) >= f32::EPSILON
+}
+
+///GPU buffers for a single layer of the toolpath
+pub struct ToolpathLayerBuffers {
+    pub z: f32,
+    pub vert_buff: glium::VertexBuffer<ToolpathVertex>,
+    pub color_buff: glium::VertexBuffer<ToolpathColor>,
+    pub index_buff: glium::IndexBuffer<u32>,
+    pub overlays: Vec<(Overlay, glium::VertexBuffer<DisplayVertex>)>,
+}
+
+impl ToolpathLayerBuffers {
+    pub fn is_empty(&self) -> bool {
+        self.index_buff.len() == 0
+    }
+}
+
+///Build the extrusion geometry for each layer. This is independent of the color scheme so it is
+///done once per slice, next to splitting the layers
+pub fn build_layers(layers: &[LayerMoves]) -> Vec<ToolpathLayer> {
+    layers
+        .iter()
+        .map(|layer| {
+            let mut geometry = ToolpathLayer::new(layer.z);
+
+            for extrusion in layer.extrusions.iter().filter(|e| is_drawn(e)) {
+                geometry.add_extrusion(
+                    extrusion.start.extend(layer.z),
+                    extrusion.end.extend(layer.z),
+                    extrusion.width,
+                    extrusion.thickness,
+                );
+            }
+
+            geometry.add_overlays(layer);
+            geometry
+        })
+        .collect()
+}
+
+///Color of every vertex build_layers generates for the layer
+pub fn build_colors(layer: &LayerMoves, color_map: &ColorMap) -> Vec<ToolpathColor> {
+    layer
+        .extrusions
+        .iter()
+        .filter(|e| is_drawn(e))
+        .flat_map(|extrusion| {
+            let color = color_map.get_color(layer, extrusion);
+            let color = ToolpathColor {
+                color: (
+                    color.r() as f32 / 255.0,
+                    color.g() as f32 / 255.0,
+                    color.b() as f32 / 255.0,
+                ),
+            };
+            std::iter::repeat(color).take(EXTRUSION_VERTICES)
+        })
+        .collect()
+}
+
+///The sliced toolpath ready for display
+pub struct Toolpath {
+    pub color_map: ColorMap,
+    pub layers: Vec<ToolpathLayerBuffers>,
+}
+
+impl Toolpath {
+    ///Upload the geometry built in the background, colored with the given color map
+    pub fn new(
+        display: &glium::Display,
+        geometry: &[ToolpathLayer],
+        layers: &[LayerMoves],
+        color_map: ColorMap,
+    ) -> Self {
+        let buffers = geometry
+            .iter()
+            .zip(layers)
+            .map(|(layer, moves)| ToolpathLayerBuffers {
+                z: layer.z,
+                vert_buff: glium::VertexBuffer::new(display, &layer.vertices).unwrap(),
+                color_buff: glium::VertexBuffer::new(display, &build_colors(moves, &color_map))
+                    .unwrap(),
+                index_buff: glium::IndexBuffer::new(
+                    display,
+                    glium::index::PrimitiveType::TrianglesList,
+                    &layer.indices,
+                )
+                .unwrap(),
+                overlays: layer
+                    .overlays
+                    .iter()
+                    .map(|(overlay, vertices)| {
+                        (*overlay, glium::VertexBuffer::new(display, vertices).unwrap())
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Toolpath {
+            color_map,
+            layers: buffers,
+        }
+    }
+
+    ///Replace only the colors, the geometry buffers are kept
+    pub fn recolor(&mut self, display: &glium::Display, layers: &[LayerMoves], color_map: ColorMap) {
+        for (buffers, moves) in self.layers.iter_mut().zip(layers) {
+            buffers.color_buff =
+                glium::VertexBuffer::new(display, &build_colors(moves, &color_map)).unwrap();
+        }
+        self.color_map = color_map;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcode_parser::parse_gcode;
+    use crate::materials::MaterialProfile;
+
+    ///Speed section as written by the slicer's default settings, in mm/s
+    const SETTINGS: &str = r#"{
+        "layer_height": 0.2,
+        "speed": {
+            "interior_inner_perimeter": 40.0,
+            "interior_surface_perimeter": 40.0,
+            "exterior_inner_perimeter": 40.0,
+            "exterior_surface_perimeter": 30.0,
+            "solid_top_infill": 150.0,
+            "solid_infill": 200.0,
+            "infill": 200.0,
+            "travel": 180.0,
+            "bridge": 20.0,
+            "support": 50.0
+        }
+    }"#;
+
+    fn get_features(gcode: &str) -> Vec<FeatureType> {
+        let settings: serde_json::Value = serde_json::from_str(SETTINGS).unwrap();
+        let parsed = parse_gcode(gcode, &MaterialProfile::default());
+        split_layers(&parsed.commands, &FeatureSpeeds::from_settings(&settings))
+            .iter()
+            .flat_map(|layer| layer.extrusions.iter().map(|e| e.state.feature))
+            .collect()
+    }
+
+    #[test]
+    fn features_are_estimated_from_unique_speeds() {
+        let features = get_features(
+            "G1 Z0.2 F10800\n\
+             G1 X10 Y10\n\
+             G1 X20 E1 F1800\n\
+             G1 Y20 E2 F1200\n\
+             G1 X10 E3 F9000\n\
+             G1 Y10 E4 F3000\n",
+        );
+
+        assert_eq!(
+            features,
+            vec![
+                FeatureType::OuterPerimeter,
+                FeatureType::Bridge,
+                FeatureType::TopSolidInfill,
+                FeatureType::Support,
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_and_slowed_speeds_are_other() {
+        let features = get_features(
+            "G1 Z0.2 F10800\n\
+             G1 X10 Y10\n\
+             G1 X20 E1 F2400\n\
+             G1 Y20 E2 F12000\n\
+             G1 X10 E3 F900\n",
+        );
+
+        assert_eq!(features, vec![FeatureType::Other; 3]);
+    }
+
+    #[test]
+    fn colors_match_the_geometry_vertices() {
+        let parsed = parse_gcode(
+            "G1 Z0.2 F600\n\
+             G1 X10 Y10\n\
+             G1 X20 E1\n\
+             G1 Y20 E2\n",
+            &MaterialProfile::default(),
+        );
+        let mut layers = split_layers(&parsed.commands, &FeatureSpeeds::default());
+        let mut degenerate = layers[0].extrusions[0].clone();
+        degenerate.end = degenerate.start;
+        layers[0].extrusions.insert(1, degenerate);
+
+        let geometry = build_layers(&layers);
+        let color_map = ColorMap::new(crate::color::ColorScheme::Speed, &layers);
+
+        assert_eq!(layers[0].extrusions.len(), 3);
+        assert_eq!(geometry[0].vertices.len(), 2 * EXTRUSION_VERTICES);
+        assert_eq!(build_colors(&layers[0], &color_map).len(), geometry[0].vertices.len());
+        assert_eq!(
+            layers[0].bounding_box,
+            Some((Vec2::new(0.0, 0.0), Vec2::new(20.0, 20.0)))
+        );
+    }
+
+    #[test]
+    fn missing_settings_leave_every_feature_other() {
+        let speeds = FeatureSpeeds::from_settings_file("/nonexistent/settings.json");
+        assert_eq!(speeds.get_feature(30.0), FeatureType::Other);
+    }
+}